@@ -1,2 +1 @@
-from .shared import PartialTask, FileDescriptorPartial, Server
-
+from .shared import Server
@@ -4,50 +4,6 @@ from typing import Callable
 from .. import _Server, create_server
 
 
-class FileDescriptorPartial:
-    """
-    Creates a partial callback with a preset callback set,
-    this means that only a file descriptor and index is needed in oder
-    to add a fd listener to the event loop, this is mostly just utils
-    for the internal Rust handlers to ensure the callbacks are available
-    from the heap.
-
-    Args:
-        caller:
-            The callable that will be invoked when an instance of this
-            class is called.
-        callback:
-            The callable that is passed to the caller as a preset callback
-            kwarg.
-
-    Returns:
-        A instance of `FileDescriptorPartial` read to be called.
-    """
-
-    __slots__ = ('_caller', '_callback')
-
-    def __init__(self, caller, callback):
-        self._caller = caller
-        self._callback = callback
-
-    def __call__(self, fd: int, index: int, *args):
-        self._caller(fd, self._callback, index)
-
-
-class PartialTask:
-    """
-    A partial task factory, when called it produces a task of the
-    callback with the give args and kwargs.
-    """
-
-    def __init__(self, executor: asyncio.AbstractEventLoop, cb):
-        self.executor = executor
-        self.cb = cb
-
-    def __call__(self, *args, **kwargs):
-        self.executor.call_soon_threadsafe(self.cb, *args)
-
-
 class Server:
     def __init__(
             self,
@@ -56,89 +12,83 @@ class Server:
             port: int = 8080,
             *,
             debug: bool = False,
-            backlog: int = 1024,
             keep_alive: int = 5,
-            idle_max: int = -1,
+            workers: int = 1,
+            lifespan: str = "auto",
+            max_in_flight: int = None,
+            body_memoryview: bool = False,
+            buffer_size: int = 8192,
+            max_buffer_size: int = 256 * 1024,
+            buffer_pool_size: int = 1024,
+            bind: str = None,
+            protocol: str = "auto",
+            listeners: list = None,
+            log_level: str = "info",
             loop: asyncio.AbstractEventLoop = None
     ):
         self.host = host
         self.port = port
         self.debug = debug
-        self.backlog = backlog
         self.keep_alive = keep_alive
-        self.idle_max = idle_max
+        self.workers = workers
+        self.lifespan = lifespan
+        self.max_in_flight = max_in_flight
+        self.body_memoryview = body_memoryview
+        self.buffer_size = buffer_size
+        self.max_buffer_size = max_buffer_size
+        self.buffer_pool_size = buffer_pool_size
+        self.bind = bind
+        self.protocol = protocol
+        self.listeners = listeners
+        self.log_level = "debug" if debug else log_level
         self.loop = loop or asyncio.get_event_loop()
 
         self._waiter = self.loop.create_future()
-        self._factory = PartialTask(self.loop, app)
 
+        # The server schedules the app on the loop itself.
         self._server: _Server = create_server(
             self.host,
             self.port,
-            self._factory,
-            self.backlog,
+            app,
             self.keep_alive,
-            self.idle_max if idle_max > 0 else 0,
+            workers=self.workers,
+            lifespan=self.lifespan,
+            loop=self.loop,
+            max_in_flight=self.max_in_flight,
+            body_memoryview=self.body_memoryview,
+            buffer_size=self.buffer_size,
+            max_buffer_size=self.max_buffer_size,
+            buffer_pool_size=self.buffer_pool_size,
+            bind=self.bind,
+            protocol=self.protocol,
+            listeners=self.listeners,
+            log_level=self.log_level,
         )
 
-        self._server.init(
-            self._add_reader,
-            self._remove_reader,
-            self._add_writer,
-            self._remove_writer,
-        )
+    async def shutdown(self):
+        """
+        Stops the server waiting for its threads to exit, this is
+        waited on in the executor as the lifespan shutdown needs
+        the event loop to keep running.
+        """
+        self._server.stop()
+        await self.loop.run_in_executor(None, self._server.join)
 
-    def shutdown(self):
-        self._server.shutdown()
-        self._waiter.set_result(None)
+        if not self._waiter.done():
+            self._waiter.set_result(None)
 
     def start(self):
-        self._server.start(self.loop.add_reader, self._server.poll_accept)
-        self.loop.create_task(self.keep_alive_ticker())
+        """ Starts the server in the background. """
+        self._server.start()
 
-        if self.idle_max > 0:
-            self.loop.create_task(self.idle_max_ticker())
-        self._server.poll_accept()
+        if self.debug:
+            self.loop.create_task(self.stats_ticker())
 
     async def run_forever(self):
         await self._waiter
 
-    async def keep_alive_ticker(self):
+    async def stats_ticker(self):
         while not self._waiter.done():
-            if self.debug:
-                print("Active Clients: ", self._server.len_clients())
-            try:
-                self._server.poll_keep_alive()
-            except Exception as e:
-                print("Unhandled keep alive exception: {}".format(e))
+            print("Active Clients: ", self._server.len_clients())
+            print("Buffers: ", self._server.buffer_stats())
             await asyncio.sleep(self.keep_alive)
-
-    async def idle_max_ticker(self):
-        while not self._waiter.done():
-            try:
-                self._server.poll_idle()
-            except Exception as e:
-                print("Unhandled keep alive exception: {}".format(e))
-            await asyncio.sleep(self.idle_max)
-
-    @property
-    def _add_reader(self):
-        return FileDescriptorPartial(
-            self.loop.add_reader,
-            callback=self._server.poll_read
-        )
-
-    @property
-    def _remove_reader(self):
-        return self.loop.remove_reader
-
-    @property
-    def _add_writer(self):
-        return FileDescriptorPartial(
-            self.loop.add_writer,
-            callback=self._server.poll_write
-        )
-
-    @property
-    def _remove_writer(self):
-        return self.loop.remove_writer
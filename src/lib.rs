@@ -7,17 +7,36 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 mod pyre_server;
 
-use crate::pyre_server::server;
+use crate::pyre_server::workers::WorkerPool;
+use crate::pyre_server::handle::ServerHandle;
+use crate::pyre_server::lifespan::{
+    Lifespan,
+    LifespanMode,
+    LifespanReceiver,
+    LifespanSender,
+};
 use crate::pyre_server::responders::receiver::DataReceiver;
 use crate::pyre_server::responders::sender::DataSender;
 
 use pyo3::prelude::*;
+use pyo3::types::PyDict;
 use pyo3::wrap_pyfunction;
 use std::time::Duration;
-use crate::pyre_server::py_callback::CallbackHandler;
+use crate::pyre_server::py_callback::{self, CallbackHandler};
+use crate::pyre_server::config::{ServerConfig, ListenerConfig, SharedListenerConfig};
+use crate::pyre_server::switch::DefaultProtocol;
+use crate::pyre_server::server::MAX_LISTENERS;
+use crate::pyre_server::logging;
+use crate::pyre_server::buffer_pool::BufferPool;
+use crate::pyre_server::net::BindAddr;
+use std::sync::Arc;
+use pyo3::exceptions::PyValueError;
 
+#[cfg(feature = "tls")]
+use crate::pyre_server::tls;
 
-/// Creates a client handler instance linked to a TcpListener and event loop.
+
+/// Binds a server to the given address without starting it.
 ///
 /// Args:
 ///     host:
@@ -27,32 +46,317 @@ use crate::pyre_server::py_callback::CallbackHandler;
 ///     backlog:
 ///         The max amount of iterations to do when accepting clients
 ///         when the socket is ready and has been invoked.
+///     server_header:
+///         The value of the `Server` header added to every response,
+///         if this is None no header is added.
+///     header_timeout:
+///         The max seconds allowed between receiving parts of a request
+///         head before responding with `408 Request Timeout`.
+///     head_timeout:
+///         The max seconds allowed to receive a whole request head
+///         before responding with `408 Request Timeout`.
+///     min_body_rate:
+///         The minimum average bytes per second a request body must be
+///         sent at, if this is None no minimum is enforced.
+///     max_head_size:
+///         The max size in bytes of a request head before responding
+///         with `431 Request Header Fields Too Large`.
+///     max_body_size:
+///         The max size in bytes of a request body before responding
+///         with `413 Payload Too Large`, if this is None there is no limit.
+///     body_memoryview:
+///         If request bodies are given to the app as `memoryview`s over
+///         the server's own buffers rather than being copied into `bytes`,
+///         the views are read-only and valid for as long as they are kept.
+///     ws_max_size:
+///         The max size in bytes of a single WebSocket message before
+///         the connection is closed with code 1009.
+///     h2_max_concurrent_streams:
+///         The max number of streams a HTTP/2 client can have open at
+///         once, any streams past this are refused.
+///     shutdown_timeout:
+///         The max seconds open connections are given to finish once
+///         the server is stopped before they are closed.
+///     lifespan:
+///         When the ASGI lifespan scope is run, either 'auto' to carry
+///         on without it if the app does not support it, 'on' to fail
+///         starting if the app does not complete the startup or 'off'.
+///     certfile:
+///         The path to a PEM encoded certificate chain, if this is given
+///         along with `keyfile` connections are served over TLS.
+///     keyfile:
+///         The path to the PEM encoded private key of the certificate.
+///     protocol:
+///         The protocol connections start out with, either 'auto' to
+///         start with HTTP/1 and move to HTTP/2 with a upgrade, the
+///         HTTP/2 preface or TLS ALPN, or 'h2' to speak HTTP/2 from the
+///         start to clients with prior knowledge and TLS clients that do
+///         not use ALPN.
+///     workers:
+///         The number of event loop threads to run, each worker accepts
+///         from every listener and keeps the clients it accepts. TCP
+///         listeners are bound by each worker with `SO_REUSEPORT`.
+///     loop:
+///         The asyncio event loop the app is run on, each request is
+///         scheduled as a task on it and any exception the app raises is
+///         logged. If this is None the callback is called directly on the
+///         server thread and must hand the request off itself, async apps
+///         are rejected as there is nothing to run them on.
+///     max_in_flight:
+///         The max number of requests being handled by the app at once,
+///         any more are left unread until one finishes. If this is None
+///         there is no limit.
+///     buffer_size:
+///         The size in bytes each connection's read and write buffers
+///         start out at, buffers are taken from a pool shared by every
+///         connection and given back while the connection is idle.
+///     max_buffer_size:
+///         The size in bytes a read buffer is doubled up to, past this it
+///         only grows as much as a single message needs. Buffers that have
+///         grown past this are freed rather than given back to the pool.
+///         This is also the amount of read data that can be waiting on the
+///         app before reading from the connection is paused, it is resumed
+///         once the app has drained it to a quarter of this.
+///     buffer_pool_size:
+///         The max number of free buffers kept in the pool, the memory
+///         of any more given back is freed.
+///     bind:
+///         A address to bind to instead of the host and port, either
+///         'host:port', 'unix:/path/to.sock' for a Unix socket, 'fd://N'
+///         to adopt a socket that is already bound or 'fd://' for the
+///         first socket passed by systemd.
+///     listeners:
+///         A list of addresses to listen on at once instead of `bind` or
+///         the host and port, each is either a address in the same form
+///         as `bind` or a dict with a 'bind' address and optionally its
+///         own 'certfile', 'keyfile' and 'protocol'. Any settings a
+///         listener does not give are taken from the ones given here,
+///         setting 'certfile' and 'keyfile' to None serves it in plain text.
+///     log_level:
+///         The minimum level of the server's logs, either 'trace', 'debug',
+///         'info', 'warning' or 'error'. Logs are sent to the Python `pyre`
+///         logger along with the `token` of the connection they are about,
+///         the level is shared by every server in the process.
+///     startup_timeout:
+///         The max seconds the app is given to complete the lifespan
+///         startup, once passed the server fails to start if `lifespan`
+///         is 'on' and carries on without the scope if it is 'auto'.
 ///
 /// Returns:
-///     A un-initialised HandleClients instance linked to the main listener.
-#[pyfunction]
+///     A `_Server` handle bound to the address, call `start()` to begin
+///     serving in the background and `stop()` / `join()` to shut it down.
+#[pyfunction(
+    server_header = "\"Pyre\"",
+    header_timeout = "10.0",
+    head_timeout = "30.0",
+    min_body_rate = "None",
+    max_head_size = "65536",
+    max_body_size = "None",
+    body_memoryview = "false",
+    ws_max_size = "16777216",
+    h2_max_concurrent_streams = "100",
+    shutdown_timeout = "30.0",
+    lifespan = "\"auto\"",
+    certfile = "None",
+    keyfile = "None",
+    workers = "1",
+    loop = "None",
+    max_in_flight = "None",
+    buffer_size = "8192",
+    max_buffer_size = "262144",
+    buffer_pool_size = "1024",
+    bind = "None",
+    protocol = "\"auto\"",
+    listeners = "None",
+    log_level = "\"info\"",
+    startup_timeout = "60.0",
+)]
+#[allow(clippy::too_many_arguments)]
 fn create_server(
+    py: Python,
     host: &str,
     port: u16,
     cb: PyObject,
     keep_alive: f64,
-) -> PyResult<()> {
-    println!("Running on http://{}:{}", host, port);
-    let bind = format!("{}:{}", host, port);
-    let keep_alive = Duration::from_secs_f64(keep_alive);
-    let callbacks = CallbackHandler::new(cb);
+    server_header: Option<&str>,
+    header_timeout: f64,
+    head_timeout: f64,
+    min_body_rate: Option<f64>,
+    max_head_size: usize,
+    max_body_size: Option<usize>,
+    body_memoryview: bool,
+    ws_max_size: usize,
+    h2_max_concurrent_streams: u32,
+    shutdown_timeout: f64,
+    lifespan: &str,
+    certfile: Option<&str>,
+    keyfile: Option<&str>,
+    workers: usize,
+    r#loop: Option<PyObject>,
+    max_in_flight: Option<usize>,
+    buffer_size: usize,
+    max_buffer_size: usize,
+    buffer_pool_size: usize,
+    bind: Option<&str>,
+    protocol: &str,
+    listeners: Option<Vec<&PyAny>>,
+    log_level: &str,
+    startup_timeout: f64,
+) -> PyResult<ServerHandle> {
+    let log_level = logging::level_from_name(log_level)
+        .ok_or_else(|| PyValueError::new_err(
+            "log_level must be one of 'trace', 'debug', 'info', 'warning' or 'error'"
+        ))?;
+    logging::init(py, log_level)?;
 
+    let lifespan = LifespanMode::from_name(lifespan)
+        .ok_or_else(|| PyValueError::new_err(
+            "lifespan must be one of 'auto', 'on' or 'off'"
+        ))?;
 
-    let mut server = server::LowLevelServer::from_addr(
-        bind,
+    let addrs = match listeners {
+        Some(listeners) => listeners.into_iter()
+            .map(|item| parse_listener(item, protocol, certfile, keyfile))
+            .collect::<PyResult<Vec<_>>>()?,
+        None => {
+            let bind = match bind {
+                Some(bind) => BindAddr::parse(bind)?,
+                None => BindAddr::parse(&format!("{}:{}", host, port))?,
+            };
+
+            vec![(bind, listener_config(protocol, certfile, keyfile)?)]
+        },
+    };
+
+    if addrs.is_empty() | (addrs.len() > MAX_LISTENERS) {
+        return Err(PyValueError::new_err(format!(
+            "listeners must have between 1 and {} addresses",
+            MAX_LISTENERS,
+        )))
+    }
+    if r#loop.is_none() {
+        py_callback::check_sync_app(py, &cb)?;
+    }
+
+    let keep_alive = Duration::from_secs_f64(keep_alive);
+    let callbacks = CallbackHandler::new(cb, r#loop, max_in_flight);
+    let lifespan = Lifespan::new(
+        lifespan,
+        callbacks.clone(),
+        Duration::from_secs_f64(startup_timeout),
+    );
+    let buffers = BufferPool::new(buffer_size, max_buffer_size, buffer_pool_size);
+    let config = ServerConfig {
+        server_header: server_header.map(String::from),
+        header_timeout: Duration::from_secs_f64(header_timeout),
+        head_timeout: Duration::from_secs_f64(head_timeout),
+        min_body_rate,
+        max_head_size,
+        max_body_size,
+        body_memoryview,
+        ws_max_size,
+        read_high_water: max_buffer_size,
+        read_low_water: max_buffer_size / 4,
+        h2_max_concurrent_streams,
+        shutdown_timeout: Duration::from_secs_f64(shutdown_timeout),
+    };
+
+    let pool = WorkerPool::bind(
+        &addrs,
+        workers,
         keep_alive,
         callbacks,
+        Arc::new(config),
+        buffers,
+        lifespan,
     )?;
 
-    if let Err(e) = server.start() {
-        eprintln!("{:?}", e);
+    Ok(ServerHandle::new(pool))
+}
+
+/// Reads a item of the `listeners` list, any settings it does not give
+/// are taken from the ones given to `create_server`.
+fn parse_listener<'a>(
+    item: &'a PyAny,
+    protocol: &'a str,
+    certfile: Option<&'a str>,
+    keyfile: Option<&'a str>,
+) -> PyResult<(BindAddr, SharedListenerConfig)> {
+    if let Ok(bind) = item.extract::<&str>() {
+        let config = listener_config(protocol, certfile, keyfile)?;
+        return Ok((BindAddr::parse(bind)?, config))
+    }
+
+    let item: &PyDict = item.downcast()?;
+    let setting = |key: &str, default: Option<&'a str>| {
+        match item.get_item(key) {
+            Some(value) => value.extract::<Option<&str>>(),
+            None => Ok(default),
+        }
     };
 
+    let bind = setting("bind", None)?
+        .ok_or_else(|| PyValueError::new_err(
+            "each listener must have a 'bind' address"
+        ))?;
+
+    let config = listener_config(
+        setting("protocol", Some(protocol))?.unwrap_or(protocol),
+        setting("certfile", certfile)?,
+        setting("keyfile", keyfile)?,
+    )?;
+
+    Ok((BindAddr::parse(bind)?, config))
+}
+
+/// Builds the settings of a listener.
+fn listener_config(
+    protocol: &str,
+    certfile: Option<&str>,
+    keyfile: Option<&str>,
+) -> PyResult<SharedListenerConfig> {
+    let protocol = DefaultProtocol::from_name(protocol)
+        .ok_or_else(|| PyValueError::new_err(
+            "protocol must be either 'auto' or 'h2'"
+        ))?;
+
+    #[cfg(not(feature = "tls"))]
+    load_tls(certfile, keyfile)?;
+
+    Ok(Arc::new(ListenerConfig {
+        protocol,
+
+        #[cfg(feature = "tls")]
+        tls: load_tls(certfile, keyfile)?,
+    }))
+}
+
+/// Loads the TLS settings if a certificate and key were given.
+#[cfg(feature = "tls")]
+fn load_tls(
+    certfile: Option<&str>,
+    keyfile: Option<&str>,
+) -> PyResult<Option<Arc<rustls::ServerConfig>>> {
+    match (certfile, keyfile) {
+        (Some(certfile), Some(keyfile)) => {
+            Ok(Some(tls::load_config(certfile, keyfile)?))
+        },
+        (None, None) => Ok(None),
+        _ => Err(PyValueError::new_err(
+            "certfile and keyfile must be given together"
+        )),
+    }
+}
+
+/// Rejects any TLS settings as the `tls` feature is not enabled.
+#[cfg(not(feature = "tls"))]
+fn load_tls(certfile: Option<&str>, keyfile: Option<&str>) -> PyResult<()> {
+    if certfile.is_some() | keyfile.is_some() {
+        return Err(PyValueError::new_err(
+            "TLS is not supported, pyre must be built with the `tls` feature"
+        ))
+    }
+
     Ok(())
 }
 
@@ -65,5 +369,8 @@ fn pyre_test(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(create_server, m)?)?;
     m.add_class::<DataSender>()?;
     m.add_class::<DataReceiver>()?;
+    m.add_class::<ServerHandle>()?;
+    m.add_class::<LifespanSender>()?;
+    m.add_class::<LifespanReceiver>()?;
     Ok(())
 }
@@ -2,6 +2,8 @@ use pyo3::PyResult;
 
 use bytes::BytesMut;
 
+use crate::pyre_server::socket_io::WriteBuffer;
+
 
 /// Defined the necessary polling methods
 pub trait SocketCommunicator {
@@ -18,7 +20,7 @@ pub trait SocketCommunicator {
     /// the returned buffer is drained and written to the socket.
     /// Once all the data has been written to, or all that can be
     /// written has been. The write_buffer_drained is invoked.
-    fn write_buffer_acquire(&mut self) -> PyResult<&mut BytesMut>;
+    fn write_buffer_acquire(&mut self) -> PyResult<&mut WriteBuffer>;
 
     /// Called once data has been written to the socket after acquiring
     /// the buffer from write_buffer_acquire and has been successfully drained.
@@ -33,28 +35,5 @@ pub trait ProtocolBuffers {
     fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()>;
 
     /// Invoked when data is ready to be written to the socket.
-    fn fill_write_buffer(&mut self, buffer: &mut BytesMut) -> PyResult<()>;
-
-    /// The EOF has been sent by the socket.
-    fn eof_received(&mut self) -> PyResult<()>;
-}
-
-
-/// Defined the necessary methods for a transport handler
-pub trait BaseTransport {
-    /// Pauses reading of the set connection.
-    fn pause_reading(&self) -> PyResult<()>;
-
-    /// Resumes reading of the set connection.
-    fn resume_reading(&self) -> PyResult<()>;
-
-    /// Pauses writing of the set connection.
-    fn pause_writing(&self) -> PyResult<()>;
-
-    /// Resumes writing of the set connection.
-    fn resume_writing(&self) -> PyResult<()>;
-
+    fn fill_write_buffer(&mut self, buffer: &mut WriteBuffer) -> PyResult<()>;
 }
-
-
-
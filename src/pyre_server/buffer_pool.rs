@@ -0,0 +1,240 @@
+use bytes::BytesMut;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// The usage counts of a buffer pool, these can be read from any thread.
+#[derive(Default)]
+pub struct PoolStats {
+    /// The number of buffers that have been allocated.
+    pub allocated: AtomicUsize,
+
+    /// The number of buffers currently held by connections.
+    pub in_use: AtomicUsize,
+
+    /// The number of buffers waiting in the pool.
+    pub free: AtomicUsize,
+
+    /// The total capacity in bytes of the buffers waiting in the pool.
+    pub free_bytes: AtomicUsize,
+
+    /// The number of buffers given back that were dropped rather than
+    /// kept, either as the pool was full or they had outgrown the limit.
+    pub discarded: AtomicUsize,
+}
+
+
+/// The buffers shared between every connection, this is shared between
+/// every worker.
+struct Pool {
+    /// The buffers waiting to be used.
+    free: Mutex<Vec<BytesMut>>,
+
+    /// The capacity each buffer starts out with.
+    initial_size: usize,
+
+    /// The capacity a buffer is grown up to while it is being read into,
+    /// buffers that have grown past this are not kept in the pool.
+    max_size: usize,
+
+    /// The max number of buffers kept waiting in the pool.
+    max_free: usize,
+
+    /// The usage counts of the pool.
+    stats: PoolStats,
+}
+
+
+/// A cheaply cloneable handle to the pool of read and write buffers.
+///
+/// Connections take a small buffer from the pool when they have data to
+/// handle and give it back once they are idle, this means memory is only
+/// held by the connections that are doing something rather than every
+/// open connection.
+#[derive(Clone)]
+pub struct BufferPool {
+    pool: Arc<Pool>,
+}
+
+impl BufferPool {
+    /// Creates a empty pool, buffers are allocated as they are needed.
+    pub fn new(initial_size: usize, max_size: usize, max_free: usize) -> Self {
+        let pool = Pool {
+            free: Mutex::new(Vec::new()),
+            initial_size,
+            max_size: max_size.max(initial_size),
+            max_free,
+            stats: PoolStats::default(),
+        };
+
+        Self { pool: Arc::new(pool) }
+    }
+
+    /// The capacity a buffer is grown up to while it is being read into.
+    pub fn max_size(&self) -> usize {
+        self.pool.max_size
+    }
+
+    /// The usage counts of the pool.
+    pub fn stats(&self) -> &PoolStats {
+        &self.pool.stats
+    }
+
+    /// Takes a empty buffer from the pool or allocates a new one if the
+    /// pool is empty.
+    pub fn acquire(&self) -> BytesMut {
+        let stats = &self.pool.stats;
+        stats.in_use.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(buffer) = self.pool.free.lock().unwrap().pop() {
+            stats.free.fetch_sub(1, Ordering::Relaxed);
+            stats.free_bytes.fetch_sub(buffer.capacity(), Ordering::Relaxed);
+            return buffer
+        }
+
+        stats.allocated.fetch_add(1, Ordering::Relaxed);
+        BytesMut::with_capacity(self.pool.initial_size)
+    }
+
+    /// Gives a buffer back to the pool, any data left in it is discarded.
+    ///
+    /// Buffers that have outgrown the max size are dropped so one large
+    /// request does not keep its memory reserved forever.
+    pub fn release(&self, mut buffer: BytesMut) {
+        let stats = &self.pool.stats;
+        stats.in_use.fetch_sub(1, Ordering::Relaxed);
+
+        // Any space split off the front is reclaimed once nothing else
+        // references it, otherwise this allocates a new buffer.
+        buffer.clear();
+        buffer.reserve(self.pool.initial_size);
+
+        let capacity = buffer.capacity();
+        let fits = (capacity >= self.pool.initial_size)
+            & (capacity <= self.pool.max_size);
+
+        if fits {
+            let mut free = self.pool.free.lock().unwrap();
+            if free.len() < self.pool.max_free {
+                free.push(buffer);
+                stats.free.fetch_add(1, Ordering::Relaxed);
+                stats.free_bytes.fetch_add(capacity, Ordering::Relaxed);
+                return
+            }
+        }
+
+        stats.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Makes sure the buffer has space to be read into, the buffer is
+    /// doubled in size each time it fills up until it reaches the max.
+    ///
+    /// Past the max reading still grows the buffer a little at a time,
+    /// the backlog limit is what stops it growing without bound.
+    pub fn reserve(&self, buffer: &mut BytesMut) {
+        if buffer.capacity() > buffer.len() {
+            return
+        }
+
+        let capacity = buffer.capacity().max(self.pool.initial_size);
+        let additional = capacity.min(self.pool.max_size.saturating_sub(capacity));
+        if additional > 0 {
+            buffer.reserve(additional);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(count: &AtomicUsize) -> usize {
+        count.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn acquire_allocates_when_empty() {
+        let pool = BufferPool::new(1024, 4096, 2);
+        let buffer = pool.acquire();
+
+        assert!(buffer.capacity() >= 1024);
+        assert!(buffer.is_empty());
+        assert_eq!(load(&pool.stats().allocated), 1);
+        assert_eq!(load(&pool.stats().in_use), 1);
+        assert_eq!(load(&pool.stats().free), 0);
+    }
+
+    #[test]
+    fn release_reuses_buffer() {
+        let pool = BufferPool::new(1024, 4096, 2);
+        let mut buffer = pool.acquire();
+        buffer.extend_from_slice(b"leftover");
+        let ptr = buffer.as_ptr();
+        pool.release(buffer);
+
+        assert_eq!(load(&pool.stats().in_use), 0);
+        assert_eq!(load(&pool.stats().free), 1);
+        assert!(load(&pool.stats().free_bytes) >= 1024);
+
+        let buffer = pool.acquire();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert_eq!(load(&pool.stats().allocated), 1);
+        assert_eq!(load(&pool.stats().free), 0);
+        assert_eq!(load(&pool.stats().free_bytes), 0);
+    }
+
+    #[test]
+    fn release_discards_past_max_free() {
+        let pool = BufferPool::new(1024, 4096, 1);
+        let first = pool.acquire();
+        let second = pool.acquire();
+        pool.release(first);
+        pool.release(second);
+
+        assert_eq!(load(&pool.stats().free), 1);
+        assert_eq!(load(&pool.stats().discarded), 1);
+        assert_eq!(load(&pool.stats().in_use), 0);
+    }
+
+    #[test]
+    fn release_discards_outgrown_buffer() {
+        let pool = BufferPool::new(1024, 4096, 2);
+        let mut buffer = pool.acquire();
+        buffer.reserve(8192);
+        pool.release(buffer);
+
+        assert_eq!(load(&pool.stats().free), 0);
+        assert_eq!(load(&pool.stats().discarded), 1);
+    }
+
+    #[test]
+    fn reserve_doubles_up_to_max() {
+        let pool = BufferPool::new(1024, 4096, 2);
+        let mut buffer = pool.acquire();
+
+        // Space is only added once the buffer is full.
+        pool.reserve(&mut buffer);
+        assert_eq!(buffer.capacity(), 1024);
+
+        buffer.resize(1024, 0);
+        pool.reserve(&mut buffer);
+        assert!(buffer.capacity() >= 2048);
+
+        buffer.resize(buffer.capacity(), 0);
+        pool.reserve(&mut buffer);
+        assert_eq!(buffer.capacity(), 4096);
+
+        buffer.resize(4096, 0);
+        pool.reserve(&mut buffer);
+        assert_eq!(buffer.capacity(), 4096);
+    }
+
+    #[test]
+    fn max_size_is_at_least_initial_size() {
+        let pool = BufferPool::new(4096, 1024, 2);
+        assert_eq!(pool.max_size(), 4096);
+    }
+}
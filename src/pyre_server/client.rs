@@ -1,31 +1,42 @@
-use mio::net::TcpStream;
 use mio::Token;
+use log::debug;
 
-use std::net::{SocketAddr, Shutdown};
-use std::error::Error;
-use std::io::ErrorKind;
+use std::net::Shutdown;
+use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "tls")]
+use rustls::{ServerSession, Session};
 
 use crate::pyre_server::transport::EventLoopHandle;
 use crate::pyre_server::protocol_manager::AutoProtocol;
 use crate::pyre_server::switch::SelectedProtocol;
 use crate::pyre_server::py_callback::CallbackHandler;
+use crate::pyre_server::config::{SharedConfig, SharedListenerConfig};
+use crate::pyre_server::buffer_pool::BufferPool;
 use crate::pyre_server::socket_io::BufferIO;
 use crate::pyre_server::abc::SocketCommunicator;
+use crate::pyre_server::net::{Addr, Connection};
+use crate::pyre_server::error::PyreResult;
+
+#[cfg(feature = "tls")]
+use crate::pyre_server::tls;
 
 
-/// A handler for a TcpStream.
+/// A handler for a connected stream e.g. a TCP or Unix socket.
 ///
 /// This is in charge of managing both the socket and it's relevant event loop
 /// handling e.g. adding and remove the socket from the event loop.
-pub struct Client {
+pub struct Client<S> {
     /// The event loop token identifier.
     token: Token,
 
     /// The remote address of the stream.
-    addr: SocketAddr,
+    addr: Addr,
 
-    /// The TcpStream itself.
-    pub stream: TcpStream,
+    /// The stream itself, this is `None` once the remote has closed the
+    /// connection until the client handles a new one.
+    pub stream: Option<S>,
 
     /// A cheaply cloneable handle for updating event loop calls.
     event_loop: EventLoopHandle,
@@ -42,28 +53,50 @@ pub struct Client {
     /// Whether or not the client is idle by not handling the stream
     /// anymore or is inactive.
     pub is_idle: bool,
+
+    /// The last time data was read from or written to the stream.
+    last_activity: Instant,
+
+    /// The deadline of the most recent timer scheduled for the client,
+    /// any timers with a different deadline are stale.
+    pub timer_deadline: Option<Instant>,
+
+    /// The settings of the listener the current connection was
+    /// accepted from.
+    listener: SharedListenerConfig,
+
+    /// The TLS session of the current connection, everything read from
+    /// and written to the stream passes through this if it is set.
+    #[cfg(feature = "tls")]
+    tls: Option<ServerSession>,
 }
 
-impl Client {
+impl<S: Connection> Client<S> {
     /// Builds a Client instance from the given token,
     /// stream and socket address.
+    #[allow(clippy::too_many_arguments)]
     pub fn build_from(
         token: Token,
-        stream: TcpStream,
-        addr: SocketAddr,
+        stream: S,
+        addr: Addr,
+        listener: SharedListenerConfig,
         event_loop: EventLoopHandle,
         callbacks: CallbackHandler,
+        config: SharedConfig,
+        buffers: BufferPool,
     ) -> Self {
         let protocol = AutoProtocol::new(
             token,
             SelectedProtocol::H1,
             event_loop.clone(),
             callbacks,
+            config,
+            buffers,
         );
 
         Self {
             token,
-            stream,
+            stream: Some(stream),
             addr,
             event_loop,
             protocol,
@@ -71,39 +104,120 @@ impl Client {
             is_reading: false,
             is_writing: false,
             is_idle: false,
+
+            last_activity: Instant::now(),
+            timer_deadline: None,
+
+            listener,
+
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
-    /// Allows the client to handle a new stream by essentially
-    /// resetting it state.
+    /// Allows the client to handle a new stream by resetting its state,
+    /// nothing from the previous connection is carried over.
     pub fn handle_new(
         &mut self,
-        stream: TcpStream,
-        addr: SocketAddr,
+        token: Token,
+        stream: S,
+        addr: Addr,
+        listener: SharedListenerConfig,
     ) {
-        self.stream = stream;
+        self.token = token;
+        self.stream = Some(stream);
         self.addr = addr;
+        self.listener = listener;
+        self.protocol.reset(token);
 
         self.is_reading = false;
         self.is_writing = false;
         self.is_idle = false;
+
+        self.last_activity = Instant::now();
+        self.timer_deadline = None;
+    }
+
+    /// The token of the connection currently being handled.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// Notifies the protocol that it is now in charge of the
+    /// current stream.
+    pub fn connection_made(&mut self) -> PyreResult<()> {
+        #[cfg(feature = "tls")]
+        {
+            self.tls = self.listener.tls.as_ref().map(ServerSession::new);
+        }
+
+        debug!(token = self.token.0; "Connection made from {}", self.addr);
+
+        let server = match self.stream.as_ref() {
+            Some(stream) => stream.local_addr()?,
+            None => return Err(not_connected().into()),
+        };
+        self.protocol.new_connection(
+            self.addr.clone(),
+            server,
+            self.is_secure(),
+        )?;
+
+        // Encrypted connections agree on the protocol in the handshake.
+        if !self.is_secure() {
+            self.protocol.select_protocol(self.listener.protocol.selected())?;
+        }
+
+        Ok(())
+    }
+
+    /// If the current connection is encrypted with TLS.
+    fn is_secure(&self) -> bool {
+        #[cfg(feature = "tls")]
+        {
+            self.tls.is_some()
+        }
+
+        #[cfg(not(feature = "tls"))]
+        {
+            false
+        }
     }
 }
 
 
 /// Event loop event callbacks.
-impl Client {
+impl<S: Connection> Client<S> {
     /// Invoked when the socket is readable.
     ///
     /// This should be used to propel the state machine of the server
     /// for the most part e.g. parsing and invoking callbacks.
-    pub fn read_ready(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn read_ready(&mut self) -> PyreResult<()> {
+        // Events left over from before the connection was closed.
+        if self.is_idle {
+            return Ok(())
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            if self.tls.is_some() {
+                return self.tls_read_ready()
+            }
+        }
+
         loop {
-            let mut buffer = self.protocol.read_buffer_acquire()?;
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Ok(()),
+            };
+
+            let buffer = self.protocol.read_buffer_acquire()?;
 
-            let n = match self.stream.read_buf(&mut buffer) {
+            let n = match BufferIO::read_buf(stream, buffer) {
+                Ok(0) => return self.sock_shutdown(),
                 Ok(n) => n,
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.protocol.release_idle_buffers();
                     return Ok(())
                 },
                 Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
@@ -116,14 +230,40 @@ impl Client {
                     return self.sock_shutdown();
                 },
                 Err(e) => {
-                    return Err(Box::new(e))
+                    return Err(e.into())
                 },
             };
 
+            self.last_activity = Instant::now();
             self.protocol.read_buffer_filled(n)?;
+            self.protocol.maybe_switch()?;
+
+            if self.pause_if_backlogged() {
+                return Ok(())
+            }
         }
+    }
 
-        Ok(())
+    /// Pauses reading if enough data is waiting on the app, reading is
+    /// resumed once the app has received enough of it.
+    ///
+    /// Returns if reading was paused.
+    fn pause_if_backlogged(&mut self) -> bool {
+        if !self.protocol.is_backlogged() {
+            return false
+        }
+
+        self.protocol.set_backlog_paused(true);
+        self.event_loop.pause_reading(self.token);
+
+        // The app may have received the data before the pause was queued
+        // which would leave reading paused forever.
+        if !self.protocol.is_backlogged() {
+            self.protocol.set_backlog_paused(false);
+            self.event_loop.resume_reading(self.token);
+        }
+
+        true
     }
 
     /// Invoked when the socket is writeable.
@@ -131,13 +271,44 @@ impl Client {
     /// This can be used to propel the state machine of the server
     /// but that should mostly be done with the read event, this can
     /// be used to drain the writing buffer and wake up python tasks.
-    pub fn write_ready(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn write_ready(&mut self) -> PyreResult<()> {
+        // Events left over from before the connection was closed.
+        if self.is_idle {
+            return Ok(())
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            if self.tls.is_some() {
+                return self.tls_write_ready()
+            }
+        }
+
         loop {
-            let mut buffer = self.protocol.write_buffer_acquire()?;
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Ok(()),
+            };
+
+            let buffer = self.protocol.write_buffer_acquire()?;
+            if buffer.is_empty() {
+                self.protocol.write_buffer_drained(0)?;
 
-            let n = match self.stream.write_buf(&mut buffer) {
+                if self.protocol.should_close() {
+                    return self.close_write();
+                }
+
+                return Ok(())
+            }
+
+            // Any files queued by the protocol are sent by the kernel
+            // rather than being read into the buffer first.
+            let n = match stream.write_buf(buffer) {
                 Ok(n) => n,
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    // Acquiring the buffer paused writing, the rest of
+                    // it is written once the socket is writable again.
+                    self.event_loop.resume_writing(self.token);
                     return Ok(())
                 },
                 Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
@@ -150,14 +321,13 @@ impl Client {
                     return self.sock_shutdown();
                 },
                 Err(e) => {
-                    return Err(Box::new(e))
+                    return Err(e.into())
                 },
             };
 
-            self.protocol.write_buffer_drained(n);
+            self.last_activity = Instant::now();
+            self.protocol.write_buffer_drained(n)?;
         }
-
-        Ok(())
     }
 
     /// Invoked when the socket has closed at least one half of its
@@ -165,21 +335,293 @@ impl Client {
     ///
     /// If either the read or write side of the socket has been closed
     /// the whole stream should probably be closed down as the server is
-    /// no longer able to continue with this stream.
+    /// no longer able to continue with this stream. The socket is
+    /// released straight away so it does not hold its descriptor until
+    /// the client expires or handles a new connection.
     ///
     /// NOTE:
     /// This is not guaranteed to always be called when a socket shuts down.
-    pub fn sock_shutdown(&mut self) -> Result<(), Box<dyn Error>> {
-        println!("Shutdown");
+    pub fn sock_shutdown(&mut self) -> PyreResult<()> {
+        if self.is_idle {
+            return Ok(())
+        }
+
+        self.close_write()?;
+        self.event_loop.release(self.token);
+        Ok(())
+    }
+
+    /// Stops handling the connection and shuts down the write side of
+    /// the socket, this is used when the server ends the connection.
+    ///
+    /// The socket is left open until the client expires or handles a new
+    /// connection, closing it with data from the remote still unread
+    /// would reset the connection before the response has been read.
+    fn close_write(&mut self) -> PyreResult<()> {
+        if self.is_idle {
+            return Ok(())
+        }
+
+        debug!(token = self.token.0; "Connection shutdown");
         self.protocol.lost_connection()?;
 
+        self.event_loop.pause_reading(self.token);
+        self.event_loop.pause_writing(self.token);
+
         self.is_idle = true;
-        let _ = self.stream.shutdown(Shutdown::Write);
+        if let Some(stream) = self.stream.as_ref() {
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+
+        Ok(())
+    }
+
+    /// Drops the socket once it has been removed from the event loop,
+    /// the client itself is kept to handle a new connection.
+    pub fn release_stream(&mut self) {
+        self.stream = None;
+    }
+
+    /// Invoked when the event loop resumes reading from the socket.
+    ///
+    /// Any data that was left in the buffer while reading was paused
+    /// is processed as it will not produce another read event.
+    pub fn reading_resumed(&mut self) -> PyreResult<()> {
+        self.protocol.process_buffered()?;
+        self.protocol.maybe_switch()?;
         Ok(())
     }
 
-    pub fn check_keep_alive(&mut self) -> Result<(), Box<dyn Error>> {
+    /// The time the current request must make progress by if the
+    /// protocol has a deadline for it.
+    pub fn request_deadline(&self) -> Option<Instant> {
+        if self.is_idle {
+            return None
+        }
+
+        self.protocol.request_deadline(self.last_activity)
+    }
+
+    /// Checks if the current request has missed its deadline or if the
+    /// client has been inactive for longer than the keep alive timeout
+    /// while waiting on a new request.
+    ///
+    /// Returns the time the client should next be checked at or `None`
+    /// if the client has expired and should be closed.
+    pub fn check_timeouts(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+    ) -> Option<Instant> {
+        if !self.is_idle {
+            let checked = self.protocol.check_timeouts(
+                now,
+                self.last_activity,
+            );
+
+            if checked.is_some() {
+                return checked
+            }
+        }
+
+        // A request that is still being handled is not idle.
+        if !self.is_idle & !self.protocol.is_awaiting_request() {
+            return Some(now + timeout)
+        }
+
+        let deadline = self.last_activity + timeout;
+        if deadline <= now {
+            None
+        } else {
+            Some(deadline)
+        }
+    }
+
+    /// Invoked when the server is shutting down, the connection is
+    /// closed by the protocol once the current request is complete.
+    pub fn shutdown(&mut self) {
+        if !self.is_idle {
+            self.protocol.shutdown();
+        }
+    }
+
+    /// Closes the stream, this is used when the server closes the
+    /// connection rather than the remote.
+    pub fn close(&mut self) -> PyreResult<()> {
+        if !self.is_idle {
+            self.protocol.lost_connection()?;
+            self.is_idle = true;
+        }
+
+        if let Some(stream) = self.stream.as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// TLS event callbacks, these sit between the stream and the protocol
+/// decrypting what is read and encrypting what is written.
+#[cfg(feature = "tls")]
+impl<S: Connection> Client<S> {
+    /// Invoked when the socket is readable and the connection uses TLS.
+    ///
+    /// This reads the TLS records from the socket, any decrypted data
+    /// is then handed to the protocol the same as a plain socket.
+    fn tls_read_ready(&mut self) -> PyreResult<()> {
+        loop {
+            let session = match self.tls.as_mut() {
+                Some(session) => session,
+                None => return Err(missing_session().into()),
+            };
+
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Ok(()),
+            };
+
+            match session.read_tls(stream) {
+                Ok(0) => return self.sock_shutdown(),
+                Ok(_) => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.protocol.release_idle_buffers();
+                    return Ok(())
+                },
+                Err(ref e) if is_disconnect(e) => {
+                    return self.sock_shutdown();
+                },
+                Err(e) => {
+                    return Err(e.into())
+                },
+            };
+
+            self.last_activity = Instant::now();
+
+            let was_handshaking = session.is_handshaking();
+            if session.process_new_packets().is_err() {
+                // The alert describing the failure is sent before closing.
+                let _ = session.write_tls(stream);
+                return self.close_write();
+            }
+
+            if was_handshaking & !session.is_handshaking() {
+                let selected = tls::negotiated_protocol(
+                    session,
+                    self.listener.protocol,
+                );
+                self.protocol.select_protocol(selected)?;
+            }
+
+            loop {
+                let buffer = self.protocol.read_buffer_acquire()?;
+
+                let n = match session.read_buf(buffer) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(ref e) if is_disconnect(e) => {
+                        return self.sock_shutdown();
+                    },
+                    Err(e) => {
+                        return Err(e.into())
+                    },
+                };
+
+                self.protocol.read_buffer_filled(n)?;
+                self.protocol.maybe_switch()?;
+            }
+
+            // Handshake messages need writing without anything being
+            // sent by the protocol.
+            if session.wants_write() {
+                self.event_loop.resume_writing(self.token);
+            }
+
+            if self.pause_if_backlogged() {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Invoked when the socket is writeable and the connection uses TLS.
+    ///
+    /// Any pending TLS records are written before more data is taken
+    /// from the protocol and encrypted.
+    fn tls_write_ready(&mut self) -> PyreResult<()> {
+        // Acquiring the write buffer pauses writing so it has to be
+        // resumed if the socket fills up with records still pending.
+        let mut acquired = false;
+
+        loop {
+            let session = match self.tls.as_mut() {
+                Some(session) => session,
+                None => return Err(missing_session().into()),
+            };
+
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Ok(()),
+            };
+
+            while session.wants_write() {
+                match session.write_tls(stream) {
+                    Ok(_) => self.last_activity = Instant::now(),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        if acquired {
+                            self.event_loop.resume_writing(self.token);
+                        }
+
+                        return Ok(())
+                    },
+                    Err(ref e) if is_disconnect(e) => {
+                        return self.sock_shutdown();
+                    },
+                    Err(e) => {
+                        return Err(e.into())
+                    },
+                };
+            }
+
+            let buffer = self.protocol.write_buffer_acquire()?;
+            acquired = true;
+
+            if buffer.is_empty() {
+                self.protocol.write_buffer_drained(0)?;
+
+                if self.protocol.should_close() {
+                    session.send_close_notify();
+                    let _ = session.write_tls(stream);
+                    return self.close_write();
+                }
+
+                return Ok(())
+            }
+
+            let n = session.write_buf(buffer)?;
+            self.protocol.write_buffer_drained(n)?;
+        }
+    }
+}
+
+/// The error for a client without a socket.
+fn not_connected() -> io::Error {
+    io::Error::new(ErrorKind::NotConnected, "client has no connection")
+}
+
+/// The error for a TLS connection without a session, this closes the
+/// connection rather than taking down the whole server.
+#[cfg(feature = "tls")]
+fn missing_session() -> io::Error {
+    io::Error::new(ErrorKind::Other, "TLS session missing on a TLS connection")
+}
+
+/// If the error means the remote has gone away.
+#[cfg(feature = "tls")]
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::UnexpectedEof
+    )
+}
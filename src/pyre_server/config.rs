@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::pyre_server::switch::DefaultProtocol;
+
+
+/// A cheaply cloneable reference to the server settings.
+pub type SharedConfig = Arc<ServerConfig>;
+
+/// A cheaply cloneable reference to the settings of a listener.
+pub type SharedListenerConfig = Arc<ListenerConfig>;
+
+
+/// The settings that are shared between every client and protocol
+/// running on the server.
+pub struct ServerConfig {
+    /// The value of the `Server` header added to every response,
+    /// no header is added if this is `None`.
+    pub server_header: Option<String>,
+
+    /// The max time allowed between receiving parts of a request head.
+    pub header_timeout: Duration,
+
+    /// The max total time allowed to receive a whole request head.
+    pub head_timeout: Duration,
+
+    /// The minimum average rate in bytes per second a request body must
+    /// be sent at, no minimum is enforced if this is `None`.
+    pub min_body_rate: Option<f64>,
+
+    /// The max size in bytes of a request head.
+    pub max_head_size: usize,
+
+    /// The max size in bytes of a request body, no limit is enforced if
+    /// this is `None`.
+    pub max_body_size: Option<usize>,
+
+    /// If request bodies are given to the app as `memoryview`s over the
+    /// server's own buffers rather than being copied into `bytes`.
+    pub body_memoryview: bool,
+
+    /// The max size in bytes of a single WebSocket message.
+    pub ws_max_size: usize,
+
+    /// The amount in bytes of read data waiting on the app past which
+    /// reading from the connection is paused.
+    pub read_high_water: usize,
+
+    /// The amount in bytes of read data waiting on the app the app must
+    /// drain down to before reading from the connection is resumed.
+    pub read_low_water: usize,
+
+    /// The max number of streams a HTTP/2 client can have open at once.
+    pub h2_max_concurrent_streams: u32,
+
+    /// The max time open connections are given to finish once the
+    /// server is shutting down before they are closed.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            server_header: Some(String::from("Pyre")),
+            header_timeout: Duration::from_secs(10),
+            head_timeout: Duration::from_secs(30),
+            min_body_rate: None,
+            max_head_size: 64 * 1024,
+            max_body_size: None,
+            body_memoryview: false,
+            ws_max_size: 16 * 1024 * 1024,
+            read_high_water: 256 * 1024,
+            read_low_water: 64 * 1024,
+            h2_max_concurrent_streams: 100,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+
+/// The settings of a single listener, these apply to every connection
+/// accepted from it.
+pub struct ListenerConfig {
+    /// The protocol connections start out with.
+    pub protocol: DefaultProtocol,
+
+    /// The TLS settings, connections are served in plain text if this
+    /// is `None`.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl ListenerConfig {
+    /// If connections are encrypted with TLS.
+    pub fn is_secure(&self) -> bool {
+        #[cfg(feature = "tls")]
+        {
+            self.tls.is_some()
+        }
+
+        #[cfg(not(feature = "tls"))]
+        {
+            false
+        }
+    }
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            protocol: DefaultProtocol::Auto,
+
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
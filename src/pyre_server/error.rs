@@ -0,0 +1,68 @@
+use mio::Token;
+use pyo3::PyErr;
+
+use std::fmt;
+use std::io;
+use std::error::Error;
+
+use crate::pyre_server::lifespan::LifespanError;
+
+
+/// A specialized result for anything that can fail while running the
+/// event loop.
+pub type PyreResult<T> = Result<T, PyreError>;
+
+
+/// The errors that can happen while running the server.
+///
+/// Errors from handling a client only close that client's connection,
+/// the event loop is only stopped by errors from polling or the
+/// listeners themselves.
+#[derive(Debug)]
+pub enum PyreError {
+    /// A error from the OS e.g. reading from a socket or polling.
+    Io(io::Error),
+
+    /// A error raised on the Python side e.g. while building a scope.
+    Python(PyErr),
+
+    /// The app failed the lifespan startup or shutdown.
+    Lifespan(LifespanError),
+
+    /// The token does not belong to any open connection, this is a
+    /// connection that has already been closed.
+    UnknownClient(Token),
+}
+
+impl fmt::Display for PyreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::Python(e) => write!(f, "python error: {}", e),
+            Self::Lifespan(e) => write!(f, "lifespan error: {}", e),
+            Self::UnknownClient(token) => {
+                write!(f, "no client with token {}", token.0)
+            },
+        }
+    }
+}
+
+impl Error for PyreError {}
+
+impl From<io::Error> for PyreError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<PyErr> for PyreError {
+    fn from(e: PyErr) -> Self {
+        Self::Python(e)
+    }
+}
+
+impl From<LifespanError> for PyreError {
+    fn from(e: LifespanError) -> Self {
+        Self::Lifespan(e)
+    }
+}
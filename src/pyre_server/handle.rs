@@ -0,0 +1,163 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::types::PyDict;
+use log::info;
+
+use std::thread;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::pyre_server::workers::WorkerPool;
+use crate::pyre_server::protocols::scope::addr_to_tuple;
+
+
+/// How long a join waits between checking for signals.
+const JOIN_INTERVAL: Duration = Duration::from_millis(100);
+
+
+/// The Python handle to a bound server, the event loops run on their
+/// own threads once started so the calling thread is never blocked.
+#[pyclass(name = "_Server")]
+pub struct ServerHandle {
+    /// The workers running the event loops.
+    pool: WorkerPool,
+}
+
+impl ServerHandle {
+    /// Creates a new handle for the given workers.
+    pub fn new(pool: WorkerPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[pymethods]
+impl ServerHandle {
+    /// Starts the event loops in the background, this returns
+    /// immediately and the server runs until `stop()` is called.
+    ///
+    /// Raises:
+    ///     RuntimeError:
+    ///         If the server has already been started.
+    fn start(&self) -> PyResult<()> {
+        if !self.pool.start()? {
+            return Err(PyRuntimeError::new_err("server already started"))
+        }
+
+        for (addr, config) in self.pool.listeners() {
+            let scheme = if config.is_secure() { "https" } else { "http" };
+            info!("Running on {}://{}", scheme, addr);
+        }
+
+        Ok(())
+    }
+
+    /// Tells the server to stop, new connections are no longer accepted
+    /// and the event loops exit once the open ones have finished their
+    /// current request or the shutdown timeout passes, use `join()` to
+    /// wait for this.
+    fn stop(&self) {
+        self.pool.stop();
+    }
+
+    /// Waits for the server to stop with the GIL released.
+    ///
+    /// Args:
+    ///     timeout:
+    ///         The max seconds to wait, if this is None it waits forever.
+    ///
+    /// Returns:
+    ///     If the server has stopped.
+    ///
+    /// Raises:
+    ///     RuntimeError:
+    ///         If the server stopped because of an error e.g. the app
+    ///         failed the lifespan startup.
+    #[args(timeout = "None")]
+    fn join(&self, py: Python, timeout: Option<f64>) -> PyResult<bool> {
+        let deadline = timeout
+            .map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+
+        loop {
+            if self.pool.reap().map_err(PyRuntimeError::new_err)? {
+                return Ok(true)
+            }
+
+            let wait = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false)
+                    }
+
+                    JOIN_INTERVAL.min(deadline - now)
+                },
+                None => JOIN_INTERVAL,
+            };
+
+            py.allow_threads(|| thread::sleep(wait));
+
+            // Lets a KeyboardInterrupt through while waiting.
+            py.check_signals()?;
+        }
+    }
+
+    /// If any of the event loops are still running.
+    fn is_running(&self) -> bool {
+        self.pool.is_running()
+    }
+
+    /// The number of connections currently open.
+    fn len_clients(&self) -> usize {
+        self.pool.len_clients()
+    }
+
+    /// The number of clients kept around to handle new connections.
+    fn len_idle(&self) -> usize {
+        self.pool.len_idle()
+    }
+
+    /// The usage of the read and write buffers shared by every
+    /// connection, this is a dict of:
+    ///
+    ///     allocated:
+    ///         The number of buffers that have been allocated.
+    ///     in_use:
+    ///         The number of buffers currently held by connections.
+    ///     free:
+    ///         The number of buffers waiting in the pool.
+    ///     free_bytes:
+    ///         The total capacity of the buffers waiting in the pool.
+    ///     discarded:
+    ///         The number of buffers dropped rather than kept in the
+    ///         pool, either as the pool was full or they had outgrown
+    ///         the max buffer size.
+    fn buffer_stats<'a>(&self, py: Python<'a>) -> PyResult<&'a PyDict> {
+        let stats = self.pool.buffer_stats();
+
+        let dict = PyDict::new(py);
+        dict.set_item("allocated", stats.allocated.load(Ordering::Relaxed))?;
+        dict.set_item("in_use", stats.in_use.load(Ordering::Relaxed))?;
+        dict.set_item("free", stats.free.load(Ordering::Relaxed))?;
+        dict.set_item("free_bytes", stats.free_bytes.load(Ordering::Relaxed))?;
+        dict.set_item("discarded", stats.discarded.load(Ordering::Relaxed))?;
+
+        Ok(dict)
+    }
+
+    /// The address the first listener is bound to as a `(host, port)`
+    /// tuple, a Unix socket is given as `(path, None)`.
+    fn addr(&self) -> Option<(String, Option<u16>)> {
+        self.pool.listeners()
+            .first()
+            .and_then(|(addr, _)| addr_to_tuple(addr))
+    }
+
+    /// The addresses of every listener in the order they were given,
+    /// these are in the same form as `addr()`.
+    fn addrs(&self) -> Vec<Option<(String, Option<u16>)>> {
+        self.pool.listeners()
+            .iter()
+            .map(|(addr, _)| addr_to_tuple(addr))
+            .collect()
+    }
+}
@@ -0,0 +1,433 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::exceptions::PyValueError;
+
+use crossbeam::channel::{Sender, Receiver, unbounded, RecvTimeoutError};
+use log::info;
+
+use std::fmt;
+use std::error::Error;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::Duration;
+
+use crate::pyre_server::py_callback::CallbackHandler;
+use crate::pyre_server::responders::awaitable::{Ready, LoopFuture};
+
+
+/// When the ASGI `lifespan` scope is run.
+#[derive(Copy, Clone, PartialEq)]
+pub enum LifespanMode {
+    /// Runs the scope but carries on without it if the app raises
+    /// or exits without completing the startup.
+    Auto,
+
+    /// Runs the scope, the server fails to start if it does not
+    /// complete the startup.
+    On,
+
+    /// Never runs the scope.
+    Off,
+}
+
+impl LifespanMode {
+    /// Parses the mode from its name, either `auto`, `on` or `off`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Self::Auto),
+            "on" => Some(Self::On),
+            "off" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+
+/// The error produced when the app fails the lifespan startup or
+/// shutdown.
+#[derive(Debug)]
+pub struct LifespanError(String);
+
+impl fmt::Display for LifespanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for LifespanError {}
+
+
+/// The reply sent by the app to a lifespan event.
+enum Outcome {
+    /// The `lifespan.*.complete` events.
+    Complete,
+
+    /// The `lifespan.*.failed` events containing the message.
+    Failed(String),
+}
+
+
+/// How far through the startup the lifespan scope is.
+#[derive(Clone)]
+enum Phase {
+    /// No worker has started yet.
+    Pending,
+
+    /// The first worker is waiting on the app to complete the startup.
+    Starting,
+
+    /// The app has completed the startup.
+    Started,
+
+    /// The app does not support the scope or it is turned off.
+    Skipped,
+
+    /// The app failed the startup with the given reason.
+    Failed(String),
+}
+
+
+/// The events waiting to be received by the app.
+#[derive(Default)]
+struct Events {
+    /// The events the app has not received yet.
+    pending: VecDeque<&'static str>,
+
+    /// The future the app is awaiting if it is waiting on an event.
+    waiter: Option<LoopFuture>,
+}
+
+/// A cheaply cloneable reference to the events waiting on the app.
+type SharedEvents = Arc<Mutex<Events>>;
+
+
+/// The state shared between every worker.
+struct State {
+    /// How far through the startup the scope is.
+    phase: Phase,
+
+    /// The number of workers that have started and not shutdown yet.
+    workers: usize,
+
+    /// The events queue and the replies of the app once started.
+    channel: Option<(SharedEvents, Receiver<Outcome>)>,
+}
+
+
+/// Runs the ASGI `lifespan` scope, this is shared between every worker
+/// so the startup happens once before any of them accept connections
+/// and the shutdown happens once the last of them has drained.
+#[derive(Clone)]
+pub struct Lifespan {
+    /// When the scope is run.
+    mode: LifespanMode,
+
+    /// The python callback the scope is given to.
+    callback: CallbackHandler,
+
+    /// The max time the app is given to reply to `lifespan.startup`.
+    startup_timeout: Duration,
+
+    /// The state shared between every worker.
+    state: Arc<Mutex<State>>,
+
+    /// Wakes the workers waiting on the first one to run the startup.
+    started: Arc<Condvar>,
+}
+
+impl Lifespan {
+    /// Creates a new lifespan handler invoking the given callback.
+    pub fn new(
+        mode: LifespanMode,
+        callback: CallbackHandler,
+        startup_timeout: Duration,
+    ) -> Self {
+        let state = State {
+            phase: Phase::Pending,
+            workers: 0,
+            channel: None,
+        };
+
+        Self {
+            mode,
+            callback,
+            startup_timeout,
+            state: Arc::new(Mutex::new(state)),
+            started: Arc::new(Condvar::new()),
+        }
+    }
+
+    /// Sends `lifespan.startup` to the app and waits for it to reply,
+    /// this is only done by the first worker and the rest wait on it.
+    pub fn startup(&self) -> Result<(), LifespanError> {
+        let mut state = self.state.lock().unwrap();
+        state.workers += 1;
+
+        if let Phase::Pending = state.phase {
+            state.phase = Phase::Starting;
+            drop(state);
+
+            let (phase, channel) = self.run_startup();
+
+            state = self.state.lock().unwrap();
+            state.phase = phase;
+            state.channel = channel;
+            self.started.notify_all();
+        }
+
+        while let Phase::Starting = state.phase {
+            state = self.started.wait(state).unwrap();
+        }
+
+        // A worker that fails to start never shuts down so is not
+        // counted, otherwise the last worker would never run the shutdown.
+        match &state.phase {
+            Phase::Failed(reason) => {
+                let error = LifespanError(reason.clone());
+                state.workers -= 1;
+                Err(error)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends `lifespan.shutdown` to the app and waits up to the given
+    /// timeout for it to reply, this is only done by the last worker to
+    /// shutdown.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), LifespanError> {
+        let (events, outcomes) = {
+            let mut state = self.state.lock().unwrap();
+            state.workers = state.workers.saturating_sub(1);
+
+            if state.workers > 0 {
+                return Ok(())
+            }
+
+            match state.channel.take() {
+                Some(channel) => channel,
+                None => return Ok(()),
+            }
+        };
+
+        if let Err(e) = deliver(&events, "lifespan.shutdown") {
+            return Err(LifespanError(format!(
+                "lifespan shutdown could not be sent: {:?}", e
+            )))
+        }
+
+        match outcomes.recv_timeout(timeout) {
+            Ok(Outcome::Complete) => Ok(()),
+            Ok(Outcome::Failed(reason)) => Err(LifespanError(format!(
+                "lifespan shutdown failed: {}", reason
+            ))),
+            Err(RecvTimeoutError::Timeout) => Err(LifespanError(
+                String::from("lifespan shutdown timed out")
+            )),
+
+            // The app has already exited.
+            Err(RecvTimeoutError::Disconnected) => Ok(()),
+        }
+    }
+
+    /// Invokes the app with the `lifespan` scope and waits for it to
+    /// reply to the `lifespan.startup` event.
+    fn run_startup(&self) -> (Phase, Option<(SharedEvents, Receiver<Outcome>)>) {
+        if self.mode == LifespanMode::Off {
+            return (Phase::Skipped, None)
+        }
+
+        let events = SharedEvents::default();
+        events.lock().unwrap().pending.push_back("lifespan.startup");
+
+        let (tx, outcomes) = unbounded();
+        let receiver = LifespanReceiver { events: events.clone() };
+        let sender = LifespanSender { tx };
+
+        let invoked = Python::with_gil(|py| -> PyResult<()> {
+            let scope = lifespan_scope(py)?;
+            self.callback.invoke((scope, receiver, sender))
+        });
+
+        if let Err(e) = invoked {
+            return (self.unsupported(format!("{:?}", e)), None)
+        }
+
+        // The sender is dropped if the app exits without replying.
+        match outcomes.recv_timeout(self.startup_timeout) {
+            Ok(Outcome::Complete) => (Phase::Started, Some((events, outcomes))),
+            Ok(Outcome::Failed(reason)) => {
+                let reason = format!("lifespan startup failed: {}", reason);
+                (Phase::Failed(reason), None)
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                let reason = format!(
+                    "the app did not complete the startup within {:?}",
+                    self.startup_timeout,
+                );
+                (self.unsupported(reason), None)
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                let reason = "the app exited without completing the startup";
+                (self.unsupported(reason.to_string()), None)
+            },
+        }
+    }
+
+    /// The phase used when the app does not run the scope, this is only
+    /// a failure if the scope is required.
+    fn unsupported(&self, reason: String) -> Phase {
+        if self.mode == LifespanMode::On {
+            return Phase::Failed(format!("lifespan startup failed: {}", reason))
+        }
+
+        info!("ASGI lifespan unsupported, continuing without it: {}", reason);
+        Phase::Skipped
+    }
+}
+
+
+/// The receive callable given to the app with the `lifespan` scope.
+#[pyclass]
+pub struct LifespanReceiver {
+    events: SharedEvents,
+}
+
+#[pymethods]
+impl LifespanReceiver {
+    /// Invoked by python to receive the next lifespan event, this returns
+    /// a awaitable that resolves once the server sends the event.
+    #[call]
+    fn __call__(&self, py: Python) -> PyResult<PyObject> {
+        let mut events = self.events.lock().unwrap();
+
+        if let Some(kind) = events.pending.pop_front() {
+            let event = lifespan_event(py, kind)?;
+            return Ok(Ready::new(event.into()).into_py(py))
+        }
+
+        let waiter = LoopFuture::new(py)?;
+        let future = waiter.future(py);
+        events.waiter = Some(waiter);
+
+        Ok(future)
+    }
+}
+
+
+/// The send callable given to the app with the `lifespan` scope.
+#[pyclass]
+pub struct LifespanSender {
+    tx: Sender<Outcome>,
+}
+
+#[pymethods]
+impl LifespanSender {
+    /// Invoked by python passing a ASGI `lifespan.*.complete` or
+    /// `lifespan.*.failed` event.
+    #[call]
+    fn __call__(&self, py: Python, event: &PyDict) -> PyResult<Ready> {
+        let kind: String = event.get_item("type")
+            .ok_or_else(|| PyValueError::new_err("event is missing a type"))?
+            .extract()?;
+
+        let outcome = match kind.as_str() {
+            "lifespan.startup.complete" | "lifespan.shutdown.complete" => {
+                Outcome::Complete
+            },
+            "lifespan.startup.failed" | "lifespan.shutdown.failed" => {
+                let message = match event.get_item("message") {
+                    Some(message) => message.extract()?,
+                    None => String::new(),
+                };
+
+                Outcome::Failed(message)
+            },
+            _ => return Err(PyValueError::new_err(
+                format!("unknown lifespan event type: {}", kind)
+            )),
+        };
+
+        let _ = self.tx.send(outcome);
+
+        Ok(Ready::new(py.None()))
+    }
+}
+
+
+/// Hands the event to the app, resolving the future it is waiting on
+/// if it is already waiting for it.
+fn deliver(events: &SharedEvents, kind: &'static str) -> PyResult<()> {
+    Python::with_gil(|py| {
+        let mut events = events.lock().unwrap();
+
+        match events.waiter.take() {
+            Some(waiter) => {
+                let event = lifespan_event(py, kind)?;
+                waiter.set_result(py, event.into())
+            },
+            None => {
+                events.pending.push_back(kind);
+                Ok(())
+            },
+        }
+    })
+}
+
+/// Builds the ASGI `lifespan` scope.
+fn lifespan_scope<'a>(py: Python<'a>) -> PyResult<&'a PyDict> {
+    let asgi = PyDict::new(py);
+    asgi.set_item("version", "3.0")?;
+    asgi.set_item("spec_version", "2.0")?;
+
+    let scope = PyDict::new(py);
+    scope.set_item("type", "lifespan")?;
+    scope.set_item("asgi", asgi)?;
+
+    Ok(scope)
+}
+
+/// Builds a lifespan event with the given type.
+fn lifespan_event<'a>(py: Python<'a>, kind: &str) -> PyResult<&'a PyDict> {
+    let event = PyDict::new(py);
+    event.set_item("type", kind)?;
+
+    Ok(event)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lifespan whose app cannot be invoked so the startup fails when
+    /// the scope is required.
+    fn lifespan(mode: LifespanMode) -> Lifespan {
+        let callback = Python::with_gil(|py| {
+            CallbackHandler::new(py.None(), None, None)
+        });
+
+        Lifespan::new(mode, callback, Duration::from_secs(1))
+    }
+
+    #[test]
+    fn failed_startup_is_not_counted() {
+        let lifespan = lifespan(LifespanMode::On);
+        assert!(lifespan.startup().is_err());
+        assert!(lifespan.startup().is_err());
+
+        assert_eq!(lifespan.state.lock().unwrap().workers, 0);
+        assert!(lifespan.shutdown(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn skipped_startup_is_counted_until_shutdown() {
+        let lifespan = lifespan(LifespanMode::Auto);
+        assert!(lifespan.startup().is_ok());
+        assert!(lifespan.startup().is_ok());
+        assert_eq!(lifespan.state.lock().unwrap().workers, 2);
+
+        assert!(lifespan.shutdown(Duration::from_secs(1)).is_ok());
+        assert!(lifespan.shutdown(Duration::from_secs(1)).is_ok());
+        assert_eq!(lifespan.state.lock().unwrap().workers, 0);
+    }
+}
@@ -0,0 +1,203 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use log::kv::{self, Key, Value, VisitSource};
+use mio::Token;
+
+use pyo3::prelude::*;
+use pyo3::once_cell::GILOnceCell;
+use pyo3::types::PyDict;
+
+
+/// The name of the Python logger every record is forwarded to.
+const LOGGER_NAME: &str = "pyre";
+
+/// The format of the handler added when logging has not been set up.
+const DEFAULT_FORMAT: &str = "%(levelname)s: %(message)s";
+
+/// The start of the target of every record logged by the server, any
+/// records from dependencies are ignored.
+const TARGET_PREFIX: &str = env!("CARGO_CRATE_NAME");
+
+
+/// The Python logger, this is looked up once.
+static PY_LOGGER: GILOnceCell<PyObject> = GILOnceCell::new();
+
+/// The bridge installed as the global logger.
+static BRIDGE: PythonBridge = PythonBridge;
+
+
+/// Parses a level from its Python name, either `trace`, `debug`, `info`,
+/// `warning` or `error`.
+pub fn level_from_name(name: &str) -> Option<LevelFilter> {
+    match name.to_ascii_lowercase().as_str() {
+        "trace" => Some(LevelFilter::Trace),
+        "debug" => Some(LevelFilter::Debug),
+        "info" => Some(LevelFilter::Info),
+        "warning" => Some(LevelFilter::Warn),
+        "error" => Some(LevelFilter::Error),
+        _ => None,
+    }
+}
+
+/// Forwards the server's logs to the Python `pyre` logger at the given
+/// level, the level applies to every server in the process.
+///
+/// A handler writing to stderr is added to the logger if logging has
+/// not been set up so the logs are not lost.
+pub fn init(py: Python, level: LevelFilter) -> PyResult<()> {
+    // This only fails if the bridge has already been installed.
+    let _ = log::set_logger(&BRIDGE);
+    log::set_max_level(level);
+
+    let logger = py_logger(py)?;
+    logger.call_method1("setLevel", (py_level(level.to_level()),))?;
+
+    if !logger.call_method0("hasHandlers")?.is_true()? {
+        let logging = py.import("logging")?;
+        let handler = logging.call_method0("StreamHandler")?;
+        let formatter = logging.call_method1("Formatter", (DEFAULT_FORMAT,))?;
+        handler.call_method1("setFormatter", (formatter,))?;
+        logger.call_method1("addHandler", (handler,))?;
+    }
+
+    Ok(())
+}
+
+/// Logs a Python exception along with its traceback and the token of
+/// the connection it is about if there is one.
+pub fn log_exception(
+    py: Python,
+    message: &str,
+    error: &PyErr,
+    token: Option<Token>,
+) {
+    let logged = py_logger(py).and_then(|logger| {
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("exc_info", error.instance(py))?;
+
+        if let Some(token) = token {
+            let extra = PyDict::new(py);
+            extra.set_item("token", token.0)?;
+            kwargs.set_item("extra", extra)?;
+        }
+
+        logger.call_method("error", (message,), Some(kwargs))
+    });
+
+    if logged.is_err() {
+        error.print(py);
+    }
+}
+
+/// The Python `pyre` logger.
+fn py_logger<'py>(py: Python<'py>) -> PyResult<&'py PyAny> {
+    if let Some(logger) = PY_LOGGER.get(py) {
+        return Ok(logger.as_ref(py))
+    }
+
+    let logger: PyObject = py.import("logging")?
+        .call_method1("getLogger", (LOGGER_NAME,))?
+        .into();
+
+    Ok(PY_LOGGER.get_or_init(py, || logger).as_ref(py))
+}
+
+/// The Python logging level of a record level, `None` is used for
+/// disabling logging and is above every level.
+fn py_level(level: Option<Level>) -> u8 {
+    match level {
+        Some(Level::Error) => 40,
+        Some(Level::Warn) => 30,
+        Some(Level::Info) => 20,
+        Some(Level::Debug) => 10,
+        Some(Level::Trace) => 5,
+        None => 100,
+    }
+}
+
+
+/// A `log` implementation that hands every record to the Python logger,
+/// the key values of a record are given as the `extra` attributes of the
+/// Python record e.g. the `token` of the connection.
+struct PythonBridge;
+
+impl PythonBridge {
+    /// Builds a Python record from the record and handles it with the
+    /// logger if the logger is enabled for its level.
+    fn emit(&self, py: Python, record: &Record) -> PyResult<()> {
+        let logger = py_logger(py)?;
+        let level = py_level(Some(record.level()));
+
+        if !logger.call_method1("isEnabledFor", (level,))?.is_true()? {
+            return Ok(())
+        }
+
+        let extra = PyDict::new(py);
+        let _ = record.key_values().visit(&mut Extra { py, extra });
+
+        let py_record = logger.call_method1(
+            "makeRecord",
+            (
+                LOGGER_NAME,
+                level,
+                record.file().unwrap_or("<rust>"),
+                record.line().unwrap_or(0),
+                record.args().to_string(),
+                (),
+                py.None(),
+                record.module_path(),
+                extra,
+            ),
+        )?;
+
+        logger.call_method1("handle", (py_record,))?;
+
+        Ok(())
+    }
+}
+
+impl Log for PythonBridge {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        (metadata.level() <= log::max_level())
+            & metadata.target().starts_with(TARGET_PREFIX)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return
+        }
+
+        Python::with_gil(|py| {
+            if let Err(e) = self.emit(py, record) {
+                e.print(py);
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+
+/// Copies the key values of a record into the `extra` dict of the
+/// Python record, numbers are kept as ints and everything else is
+/// given as a string.
+struct Extra<'py> {
+    py: Python<'py>,
+    extra: &'py PyDict,
+}
+
+impl<'kvs> VisitSource<'kvs> for Extra<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> Result<(), kv::Error> {
+        let text = value.to_string();
+        let value = match text.parse::<i64>() {
+            Ok(number) => number.into_py(self.py),
+            Err(_) => text.into_py(self.py),
+        };
+
+        self.extra.set_item(key.as_str(), value)
+            .map_err(|_| kv::Error::msg("failed to set extra attribute"))
+    }
+}
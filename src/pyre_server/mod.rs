@@ -5,6 +5,18 @@ mod client;
 mod transport;
 mod protocol_manager;
 mod abc;
-mod switch;
+pub mod switch;
 mod protocols;
 mod socket_io;
+pub mod net;
+mod error;
+pub mod logging;
+pub mod buffer_pool;
+pub mod config;
+mod timer;
+pub mod workers;
+pub mod handle;
+pub mod lifespan;
+
+#[cfg(feature = "tls")]
+pub mod tls;
@@ -0,0 +1,476 @@
+use mio::net::{TcpListener, TcpStream, TcpSocket};
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+
+use std::fmt;
+use std::io::{self, Read, Write, IoSlice, IoSliceMut};
+use std::net::{SocketAddr, Shutdown};
+
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use crate::pyre_server::socket_io::{SendFile, FileRegion};
+
+
+/// The max length of the queue of connections waiting to be accepted.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// The first file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+
+/// Where a listener gets its socket from, this is parsed from the bind
+/// strings given to the server.
+pub enum BindAddr {
+    /// A TCP socket bound to the address e.g. `127.0.0.1:8080`.
+    Tcp(SocketAddr),
+
+    /// A Unix socket bound to the path e.g. `unix:/run/pyre.sock`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+
+    /// A socket that has already been bound and is listening e.g.
+    /// `fd://3`, this is either a TCP or a Unix socket.
+    #[cfg(unix)]
+    Fd(RawFd),
+}
+
+impl BindAddr {
+    /// Parses a bind string, this is either `host:port`,
+    /// `unix:/path/to.sock`, `fd://N` to adopt a socket that has
+    /// already been bound or `fd://` to adopt the first socket passed by
+    /// systemd socket activation.
+    pub fn parse(bind: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = bind.strip_prefix("unix:") {
+                return Ok(Self::Unix(PathBuf::from(path)))
+            }
+
+            if let Some(fd) = bind.strip_prefix("fd://") {
+                if fd.is_empty() {
+                    return listen_fds().map(Self::Fd)
+                }
+
+                return fd.parse()
+                    .map(Self::Fd)
+                    .map_err(|_| invalid_bind(bind))
+            }
+        }
+
+        bind.parse()
+            .map(Self::Tcp)
+            .map_err(|_| invalid_bind(bind))
+    }
+
+    /// If the socket is shared between workers rather than each worker
+    /// binding its own with `SO_REUSEPORT`.
+    #[cfg(unix)]
+    pub fn is_shared(&self) -> bool {
+        !matches!(self, Self::Tcp(_))
+    }
+}
+
+/// The error returned for a bind string that can not be parsed.
+fn invalid_bind(bind: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "invalid bind address {:?}, expected 'host:port', \
+             'unix:/path/to.sock' or 'fd://N'",
+            bind,
+        ),
+    )
+}
+
+/// The first socket passed by systemd socket activation.
+#[cfg(unix)]
+fn listen_fds() -> io::Result<RawFd> {
+    let not_activated = || io::Error::new(
+        io::ErrorKind::NotFound,
+        "no sockets were passed with LISTEN_FDS",
+    );
+
+    // The sockets may have been meant for a parent process.
+    if let Ok(pid) = std::env::var("LISTEN_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return Err(not_activated())
+        }
+    }
+
+    let count: usize = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+
+    if count == 0 {
+        return Err(not_activated())
+    }
+
+    Ok(LISTEN_FDS_START)
+}
+
+
+/// The address a listener is bound to or a stream is connected to.
+#[derive(Clone, Debug)]
+pub enum Addr {
+    /// A TCP address.
+    Inet(SocketAddr),
+
+    /// A Unix socket path, this is `None` for unnamed sockets e.g. the
+    /// client end of most connections.
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inet(addr) => write!(f, "{}", addr),
+
+            #[cfg(unix)]
+            Self::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+
+            #[cfg(unix)]
+            Self::Unix(None) => write!(f, "unix:<unnamed>"),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<mio::net::SocketAddr> for Addr {
+    fn from(addr: mio::net::SocketAddr) -> Self {
+        Self::Unix(addr.as_pathname().map(PathBuf::from))
+    }
+}
+
+
+/// A connected stream that a client can be served over.
+pub trait Connection: Read + Write + SendFile + Source {
+    /// The address of the local end of the stream.
+    fn local_addr(&self) -> io::Result<Addr>;
+
+    /// Shuts down the read, write or both halves of the stream.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn local_addr(&self) -> io::Result<Addr> {
+        TcpStream::local_addr(self).map(Addr::Inet)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn local_addr(&self) -> io::Result<Addr> {
+        UnixStream::local_addr(self).map(Addr::from)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+
+/// A stream accepted by any of the listener types.
+pub enum Stream {
+    Tcp(TcpStream),
+
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// Calls the method on whichever stream type is held.
+macro_rules! each_stream {
+    ($stream:expr, $inner:ident => $call:expr) => {
+        match $stream {
+            Stream::Tcp($inner) => $call,
+
+            #[cfg(unix)]
+            Stream::Unix($inner) => $call,
+        }
+    };
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        each_stream!(self, stream => stream.read(buf))
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        each_stream!(self, stream => stream.read_vectored(bufs))
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        each_stream!(self, stream => stream.write(buf))
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        each_stream!(self, stream => stream.write_vectored(bufs))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        each_stream!(self, stream => stream.flush())
+    }
+}
+
+impl SendFile for Stream {
+    fn send_file(&mut self, region: &FileRegion) -> io::Result<usize> {
+        each_stream!(self, stream => stream.send_file(region))
+    }
+}
+
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        each_stream!(self, stream => stream.register(registry, token, interests))
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        each_stream!(self, stream => stream.reregister(registry, token, interests))
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        each_stream!(self, stream => stream.deregister(registry))
+    }
+}
+
+impl Connection for Stream {
+    fn local_addr(&self) -> io::Result<Addr> {
+        each_stream!(self, stream => Connection::local_addr(stream))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        each_stream!(self, stream => Connection::shutdown(stream, how))
+    }
+}
+
+
+/// A listening socket of any of the supported types.
+pub enum Listener {
+    Tcp(TcpListener),
+
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds a listener to the address.
+    ///
+    /// If `reuse_port` is set a TCP listener is bound with `SO_REUSEPORT`
+    /// allowing several servers to share the same address.
+    pub fn bind(addr: &BindAddr, reuse_port: bool) -> io::Result<Self> {
+        match addr {
+            BindAddr::Tcp(addr) if reuse_port => {
+                bind_reuse_port(*addr).map(Self::Tcp)
+            },
+            BindAddr::Tcp(addr) => TcpListener::bind(*addr).map(Self::Tcp),
+
+            #[cfg(unix)]
+            BindAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                UnixListener::bind(path).map(Self::Unix)
+            },
+
+            #[cfg(unix)]
+            BindAddr::Fd(fd) => unsafe { Self::from_raw_fd(*fd) },
+        }
+    }
+
+    /// Adopts a socket that has already been bound and is listening.
+    ///
+    /// # Safety
+    /// The file descriptor must be a open socket that nothing else owns,
+    /// it is only taken over if it is adopted and is left open otherwise.
+    #[cfg(unix)]
+    unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Self> {
+        // Only a TCP socket has a address that is understood by std.
+        let tcp = std::net::TcpListener::from_raw_fd(fd);
+        if tcp.local_addr().is_ok() {
+            if let Err(e) = tcp.set_nonblocking(true) {
+                let _ = tcp.into_raw_fd();
+                return Err(e)
+            }
+
+            return Ok(Self::Tcp(TcpListener::from_std(tcp)))
+        }
+
+        let unix = std::os::unix::net::UnixListener::from_raw_fd(
+            tcp.into_raw_fd()
+        );
+
+        // Anything else e.g. a file is not a listener at all, it is left
+        // open for whatever owns it.
+        if let Err(e) = unix.local_addr().and_then(|_| unix.set_nonblocking(true)) {
+            let _ = unix.into_raw_fd();
+            return Err(e)
+        }
+
+        Ok(Self::Unix(UnixListener::from_std(unix)))
+    }
+
+    /// Creates a new handle to the same listening socket, this is used
+    /// to share a socket between workers that each poll their own handle.
+    #[cfg(unix)]
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let fd = match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(listener) => listener.as_raw_fd(),
+        };
+
+        let duplicate = unsafe { dup_fd(fd)? };
+        unsafe {
+            Self::from_raw_fd(duplicate).map_err(|e| {
+                // The duplicate is ours so it has to be closed here.
+                drop(std::net::TcpListener::from_raw_fd(duplicate));
+                e
+            })
+        }
+    }
+
+    /// Accepts a waiting connection.
+    ///
+    /// Returns the stream and the address of the remote end.
+    pub fn accept(&self) -> io::Result<(Stream, Addr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), Addr::Inet(addr)))
+            },
+
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Unix(stream), Addr::from(addr)))
+            },
+        }
+    }
+
+    /// The address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(Addr::Inet),
+
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.local_addr().map(Addr::from),
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.register(registry, token, interests),
+
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.reregister(registry, token, interests),
+
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.deregister(registry),
+
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+/// Duplicates the file descriptor, the duplicate is closed on exec the
+/// same as every socket std creates.
+#[cfg(unix)]
+unsafe fn dup_fd(fd: RawFd) -> io::Result<RawFd> {
+    // The descriptor is only borrowed to duplicate it.
+    let borrowed = std::mem::ManuallyDrop::new(
+        std::net::TcpListener::from_raw_fd(fd)
+    );
+
+    borrowed.try_clone().map(IntoRawFd::into_raw_fd)
+}
+
+/// Removes a socket left behind by a previous server at the path, any
+/// other kind of file or a socket that is still being listened on is
+/// left for the bind to fail on.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {},
+        _ => return Ok(()),
+    }
+
+    // Only a socket nothing is listening on refuses the connection.
+    match std::os::unix::net::UnixStream::connect(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path)
+        },
+        _ => Ok(()),
+    }
+}
+
+
+/// Binds a listener with `SO_REUSEPORT` set so the kernel spreads new
+/// connections between every listener bound to the address.
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// `SO_REUSEPORT` is only available on unix platforms.
+#[cfg(not(unix))]
+fn bind_reuse_port(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
@@ -2,19 +2,27 @@
 // internal imports
 use crate::pyre_server::switch::{Switchable, SwitchStatus, SelectedProtocol};
 use crate::pyre_server::py_callback::CallbackHandler;
+use crate::pyre_server::config::SharedConfig;
 use crate::pyre_server::transport::EventLoopHandle;
 use crate::pyre_server::abc::{ProtocolBuffers, SocketCommunicator};
+use crate::pyre_server::socket_io::WriteBuffer;
+use crate::pyre_server::buffer_pool::BufferPool;
+use crate::pyre_server::net::Addr;
 
 // protocols
-use crate::pyre_server::protocols::h1;
+use crate::pyre_server::protocols::{h1, ws, h2};
+use crate::pyre_server::protocols::h1::Upgrade;
 
 use bytes::BytesMut;
 use mio::Token;
-use std::error::Error;
+use std::mem;
+use std::time::Instant;
 use pyo3::PyResult;
 
 
-const MAX_BUFFER_LIMIT: usize = 256 * 1024;
+/// The amount of unwritten data past which no more events are taken
+/// from the app, the app's sends wait until the socket catches up.
+const WRITE_HIGH_WATER: usize = 64 * 1024;
 
 
 /// A changeable protocol which does not modify the external API.
@@ -28,18 +36,45 @@ pub struct AutoProtocol {
     /// The event loop handle, this handles all event loop interactions.
     event_loop: EventLoopHandle,
 
+    /// The python callbacks to invoke on a request.
+    callback: CallbackHandler,
+
+    /// The server settings shared with every client.
+    config: SharedConfig,
+
     /// The http/1 protocol handler.
     h1: h1::H1Protocol,
 
+    /// The WebSocket protocol handler.
+    ws: ws::WebSocketProtocol,
+
+    /// The http/2 protocol handler.
+    h2: h2::H2Protocol,
+
     /// The writer buffer that covers all protocols, this saves memory as
     /// we have to create each protocol instance per client so we dont want
-    /// to be creating 3 * 256KB every time.
-    writer_buffer: BytesMut,
+    /// to be creating 3 buffers every time.
+    writer_buffer: WriteBuffer,
 
     /// The reader buffer that covers all protocols, this saves memory as
     /// we have to create each protocol instance per client so we dont want
-    /// to be creating 3 * 256KB every time.
+    /// to be creating 3 buffers every time.
     reader_buffer: BytesMut,
+
+    /// The pool the buffers are taken from, they are only held while the
+    /// connection has data to handle and are empty otherwise.
+    buffers: BufferPool,
+
+    /// If the reader buffer has been taken from the pool.
+    reader_pooled: bool,
+
+    /// If the contiguous part of the writer buffer has been taken from
+    /// the pool.
+    writer_pooled: bool,
+
+    /// The amount of read data waiting on the app past which reading
+    /// from the socket is paused.
+    read_high_water: usize,
 }
 
 impl AutoProtocol {
@@ -50,61 +85,167 @@ impl AutoProtocol {
         selected: SelectedProtocol,
         event_loop: EventLoopHandle,
         callback: CallbackHandler,
+        config: SharedConfig,
+        buffers: BufferPool,
     ) -> Self {
 
-        let mut h1 = h1::H1Protocol::new(
+        let h1 = h1::H1Protocol::new(
             token,
-            callback,
-             event_loop.clone(),
+            callback.clone(),
+            event_loop.clone(),
+            config.clone(),
+        );
+
+        let ws = ws::WebSocketProtocol::new(
+            token,
+            callback.clone(),
+            event_loop.clone(),
+            config.clone(),
+        );
+
+        let h2 = h2::H2Protocol::new(
+            token,
+            callback.clone(),
+            event_loop.clone(),
+            config.clone(),
         );
 
-        let buff1 = BytesMut::with_capacity(MAX_BUFFER_LIMIT);
-        let buff2 = BytesMut::with_capacity(MAX_BUFFER_LIMIT);
+        let read_high_water = config.read_high_water;
 
         Self {
             token,
             selected,
             event_loop,
+            callback,
+            config,
             h1,
-            writer_buffer: buff1,
-            reader_buffer: buff2,
+            ws,
+            h2,
+            writer_buffer: WriteBuffer::default(),
+            reader_buffer: BytesMut::new(),
+            buffers,
+            reader_pooled: false,
+            writer_pooled: false,
+            read_high_water,
         }
     }
 }
 
 impl AutoProtocol {
     /// Called when the protocol is in charge of a new socket / handle.
-    pub fn new_connection(&mut self) -> PyResult<()> {
-        match self.selected {
-            SelectedProtocol::H1 => self.h1.new_connection()?,
-        };
+    pub fn new_connection(
+        &mut self,
+        client: Addr,
+        server: Addr,
+        secure: bool,
+    ) -> PyResult<()> {
+        // Both are given the addresses as HTTP/2 is reached either by
+        // switching from HTTP/1 or by selecting it up front.
+        self.h1.new_connection(client.clone(), server.clone(), secure)?;
+        self.h2.new_connection(client, server, secure)?;
 
         Ok(())
     }
 
+    /// Sets the protocol used before any data has been read e.g. the
+    /// listener's default or the one agreed with TLS ALPN.
+    pub fn select_protocol(&mut self, selected: SelectedProtocol) -> PyResult<()> {
+        if let SelectedProtocol::H2 = selected {
+            self.h2.open(None)?;
+        }
+
+        self.selected = selected;
+
+        Ok(())
+    }
+
+    /// Resets the protocol ready to handle a new connection with the
+    /// given token.
+    ///
+    /// Every protocol is rebuilt so no parser state or ASGI channels are
+    /// carried over, any handles the app still holds from the previous
+    /// connection find their channels closed.
+    pub fn reset(&mut self, token: Token) {
+        self.writer_buffer.clear();
+        self.reader_buffer.clear();
+        self.release_buffers();
+
+        self.h1 = h1::H1Protocol::new(
+            token,
+            self.callback.clone(),
+            self.event_loop.clone(),
+            self.config.clone(),
+        );
+
+        self.ws = ws::WebSocketProtocol::new(
+            token,
+            self.callback.clone(),
+            self.event_loop.clone(),
+            self.config.clone(),
+        );
+
+        self.h2 = h2::H2Protocol::new(
+            token,
+            self.callback.clone(),
+            self.event_loop.clone(),
+            self.config.clone(),
+        );
+
+        self.token = token;
+        self.selected = SelectedProtocol::H1;
+    }
+
     /// Called when the connection is lost from the protocol in order to
     /// properly reset state.
     pub fn lost_connection(&mut self) -> PyResult<()> {
-        self.reader_buffer.clear();
         self.writer_buffer.clear();
+        self.release_buffers();
 
-        return match self.selected {
+        let result = match self.selected {
             SelectedProtocol::H1 => {
                 self.h1.lost_connection()
             },
-        }
+            SelectedProtocol::WS => {
+                self.ws.lost_connection()
+            },
+            SelectedProtocol::H2 => {
+                self.h2.lost_connection()
+            },
+        };
+
+        // The next connection always starts out as HTTP/1.
+        self.selected = SelectedProtocol::H1;
+
+        result
     }
 }
 
 impl AutoProtocol {
     /// Allows the chance to switch protocol just after reading has
-    /// finished.
-    pub fn maybe_switch(&mut self) -> PyResult<SwitchStatus> {
-        return match self.selected {
-            SelectedProtocol::H1 => {
-                self.h1.switch_protocol()
-            },
-        }
+    /// finished, the new protocol takes over the connection straight away.
+    pub fn maybe_switch(&mut self) -> PyResult<()> {
+        let status = match self.selected {
+            SelectedProtocol::H1 => self.h1.switch_protocol()?,
+            SelectedProtocol::WS => self.ws.switch_protocol()?,
+            SelectedProtocol::H2 => self.h2.switch_protocol()?,
+        };
+
+        let selected = match status {
+            SwitchStatus::SwitchTo(selected) => selected,
+            SwitchStatus::NoSwitch => return Ok(()),
+        };
+
+        match self.h1.take_upgrade() {
+            Some(Upgrade::WebSocket(handshake)) => self.ws.open(handshake)?,
+            Some(Upgrade::H2(upgrade)) => self.h2.open(upgrade)?,
+            None => {},
+        };
+
+        self.selected = selected;
+
+        // Anything read after the upgrade e.g. the HTTP/2 preface is
+        // already in the buffer so will not trigger another read.
+        self.process_buffered()
     }
 
     /// Pauses reading from the event loop and notifies the protocol of
@@ -114,12 +255,129 @@ impl AutoProtocol {
         Ok(())
     }
 
-    /// The EOF has been sent by the socket.
-    pub fn eof_received(&mut self) -> PyResult<()> {
+    /// If the protocol has finished with the connection and it should be
+    /// closed once the write buffer has been drained.
+    pub fn should_close(&self) -> bool {
         match self.selected {
-            SelectedProtocol::H1 => {
-                self.h1.eof_received()
-            },
+            SelectedProtocol::H1 => self.h1.should_close(),
+            SelectedProtocol::WS => self.ws.should_close(),
+            SelectedProtocol::H2 => self.h2.should_close(),
+        }
+    }
+
+    /// Called when the server is shutting down, the protocol finishes
+    /// what is in progress and then closes the connection.
+    pub fn shutdown(&mut self) {
+        match self.selected {
+            SelectedProtocol::H1 => self.h1.shutdown(),
+            SelectedProtocol::WS => self.ws.shutdown(),
+            SelectedProtocol::H2 => self.h2.shutdown(),
+        }
+    }
+
+    /// If the protocol is between requests and waiting on a new one.
+    pub fn is_awaiting_request(&self) -> bool {
+        match self.selected {
+            SelectedProtocol::H1 => self.h1.is_awaiting_request(),
+            SelectedProtocol::WS => self.ws.is_awaiting_request(),
+            SelectedProtocol::H2 => self.h2.is_awaiting_request(),
+        }
+    }
+
+    /// The time the current request must make progress by.
+    pub fn request_deadline(&self, last_activity: Instant) -> Option<Instant> {
+        match self.selected {
+            SelectedProtocol::H1 => self.h1.request_deadline(last_activity),
+            SelectedProtocol::WS | SelectedProtocol::H2 => None,
+        }
+    }
+
+    /// Checks the current request against its deadline, returning the
+    /// time it should next be checked at.
+    pub fn check_timeouts(
+        &mut self,
+        now: Instant,
+        last_activity: Instant,
+    ) -> Option<Instant> {
+        match self.selected {
+            SelectedProtocol::H1 => self.h1.check_timeouts(now, last_activity),
+            SelectedProtocol::WS | SelectedProtocol::H2 => None,
+        }
+    }
+
+    /// If enough read data is waiting on the app that no more should be
+    /// read from the socket until the app has received some of it.
+    ///
+    /// Data the protocol is still parsing only counts once some of it
+    /// has been handed to the app, a message larger than the limit is
+    /// still read in full.
+    pub fn is_backlogged(&self) -> bool {
+        let (queued, blocked) = match self.selected {
+            SelectedProtocol::H1 => (self.h1.queued_bytes(), self.h1.is_blocked()),
+            SelectedProtocol::WS => (self.ws.queued_bytes(), self.ws.is_blocked()),
+
+            // Complete frames are always taken from the buffer and their
+            // data is limited by the connection's flow control window,
+            // only a partial frame is ever left behind.
+            SelectedProtocol::H2 => return false,
+        };
+
+        let buffered = self.reader_buffer.len();
+        if blocked & (buffered >= self.read_high_water) {
+            return true
+        }
+
+        (queued > 0) & (queued + buffered >= self.read_high_water)
+    }
+
+    /// Sets whether reading has been paused for the backlog, the app
+    /// resumes it once it has drained the data waiting on it.
+    pub fn set_backlog_paused(&self, paused: bool) {
+        match self.selected {
+            SelectedProtocol::H1 => self.h1.set_backlog_paused(paused),
+            SelectedProtocol::WS => self.ws.set_backlog_paused(paused),
+            SelectedProtocol::H2 => {},
+        }
+    }
+
+    /// Processes any data left over in the read buffer, this is invoked
+    /// when reading is resumed after the protocol paused it.
+    pub fn process_buffered(&mut self) -> PyResult<()> {
+        // HTTP/2 streams queue their body chunks outside of the buffer.
+        if let SelectedProtocol::H2 = self.selected {
+            self.h2.resume_streams();
+        }
+
+        if self.reader_buffer.is_empty() {
+            return Ok(())
+        }
+
+        self.read_buffer_filled(0)
+    }
+
+    /// Gives the buffers back to the pool if they are both empty, this
+    /// is done whenever the connection is between requests so idle
+    /// connections do not hold on to any memory.
+    pub fn release_idle_buffers(&mut self) {
+        let is_empty = self.reader_buffer.is_empty()
+            & self.writer_buffer.is_empty();
+
+        if is_empty && self.is_awaiting_request() {
+            self.release_buffers();
+        }
+    }
+
+    /// Gives any held buffers back to the pool discarding their data.
+    fn release_buffers(&mut self) {
+        if self.reader_pooled {
+            self.buffers.release(mem::take(&mut self.reader_buffer));
+            self.reader_pooled = false;
+        }
+
+        // Only the contiguous part of the write buffer is pooled.
+        if self.writer_pooled {
+            self.buffers.release(mem::take(&mut *self.writer_buffer));
+            self.writer_pooled = false;
         }
     }
 }
@@ -128,26 +386,58 @@ impl SocketCommunicator for AutoProtocol {
     /// Called when data is able to be read from the socket, the returned
     /// buffer is filled and then the read_buffer_filled callback is invoked.
     fn read_buffer_acquire(&mut self) -> PyResult<&mut BytesMut> {
+        if !self.reader_pooled {
+            self.reader_buffer = self.buffers.acquire();
+            self.reader_pooled = true;
+        }
+
+        self.buffers.reserve(&mut self.reader_buffer);
+
         Ok(&mut self.reader_buffer)
     }
 
     /// Called when data is able to be read from the socket, the returned
     /// buffer is filled and then the read_buffer_filled callback is invoked.
     fn read_buffer_filled(&mut self, _amount: usize) -> PyResult<()> {
-        return match self.selected {
+        match self.selected {
             SelectedProtocol::H1 => {
-                self.h1.data_received(&mut self.reader_buffer)
+                self.h1.data_received(&mut self.reader_buffer)?
             },
-        }
+            SelectedProtocol::WS => {
+                self.ws.data_received(&mut self.reader_buffer)?
+            },
+            SelectedProtocol::H2 => {
+                self.h2.data_received(&mut self.reader_buffer)?
+            },
+        };
+
+        self.release_idle_buffers();
+
+        Ok(())
     }
 
     /// Called when data is able to be read from the socket, the returned
     /// buffer is filled and then the read_buffer_filled callback is invoked.
-    fn write_buffer_acquire(&mut self) -> PyResult<&mut BytesMut> {
+    fn write_buffer_acquire(&mut self) -> PyResult<&mut WriteBuffer> {
+        if self.writer_buffer.len() >= WRITE_HIGH_WATER {
+            return Ok(&mut self.writer_buffer)
+        }
+
+        if !self.writer_pooled {
+            *self.writer_buffer = self.buffers.acquire();
+            self.writer_pooled = true;
+        }
+
         match self.selected {
             SelectedProtocol::H1 => {
                 self.h1.fill_write_buffer(&mut self.writer_buffer)?;
             },
+            SelectedProtocol::WS => {
+                self.ws.fill_write_buffer(&mut self.writer_buffer)?;
+            },
+            SelectedProtocol::H2 => {
+                self.h2.fill_write_buffer(&mut self.writer_buffer)?;
+            },
         };
 
         Ok(&mut self.writer_buffer)
@@ -156,10 +446,12 @@ impl SocketCommunicator for AutoProtocol {
     /// Called when data is able to be read from the socket, the returned
     /// buffer is filled and then the read_buffer_filled callback is invoked.
     fn write_buffer_drained(&mut self, amount: usize) -> PyResult<()> {
-        if (amount == 0) | (self.writer_buffer.len() == 0) {
+        if (amount == 0) | self.writer_buffer.is_empty() {
             self.pause_writing()?;
         }
 
+        self.release_idle_buffers();
+
         Ok(())
     }
 }
\ No newline at end of file
@@ -1,35 +1,121 @@
-// `mem::uninitialized` replaced with `mem::MaybeUninit`,
-// can't upgrade yet
-#![allow(deprecated)]
-
-use crate::pyre_server::abc::{ProtocolBuffers, BaseTransport};
-use crate::pyre_server::switch::{Switchable, SwitchStatus};
+use crate::pyre_server::abc::ProtocolBuffers;
+use crate::pyre_server::switch::{Switchable, SwitchStatus, SelectedProtocol};
 use crate::pyre_server::transport::EventLoopHandle;
-use crate::pyre_server::py_callback::CallbackHandler;
+use crate::pyre_server::py_callback::{CallbackHandler, Permit};
+use crate::pyre_server::config::SharedConfig;
+use crate::pyre_server::responders::{
+    SenderPayload,
+    ReceiverPayload,
+    ResponseHeaders,
+};
 use crate::pyre_server::responders::sender::SenderHandler;
 use crate::pyre_server::responders::receiver::ReceiverHandler;
+use crate::pyre_server::protocols::ws::{self, Handshake};
+use crate::pyre_server::protocols::h2::{self, H2Upgrade};
+use crate::pyre_server::protocols::scope;
+use crate::pyre_server::socket_io::{WriteBuffer, Chunk};
+use crate::pyre_server::net::Addr;
 
 use pyo3::{PyResult, Python, Py};
-use pyo3::types::PyBytes;
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::types::{PyBytes, PyDict, PyList};
 
-use std::mem;
-use std::sync::Arc;
+use std::cmp;
 use std::str;
+use std::time::{Duration, Instant, SystemTime};
 
-use bytes::{BytesMut, Bytes};
+use bytes::{Bytes, BytesMut, BufMut};
 use mio::Token;
-use crossbeam::channel::{Sender, Receiver, unbounded};
 
-use httparse::{Status, parse_chunk_size, Header, Request};
-use http::version::Version;
-use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
-use std::error::Error;
+use httparse::{
+    Status,
+    Error as ParseError,
+    InvalidChunkSize,
+    parse_chunk_size,
+    Header,
+    Request,
+    EMPTY_HEADER,
+};
+use http::StatusCode;
+use http::header::{
+    CONTENT_LENGTH,
+    TRANSFER_ENCODING,
+    CONNECTION,
+    DATE,
+    SERVER,
+};
 
 
 /// The max headers allowed in a single request.
 const MAX_HEADERS: usize = 100;
 
+/// The time a request body is given before the minimum body rate is
+/// enforced, this stops a slow start from failing the request.
+const BODY_RATE_GRACE: Duration = Duration::from_secs(5);
+
+/// The start of the HTTP/2 connection preface sent by clients with
+/// prior knowledge.
+const H2_PREFACE_START: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+
+/// A request that switches the connection to another protocol.
+pub enum Upgrade {
+    /// A WebSocket handshake.
+    WebSocket(Handshake),
+
+    /// A HTTP/2 connection, this is `None` if the client started with
+    /// the HTTP/2 preface rather than a `Upgrade: h2c` request.
+    H2(Option<H2Upgrade>),
+}
+
+
+/// The state of the current request on the connection.
+#[derive(Copy, Clone, PartialEq)]
+enum RequestState {
+    /// Waiting on a complete request head.
+    AwaitingHead,
+
+    /// The head has been parsed and the body is being read.
+    ReadingBody,
+
+    /// The request has been read and the app is still responding.
+    AwaitingResponse,
+
+    /// The request is a WebSocket or HTTP/2 upgrade waiting on the
+    /// protocol to be switched.
+    Upgrading,
+
+    /// The connection can not be kept alive so it is closed once the
+    /// response has been written.
+    Done,
+}
+
+
+/// The state of the chunked transfer decoder.
+enum ChunkState {
+    /// Waiting on the `<size>\r\n` line of the next chunk.
+    Size,
+
+    /// Reading the chunk data with the given amount remaining.
+    Data(usize),
+
+    /// Waiting on the `\r\n` that ends the chunk data.
+    DataEnd,
+
+    /// Skipping the trailers after the last chunk.
+    Trailers,
+}
+
+
+/// How the body of a request is framed.
+#[derive(Debug, PartialEq)]
+enum BodyFraming {
+    /// The body is the given length, a request without any framing
+    /// headers has no body.
+    Length(usize),
+
+    /// The body is sent with chunked transfer encoding.
+    Chunked,
+}
 
 
 /// The protocol to add handling for the HTTP/1.x protocol.
@@ -51,9 +137,59 @@ pub struct H1Protocol {
     /// The receiver half handler for ASGI callbacks.
     receiver: ReceiverHandler,
 
+    /// The amount of the body that is still to be read when the body
+    /// is framed by `Content-Length`.
     expected_content_length: usize,
 
+    /// If the body is framed with chunked transfer encoding.
     chunked_encoding: bool,
+
+    /// The state of the current request, requests are handled one at a
+    /// time so any pipelined requests wait in the buffer.
+    state: RequestState,
+
+    /// If the client allows the connection to be kept alive after the
+    /// current request.
+    keep_alive: bool,
+
+    /// The decoder for a chunked body.
+    chunks: ChunkDecoder,
+
+    /// The remote address of the connected client.
+    client: Option<Addr>,
+
+    /// The local address the client connected to.
+    server: Option<Addr>,
+
+    /// If the connection is encrypted with TLS.
+    secure: bool,
+
+    /// The serializer for the response events sent by the app.
+    writer: ResponseWriter,
+
+    /// The shared server settings.
+    config: SharedConfig,
+
+    /// The time the first part of the current request head was received.
+    head_started: Option<Instant>,
+
+    /// The time the head was parsed if the request has a body.
+    body_started: Option<Instant>,
+
+    /// The amount of body bytes received for the current request.
+    body_received: usize,
+
+    /// The upgrade waiting to be handed over once the protocol
+    /// is switched.
+    upgrade: Option<Upgrade>,
+
+    /// If the server is shutting down, the connection is closed after
+    /// the current response.
+    draining: bool,
+
+    /// The in-flight permit taken before reading the next request, this
+    /// is handed to the app along with the request.
+    permit: Option<Permit>,
 }
 
 impl H1Protocol {
@@ -62,6 +198,7 @@ impl H1Protocol {
         token: Token,
         callback: CallbackHandler,
         event_loop: EventLoopHandle,
+        config: SharedConfig,
     ) -> Self {
         let sender = SenderHandler::new(
             token,
@@ -69,7 +206,8 @@ impl H1Protocol {
         );
         let receiver = ReceiverHandler::new(
             token,
-            event_loop.clone()
+            event_loop.clone(),
+            &config,
         );
 
         Self {
@@ -81,69 +219,267 @@ impl H1Protocol {
 
             expected_content_length: 0,
             chunked_encoding: false,
+            state: RequestState::AwaitingHead,
+            keep_alive: false,
+            chunks: ChunkDecoder::new(),
+
+            client: None,
+            server: None,
+            secure: false,
+
+            writer: ResponseWriter::new(config.clone()),
+            config,
+
+            head_started: None,
+            body_started: None,
+            body_received: 0,
+
+            upgrade: None,
+            draining: false,
+            permit: None,
         }
     }
 }
 
 impl H1Protocol {
     /// Called when the protocol is in charge of a new socket / handle.
-    pub fn new_connection(&mut self) -> PyResult<()> {
+    pub fn new_connection(
+        &mut self,
+        client: Addr,
+        server: Addr,
+        secure: bool,
+    ) -> PyResult<()> {
+        self.client = Some(client);
+        self.server = Some(server);
+        self.secure = secure;
+
         Ok(())
     }
 
     /// Called when the connection is lost from the protocol in order to
     /// properly reset state.
     pub fn lost_connection(&mut self) -> PyResult<()> {
+        self.receiver.set_disconnected(true);
+        self.reset_request();
+        self.state = RequestState::AwaitingHead;
+        self.head_started = None;
+        self.upgrade = None;
+        self.draining = false;
+        self.permit = None;
+
         Ok(())
     }
+
+    /// Called when the server is shutting down, a request that is in
+    /// progress is responded to with `Connection: close` and an idle
+    /// connection is closed straight away.
+    pub fn shutdown(&mut self) {
+        self.draining = true;
+        self.writer.disable_keep_alive();
+
+        let is_idle = (self.state == RequestState::AwaitingHead)
+            & self.head_started.is_none();
+
+        if is_idle {
+            self.state = RequestState::Done;
+            self.event_loop.resume_writing(self.token);
+        }
+    }
+
+    /// If the connection is between requests and waiting on a new one.
+    pub fn is_awaiting_request(&self) -> bool {
+        (self.state == RequestState::AwaitingHead)
+            | (self.state == RequestState::Done)
+    }
+
+    /// The amount of the request body handed to the app that it has
+    /// not received yet.
+    pub fn queued_bytes(&self) -> usize {
+        self.receiver.queued()
+    }
+
+    /// If data in the read buffer is left waiting on the app rather than
+    /// being processed e.g. pipelined requests behind the current one.
+    pub fn is_blocked(&self) -> bool {
+        (self.state == RequestState::AwaitingResponse)
+            | (self.state == RequestState::Upgrading)
+    }
+
+    /// Sets whether reading has been paused until the app receives the
+    /// request body waiting on it.
+    pub fn set_backlog_paused(&self, paused: bool) {
+        self.receiver.set_paused(paused);
+    }
+
+    /// If the connection should be closed once the write buffer has
+    /// been drained.
+    pub fn should_close(&self) -> bool {
+        self.state == RequestState::Done
+    }
+
+    /// Takes the upgrade once the protocol has been switched, the
+    /// connection is no longer handled by this protocol after this.
+    pub fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.state = RequestState::AwaitingHead;
+        self.permit = None;
+        self.upgrade.take()
+    }
+
+    /// The time the current request must make progress by, this covers
+    /// reading the request head and the minimum body rate.
+    ///
+    /// Returns `None` if there is no deadline for the current state.
+    pub fn request_deadline(&self, last_activity: Instant) -> Option<Instant> {
+        match self.state {
+            RequestState::AwaitingHead => {
+                let started = self.head_started?;
+
+                Some(cmp::min(
+                    last_activity + self.config.header_timeout,
+                    started + self.config.head_timeout,
+                ))
+            },
+            RequestState::ReadingBody => {
+                // The app not consuming the body is not the client's fault.
+                if self.receiver.is_full() {
+                    return None
+                }
+
+                let rate = self.config.min_body_rate?;
+                let started = self.body_started?;
+                let expected = Duration::from_secs_f64(
+                    self.body_received as f64 / rate
+                );
+
+                Some(started + cmp::max(BODY_RATE_GRACE, expected))
+            },
+            _ => None,
+        }
+    }
+
+    /// Checks the current request against its deadline responding with
+    /// `408 Request Timeout` if it has been missed.
+    ///
+    /// Returns the time the request should next be checked at.
+    pub fn check_timeouts(
+        &mut self,
+        now: Instant,
+        last_activity: Instant,
+    ) -> Option<Instant> {
+        let deadline = self.request_deadline(last_activity)?;
+        if deadline > now {
+            return Some(deadline)
+        }
+
+        self.send_error(StatusCode::REQUEST_TIMEOUT);
+
+        None
+    }
 }
 
 impl ProtocolBuffers for H1Protocol {
     fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
-        // This should be fine as it is guaranteed to be initialised
-        // before we use it, just waiting for the ability to use
-        // MaybeUninit, till then here we are.
-        let mut headers: [Header<'_>; MAX_HEADERS] = unsafe {
-            mem::uninitialized()
-        };
+        loop {
+            match self.state {
+                RequestState::AwaitingHead => {
+                    // The next request is left in the buffer until the
+                    // app is able to take on another one.
+                    if self.permit.is_none() & !buffer.is_empty() {
+                        let permit = self.callback.acquire_or_wait(
+                            self.token,
+                            &self.event_loop,
+                        );
 
-        let body = buffer.clone();
+                        if permit.is_none() {
+                            self.event_loop.pause_reading(self.token);
+                            return Ok(())
+                        }
 
-        let mut request = Request::new(&mut headers);
-        let status = match request.parse(&body) {
-            Ok(status) => status,
-            Err(e) => {
-                eprintln!("{:?}", e);
-                return Ok(())
-            }
-        };
+                        self.permit = permit;
+                    }
 
-        let len= if status.is_partial() {
-            return Ok(())
-        } else {
-            status.unwrap()
-        };
+                    if self.head_started.is_none() & !buffer.is_empty() {
+                        self.head_started = Some(Instant::now());
+                    }
 
-        let _ = buffer.split_to(len);
+                    // Clients with prior knowledge of HTTP/2 start with
+                    // the preface straight away, it is left in the
+                    // buffer for the HTTP/2 protocol.
+                    if buffer.starts_with(H2_PREFACE_START) {
+                        self.upgrade = Some(Upgrade::H2(None));
+                        self.state = RequestState::Upgrading;
+                        return Ok(())
+                    }
 
-        self.on_request_parse(&mut request)?;
+                    // The preface may be split across reads, the rest of
+                    // it is waited on rather than parsing it as HTTP/1.
+                    if !buffer.is_empty() && H2_PREFACE_START.starts_with(buffer) {
+                        return Ok(())
+                    }
 
-        Ok(())
-    }
+                    if !self.parse_head(buffer)? {
+                        return Ok(())
+                    }
+                },
+                RequestState::ReadingBody => {
+                    self.read_body(buffer)?;
+
+                    if self.state == RequestState::ReadingBody {
+                        return Ok(())
+                    }
+                },
+                // Pipelined requests wait in the buffer until the
+                // current response has been completed.
+                RequestState::AwaitingResponse => return Ok(()),
 
-    fn fill_write_buffer(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
-        while let Ok((_more_body, buff)) = self.sender.recv() {
-            buffer.extend(buff);
+                // Anything after the upgrade request belongs to the
+                // switched protocol.
+                RequestState::Upgrading => return Ok(()),
+                RequestState::Done => {
+                    buffer.clear();
+                    return Ok(())
+                },
+            }
         }
+    }
 
-        self.event_loop.pause_writing(self.token);
+    fn fill_write_buffer(&mut self, buffer: &mut WriteBuffer) -> PyResult<()> {
+        // Checked first so every event sent before the app exited has
+        // been written by the time it is handled.
+        let exited = self.sender.app_exited();
 
-        Ok(())
-    }
+        while let Ok(payload) = self.sender.recv() {
+            match payload {
+                SenderPayload::Start(status, headers) => {
+                    self.writer.start(status, headers);
+                },
+                SenderPayload::Body((more_body, body)) => {
+                    let complete = self.writer.write_body(
+                        buffer,
+                        more_body,
+                        body,
+                    );
+
+                    if complete {
+                        self.response_complete();
+                    }
+                },
+
+                // WebSocket events have no meaning for a HTTP request.
+                SenderPayload::Accept(..)
+                | SenderPayload::Send(..)
+                | SenderPayload::Close(..) => {},
+            }
+        }
+
+        // The app exited without completing the response, this is a
+        // `500` if nothing has been written otherwise it is closed.
+        if exited.is_some() & !self.writer.is_complete() {
+            self.send_error(StatusCode::INTERNAL_SERVER_ERROR);
+        }
 
-    fn eof_received(&mut self) -> PyResult<()> {
-        self.event_loop.pause_reading(self.token);
         self.event_loop.pause_writing(self.token);
+
         Ok(())
     }
 }
@@ -153,58 +489,945 @@ impl Switchable for H1Protocol {
     /// necessary called just after reading has completed to allow
     /// for upgrading.
     fn switch_protocol(&mut self) -> PyResult<SwitchStatus> {
-        // ignore for now
-        Ok(SwitchStatus::NoSwitch)
+        match self.upgrade.as_ref() {
+            Some(Upgrade::WebSocket(_)) => {
+                Ok(SwitchStatus::SwitchTo(SelectedProtocol::WS))
+            },
+            Some(Upgrade::H2(_)) => {
+                Ok(SwitchStatus::SwitchTo(SelectedProtocol::H2))
+            },
+            None => Ok(SwitchStatus::NoSwitch),
+        }
     }
 }
 
 impl H1Protocol {
+    /// Parses the request head from the buffer, invoking the app once
+    /// the head is complete.
+    ///
+    /// Returns if a complete head was parsed.
+    fn parse_head(&mut self, buffer: &mut BytesMut) -> PyResult<bool> {
+        let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+
+        let mut request = Request::new(&mut headers);
+        let status = match request.parse(&buffer[..]) {
+            Ok(status) => status,
+            Err(ParseError::TooManyHeaders) => {
+                self.send_error(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                return Ok(false)
+            },
+            Err(_) => {
+                self.send_error(StatusCode::BAD_REQUEST);
+                return Ok(false)
+            },
+        };
+
+        let len = match status {
+            Status::Complete(len) => len,
+            Status::Partial => {
+                if buffer.len() > self.config.max_head_size {
+                    self.send_error(
+                        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                    );
+                }
+
+                return Ok(false)
+            },
+        };
+
+        if len > self.config.max_head_size {
+            self.send_error(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            return Ok(false)
+        }
+
+        // The request borrows from the buffer so the head is only split
+        // off once it has been handled.
+        let result = self.on_request_parse(&mut request);
+        let _ = buffer.split_to(len);
+        result?;
+
+        Ok(true)
+    }
+
     fn on_request_parse(&mut self, request: &mut Request) -> PyResult<()> {
-        let method = request.method
-            .expect("Value was None at complete parse");
-        let path = request.path
-            .expect("Value was None at complete parse");
-        let version = request.version
-            .expect("Value was None at complete parse");
+        // A complete parse always sets these but a bad request is sent
+        // rather than trusting the parser with the whole server.
+        let (method, path, version) = match (
+            request.method,
+            request.path,
+            request.version,
+        ) {
+            (Some(method), Some(path), Some(version)) => (method, path, version),
+            _ => {
+                self.send_error(StatusCode::BAD_REQUEST);
+                return Ok(())
+            },
+        };
 
+        // The permit is always taken before the head is parsed, a
+        // missing one is a bug that only fails this request.
+        let permit = match self.permit.take() {
+            Some(permit) => permit,
+            None => {
+                self.send_error(StatusCode::INTERNAL_SERVER_ERROR);
+                return Ok(())
+            },
+        };
+
+        self.reset_request();
+        self.head_started = None;
+        self.keep_alive = version > 0;
+        for header in request.headers.iter() {
+            self.check_header(header);
+        }
+
+        match body_framing(request.headers) {
+            Ok(BodyFraming::Length(len)) => self.expected_content_length = len,
+            Ok(BodyFraming::Chunked) => self.chunked_encoding = true,
+            Err(status) => {
+                self.send_error(status);
+                return Ok(())
+            },
+        }
 
-        let headers_new = Python::with_gil(|py| {
-            let mut parsed_vec = Vec::with_capacity(request.headers.len());
-            for header in request.headers.iter() {
-                self.check_header(&header);
+        if self.draining {
+            self.keep_alive = false;
+        }
 
-                let converted: Py<PyBytes> = Py::from(PyBytes::new(py, header.value));
-                parsed_vec.push((header.name, converted))
+        if let Some(max_size) = self.config.max_body_size {
+            if self.expected_content_length > max_size {
+                self.send_error(StatusCode::PAYLOAD_TOO_LARGE);
+                return Ok(())
             }
+        }
+
+        self.writer.reset(version, method == "HEAD", self.keep_alive);
+
+        match ws::handshake_accept_key(method, version, request.headers) {
+            Ok(Some(accept_key)) => {
+                let scope = Python::with_gil(|py| -> PyResult<Py<PyDict>> {
+                    let scope = self.build_scope(
+                        py,
+                        method,
+                        path,
+                        version,
+                        request.headers,
+                    )?;
+                    ws::upgrade_scope(py, scope, request.headers, self.secure)?;
+
+                    Ok(Py::from(scope))
+                })?;
+
+                let handshake = Handshake {
+                    scope,
+                    accept_key,
+                    permit,
+                };
+                self.upgrade = Some(Upgrade::WebSocket(handshake));
+                self.state = RequestState::Upgrading;
+                return Ok(())
+            },
+            Ok(None) => {},
+            Err(status) => {
+                self.send_error(status);
+                return Ok(())
+            },
+        };
+
+        // Requests with a body can not be upgraded to HTTP/2 as the
+        // body would have to be read before switching.
+        let has_body = self.chunked_encoding
+            | (self.expected_content_length > 0);
+        let h2c_settings = if (version == 1) & !has_body {
+            h2::h2c_settings(request.headers)
+        } else {
+            None
+        };
+
+        if let Some(settings) = h2c_settings {
+            let scope = Python::with_gil(|py| -> PyResult<Py<PyDict>> {
+                let scope = self.build_scope(
+                    py,
+                    method,
+                    path,
+                    version,
+                    request.headers,
+                )?;
+                scope.set_item("http_version", "2")?;
 
-            parsed_vec
-        });
+                Ok(Py::from(scope))
+            })?;
 
+            self.upgrade = Some(Upgrade::H2(Some(H2Upgrade {
+                scope,
+                settings,
+                is_head: method == "HEAD",
+                permit,
+            })));
+            self.state = RequestState::Upgrading;
+            return Ok(())
+        }
+
+        if self.chunked_encoding | (self.expected_content_length > 0) {
+            self.state = RequestState::ReadingBody;
+            self.body_started = Some(Instant::now());
+        } else {
+            self.state = RequestState::AwaitingResponse;
+            let payload = ReceiverPayload::Body((false, Bytes::new()));
+            let _ = self.receiver.send(payload);
+        }
+
+        let scope = Python::with_gil(|py| -> PyResult<Py<PyDict>> {
+            let scope = self.build_scope(
+                py,
+                method,
+                path,
+                version,
+                request.headers,
+            )?;
+
+            Ok(Py::from(scope))
+        })?;
 
         let sender = self.sender.make_handle();
         let receiver = self.receiver.make_handle();
-        self.callback.invoke((
-            sender,
-            receiver,
-            headers_new,
+        let exit = self.sender.make_exit_handle();
+        self.callback.dispatch(
+            self.token,
+            permit,
+            (scope, receiver, sender),
+            exit,
+        );
+
+        Ok(())
+    }
+
+    /// Builds the ASGI `http` connection scope for the parsed request.
+    fn build_scope<'a>(
+        &self,
+        py: Python<'a>,
+        method: &str,
+        path: &str,
+        version: u8,
+        headers: &[Header],
+    ) -> PyResult<&'a PyDict> {
+        let http_version = if version == 0 { "1.0" } else { "1.1" };
+
+        let headers_list = PyList::empty(py);
+        for header in headers.iter() {
+            let name = header.name.to_ascii_lowercase();
+            headers_list.append((
+                PyBytes::new(py, name.as_bytes()),
+                PyBytes::new(py, header.value),
+            ))?;
+        }
+
+        scope::http_scope(
+            py,
             method,
             path,
-            version,
-        ))?;
+            http_version,
+            headers_list,
+            self.client.as_ref(),
+            self.server.as_ref(),
+            self.secure,
+        )
+    }
+
+    /// Resets the per-request state ready for the next request on
+    /// the connection.
+    fn reset_request(&mut self) {
+        self.expected_content_length = 0;
+        self.chunked_encoding = false;
+        self.chunks.reset();
+        self.body_started = None;
+        self.body_received = 0;
+
+        // Anything left over belongs to the previous request.
+        self.receiver.reset();
+        self.sender.reset();
+    }
+
+    /// Invoked once the app has sent the final body chunk.
+    fn response_complete(&mut self) {
+        self.receiver.set_disconnected(true);
+
+        if !self.writer.keep_alive() {
+            self.state = RequestState::Done;
+        } else if self.state == RequestState::AwaitingResponse {
+            self.state = RequestState::AwaitingHead;
+
+            // Any pipelined requests are waiting in the read buffer.
+            self.event_loop.resume_reading(self.token);
+        }
+    }
+
+    /// Responds to the current request with the given error status and
+    /// closes the connection without involving the app.
+    fn send_error(&mut self, status: StatusCode) {
+        self.receiver.set_disconnected(true);
+        self.reset_request();
+
+        // Part of the app's response has already been written so the
+        // only option left is to close the connection.
+        if !self.writer.is_complete() & self.writer.is_started() {
+            self.state = RequestState::Done;
+            self.event_loop.resume_writing(self.token);
+            return
+        }
+
+        self.writer.reset(1, false, false);
+        self.state = RequestState::AwaitingResponse;
+
+        let reason = status.canonical_reason().unwrap_or("Unknown");
+        let headers = vec![(b"content-type".to_vec(), b"text/plain".to_vec())];
+
+        let _ = self.sender.send(SenderPayload::Start(status.as_u16(), headers));
+        let _ = self.sender.send(SenderPayload::Body((
+            false,
+            Bytes::from_static(reason.as_bytes()).into(),
+        )));
+
+        self.event_loop.resume_writing(self.token);
+    }
+
+    /// Invoked once the body has been read, the next request can be
+    /// handled straight away if the response is already complete.
+    fn body_complete(&mut self) {
+        if !self.writer.is_complete() {
+            self.state = RequestState::AwaitingResponse;
+        } else if self.writer.keep_alive() {
+            self.state = RequestState::AwaitingHead;
+        } else {
+            self.state = RequestState::Done;
+
+            // The response may have already been written in which case
+            // the write side needs waking to close the connection.
+            self.event_loop.resume_writing(self.token);
+        }
+    }
+
+    /// Reads as much of the body as is in the buffer and sends it to
+    /// the app as a single chunk.
+    fn read_body(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        // The app has to consume some of the body before we read more,
+        // the data is left in the buffer until then.
+        if !self.writer.is_complete() & self.receiver.is_full() {
+            self.receiver.pause_while_full();
+            return Ok(())
+        }
+
+        let (body, more_body) = if self.chunked_encoding {
+            match self.chunks.decode(buffer) {
+                Ok((body, more_body)) => (Bytes::from(body), more_body),
+                Err(_) => {
+                    self.send_error(StatusCode::BAD_REQUEST);
+                    return Ok(())
+                }
+            }
+        } else {
+            let len = cmp::min(self.expected_content_length, buffer.len());
+            self.expected_content_length -= len;
+
+            // The body shares the read buffer's memory rather than
+            // being copied out of it.
+            let body = buffer.split_to(len).freeze();
+            (body, self.expected_content_length > 0)
+        };
+
+        self.body_received += body.len();
+
+        // A chunked body has no length up front so is checked as it's read.
+        if let Some(max_size) = self.config.max_body_size {
+            if self.body_received > max_size {
+                self.send_error(StatusCode::PAYLOAD_TOO_LARGE);
+                return Ok(())
+            }
+        }
+
+        // The body is discarded if the app has already responded.
+        if !self.writer.is_complete() & (!body.is_empty() | !more_body) {
+            let payload = ReceiverPayload::Body((more_body, body));
+            let _ = self.receiver.send(payload);
+        }
+
+        if !more_body {
+            self.body_complete();
+        }
 
         Ok(())
     }
 
+    /// Applies any request headers that change how the connection
+    /// is handled.
     fn check_header(&mut self, header: &Header) {
+        if header.name == CONNECTION {
+            let lowered = header.value.to_ascii_lowercase();
+            if let Ok(value) = str::from_utf8(lowered.as_ref()) {
+                if value.contains("close") {
+                    self.keep_alive = false;
+                } else if value.contains("keep-alive") {
+                    self.keep_alive = true;
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a body sent with chunked transfer encoding as it's read.
+struct ChunkDecoder {
+    /// The part of the chunk being waited on.
+    state: ChunkState,
+}
+
+impl ChunkDecoder {
+    fn new() -> Self {
+        Self {
+            state: ChunkState::Size,
+        }
+    }
+
+    /// Resets the decoder ready for the next body.
+    fn reset(&mut self) {
+        self.state = ChunkState::Size;
+    }
+
+    /// Decodes as many chunks of a chunked body as are in the buffer.
+    ///
+    /// Returns the decoded data and if there is more body to come.
+    fn decode(
+        &mut self,
+        buffer: &mut BytesMut,
+    ) -> Result<(Vec<u8>, bool), InvalidChunkSize> {
+        let mut body = Vec::new();
+
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let (consumed, size) = match parse_chunk_size(buffer)? {
+                        Status::Complete(parsed) => parsed,
+                        Status::Partial => break,
+                    };
+
+                    let _ = buffer.split_to(consumed);
+                    self.state = if size == 0 {
+                        ChunkState::Trailers
+                    } else {
+                        ChunkState::Data(size as usize)
+                    };
+                },
+                ChunkState::Data(remaining) => {
+                    if buffer.is_empty() {
+                        break
+                    }
+
+                    let len = cmp::min(remaining, buffer.len());
+                    body.extend_from_slice(&buffer.split_to(len));
+
+                    self.state = if remaining == len {
+                        ChunkState::DataEnd
+                    } else {
+                        ChunkState::Data(remaining - len)
+                    };
+                },
+                ChunkState::DataEnd => {
+                    if buffer.len() < 2 {
+                        break
+                    }
+
+                    if &buffer[..2] != b"\r\n" {
+                        return Err(InvalidChunkSize)
+                    }
+
+                    let _ = buffer.split_to(2);
+                    self.state = ChunkState::Size;
+                },
+                ChunkState::Trailers => {
+                    let end = match find_line_end(buffer) {
+                        Some(end) => end,
+                        None => break,
+                    };
+
+                    let _ = buffer.split_to(end + 2);
+
+                    // A empty line marks the end of the trailers.
+                    if end == 0 {
+                        return Ok((body, false))
+                    }
+                },
+            }
+        }
+
+        Ok((body, true))
+    }
+}
+
+/// Serializes the ASGI response events into a HTTP/1.x response.
+///
+/// The `http.response.start` event is held back until the first body
+/// chunk arrives so the body framing can be decided, a response that is
+/// sent in one chunk gets a `Content-Length` header while a streamed
+/// response uses chunked transfer encoding.
+struct ResponseWriter {
+    /// The shared server settings.
+    config: SharedConfig,
+
+    /// The status and headers waiting on the first body chunk.
+    pending: Option<(u16, ResponseHeaders)>,
+
+    /// If the request was HTTP/1.0 which does not support chunked
+    /// transfer encoding.
+    http10: bool,
+
+    /// If the response must not contain a body e.g. a `HEAD` request
+    /// or a `204 No Content` status.
+    no_body: bool,
+
+    /// If the body is being sent with chunked transfer encoding.
+    chunked: bool,
+
+    /// If the response head has been written.
+    started: bool,
+
+    /// If the final body chunk has been written.
+    complete: bool,
+
+    /// If the connection can be kept alive after this response.
+    keep_alive: bool,
+}
+
+impl ResponseWriter {
+    fn new(config: SharedConfig) -> Self {
+        Self {
+            config,
+            pending: None,
+            http10: false,
+            no_body: false,
+            chunked: false,
+            started: false,
+            complete: true,
+            keep_alive: false,
+        }
+    }
+
+    /// Resets the writer ready to respond to a new request.
+    fn reset(&mut self, version: u8, is_head: bool, keep_alive: bool) {
+        self.pending = None;
+        self.http10 = version == 0;
+        self.no_body = is_head;
+        self.chunked = false;
+        self.started = false;
+        self.complete = false;
+        self.keep_alive = keep_alive;
+    }
+
+    /// If the final body chunk has been written.
+    fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// If the response head has been written.
+    fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// If the connection can be kept alive after the response.
+    fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// Closes the connection after the current response, this is
+    /// written as `Connection: close` if the head has not been sent yet.
+    fn disable_keep_alive(&mut self) {
+        self.keep_alive = false;
+    }
+
+    /// Stores the response start until the first body chunk is sent.
+    fn start(&mut self, status: u16, headers: ResponseHeaders) {
+        if !self.complete {
+            self.pending = Some((status, headers));
+        }
+    }
+
+    /// Writes a body chunk to the buffer, writing the response head
+    /// first if it has not been written yet.
+    ///
+    /// Returns if the response has been completed.
+    fn write_body(
+        &mut self,
+        buffer: &mut WriteBuffer,
+        more_body: bool,
+        body: Chunk,
+    ) -> bool {
+        if self.complete {
+            return true
+        }
+
+        if let Some((status, headers)) = self.pending.take() {
+            self.write_head(buffer, status, &headers, more_body, body.len());
+        } else if !self.started {
+            return false
+        }
+
+        if !self.no_body && !body.is_empty() {
+            if self.chunked {
+                buffer.put_slice(format!("{:X}\r\n", body.len()).as_bytes());
+                buffer.put_chunk(body);
+                buffer.put_slice(b"\r\n");
+            } else {
+                buffer.put_chunk(body);
+            }
+        }
+
+        if !more_body {
+            if self.chunked & !self.no_body {
+                buffer.put_slice(b"0\r\n\r\n");
+            }
+
+            self.complete = true;
+        }
+
+        self.complete
+    }
+
+    /// Writes the status line and headers to the buffer adding the
+    /// `Date`, `Server` and body framing headers if the app has not.
+    fn write_head(
+        &mut self,
+        buffer: &mut BytesMut,
+        status: u16,
+        headers: &[(Vec<u8>, Vec<u8>)],
+        more_body: bool,
+        body_len: usize,
+    ) {
+        self.started = true;
+
+        let reason = StatusCode::from_u16(status)
+            .ok()
+            .and_then(|s| s.canonical_reason())
+            .unwrap_or("Unknown");
+
+        buffer.put_slice(
+            format!("HTTP/1.1 {} {}\r\n", status, reason).as_bytes()
+        );
+
+        let mut has_length = false;
+        let mut has_date = false;
+        let mut has_server = false;
+        let mut has_connection = false;
+        for (name, value) in headers.iter() {
+            if name.eq_ignore_ascii_case(CONNECTION.as_ref()) {
+                has_connection = true;
+                if value.eq_ignore_ascii_case(b"close") {
+                    self.keep_alive = false;
+                }
+            } else if name.eq_ignore_ascii_case(CONTENT_LENGTH.as_ref()) {
+                has_length = true;
+            } else if name.eq_ignore_ascii_case(TRANSFER_ENCODING.as_ref()) {
+                let lowered = value.to_ascii_lowercase();
+                self.chunked = str::from_utf8(&lowered)
+                    .map(|v| v.contains("chunked"))
+                    .unwrap_or(false);
+            } else if name.eq_ignore_ascii_case(DATE.as_ref()) {
+                has_date = true;
+            } else if name.eq_ignore_ascii_case(SERVER.as_ref()) {
+                has_server = true;
+            }
+
+            write_header(buffer, name, value);
+        }
+
+        if !has_server {
+            if let Some(server) = self.config.server_header.as_ref() {
+                write_header(buffer, SERVER.as_ref(), server.as_bytes());
+            }
+        }
+
+        if !has_date {
+            let date = httpdate::fmt_http_date(SystemTime::now());
+            write_header(buffer, DATE.as_ref(), date.as_bytes());
+        }
+
+        let bodyless = (status < 200) | (status == 204) | (status == 304);
+        self.no_body |= bodyless;
+
+        // A response to a `HEAD` request describes the body a `GET` would
+        // have so no framing is added unless the app gave it, a length
+        // of the empty body would tell the client the body is empty.
+        if !self.no_body & !has_length & !self.chunked {
+            if !more_body {
+                let length = body_len.to_string();
+                write_header(buffer, CONTENT_LENGTH.as_ref(), length.as_bytes());
+            } else if !self.http10 {
+                self.chunked = true;
+                write_header(buffer, TRANSFER_ENCODING.as_ref(), b"chunked");
+            } else {
+                // The end of the body can only be marked by closing
+                // the connection.
+                self.keep_alive = false;
+            }
+        }
+
+        if !has_connection {
+            if !self.keep_alive {
+                write_header(buffer, CONNECTION.as_ref(), b"close");
+            } else if self.http10 {
+                write_header(buffer, CONNECTION.as_ref(), b"keep-alive");
+            }
+        }
+
+        buffer.put_slice(b"\r\n");
+    }
+}
+
+/// Writes a single `name: value` header line to the buffer.
+pub fn write_header(buffer: &mut BytesMut, name: &[u8], value: &[u8]) {
+    buffer.put_slice(name);
+    buffer.put_slice(b": ");
+    buffer.put_slice(value);
+    buffer.put_slice(b"\r\n");
+}
+
+/// Works out how the request body is framed from its headers.
+///
+/// A request that a proxy in front of the server could read differently
+/// is rejected as it can be used to smuggle requests, this is a request
+/// with both `Content-Length` and `Transfer-Encoding`, lengths that
+/// disagree or transfer codings that do not end with `chunked`.
+fn body_framing(headers: &[Header]) -> Result<BodyFraming, StatusCode> {
+    let mut length = None;
+    let mut codings: Option<Vec<String>> = None;
+
+    for header in headers.iter() {
         if header.name == CONTENT_LENGTH {
-            self.expected_content_length = str::from_utf8(header.value)
-                .map(|v| v.parse::<usize>().unwrap_or(0))
-                .unwrap_or(0)
+            let value = parse_content_length(header.value)
+                .ok_or(StatusCode::BAD_REQUEST)?;
+
+            if matches!(length, Some(length) if length != value) {
+                return Err(StatusCode::BAD_REQUEST)
+            }
+
+            length = Some(value);
         } else if header.name == TRANSFER_ENCODING {
-            let lowered = header.value.to_ascii_lowercase();
-            self.chunked_encoding = str::from_utf8(lowered.as_ref())
-                .map(|v| v.contains("chunked"))
-                .unwrap_or(false)
+            let value = str::from_utf8(header.value)
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+            codings.get_or_insert_with(Vec::new).extend(
+                value.split(',')
+                    .map(str::trim)
+                    .filter(|coding| !coding.is_empty())
+                    .map(str::to_ascii_lowercase)
+            );
         }
     }
-}
\ No newline at end of file
+
+    let codings = match codings {
+        Some(codings) => codings,
+        None => return Ok(BodyFraming::Length(length.unwrap_or(0))),
+    };
+
+    if length.is_some() {
+        return Err(StatusCode::BAD_REQUEST)
+    }
+
+    // Chunked has to be applied once and last otherwise the end of the
+    // body can not be found.
+    match codings.split_last() {
+        Some((last, rest)) if last == "chunked" => {
+            if rest.iter().any(|coding| coding == "chunked") {
+                Err(StatusCode::BAD_REQUEST)
+            } else if !rest.is_empty() {
+                Err(StatusCode::NOT_IMPLEMENTED)
+            } else {
+                Ok(BodyFraming::Chunked)
+            }
+        },
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// Parses a `Content-Length` value which must only be digits.
+fn parse_content_length(value: &[u8]) -> Option<usize> {
+    let value = str::from_utf8(value).ok()?.trim();
+    if value.is_empty() | !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None
+    }
+
+    value.parse().ok()
+}
+
+/// Finds the index of the next `\r\n` in the buffer.
+fn find_line_end(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(2).position(|window| window == b"\r\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(name: &'a str, value: &'a [u8]) -> Header<'a> {
+        Header { name, value }
+    }
+
+    fn decode_all(decoder: &mut ChunkDecoder, data: &[u8]) -> (Vec<u8>, bool) {
+        let mut buffer = BytesMut::from(data);
+        let (body, more_body) = decoder.decode(&mut buffer).unwrap();
+        assert!(buffer.is_empty());
+        (body, more_body)
+    }
+
+    #[test]
+    fn decodes_whole_chunked_body() {
+        let mut decoder = ChunkDecoder::new();
+        let (body, more_body) = decode_all(
+            &mut decoder,
+            b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n",
+        );
+
+        assert_eq!(body, b"hello, world");
+        assert!(!more_body);
+    }
+
+    #[test]
+    fn decodes_chunked_body_split_across_reads() {
+        let data = b"5\r\nhello\r\nA\r\n0123456789\r\n0\r\nx-trailer: 1\r\n\r\n";
+        let mut decoder = ChunkDecoder::new();
+        let mut buffer = BytesMut::new();
+        let mut body = Vec::new();
+        let mut more_body = true;
+
+        for byte in data.iter() {
+            assert!(more_body);
+            buffer.extend_from_slice(&[*byte]);
+
+            let (chunk, more) = decoder.decode(&mut buffer).unwrap();
+            body.extend_from_slice(&chunk);
+            more_body = more;
+        }
+
+        assert_eq!(body, b"hello0123456789");
+        assert!(!more_body);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn leaves_pipelined_request_in_buffer() {
+        let mut decoder = ChunkDecoder::new();
+        let mut buffer = BytesMut::from(&b"3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\n"[..]);
+
+        let (body, more_body) = decoder.decode(&mut buffer).unwrap();
+        assert_eq!(body, b"abc");
+        assert!(!more_body);
+        assert_eq!(&buffer[..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let mut decoder = ChunkDecoder::new();
+        let mut buffer = BytesMut::from(&b"3\r\nabcX\r\n"[..]);
+        assert!(decoder.decode(&mut buffer).is_err());
+
+        let mut decoder = ChunkDecoder::new();
+        let mut buffer = BytesMut::from(&b"zz\r\nabc\r\n"[..]);
+        assert!(decoder.decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn reset_starts_a_new_body() {
+        let mut decoder = ChunkDecoder::new();
+        let mut buffer = BytesMut::from(&b"5\r\nhel"[..]);
+        let (_, more_body) = decoder.decode(&mut buffer).unwrap();
+        assert!(more_body);
+
+        decoder.reset();
+        let (body, more_body) = decode_all(&mut decoder, b"2\r\nok\r\n0\r\n\r\n");
+        assert_eq!(body, b"ok");
+        assert!(!more_body);
+    }
+
+    #[test]
+    fn framing_without_headers_has_no_body() {
+        let headers = [header("Host", b"localhost")];
+        assert_eq!(body_framing(&headers), Ok(BodyFraming::Length(0)));
+    }
+
+    #[test]
+    fn framing_by_content_length() {
+        let headers = [header("Content-Length", b" 42 ")];
+        assert_eq!(body_framing(&headers), Ok(BodyFraming::Length(42)));
+
+        let headers = [
+            header("content-length", b"42"),
+            header("Content-Length", b"42"),
+        ];
+        assert_eq!(body_framing(&headers), Ok(BodyFraming::Length(42)));
+    }
+
+    #[test]
+    fn framing_by_transfer_encoding() {
+        let headers = [header("Transfer-Encoding", b"Chunked")];
+        assert_eq!(body_framing(&headers), Ok(BodyFraming::Chunked));
+    }
+
+    #[test]
+    fn rejects_content_length_with_transfer_encoding() {
+        let headers = [
+            header("Content-Length", b"5"),
+            header("Transfer-Encoding", b"chunked"),
+        ];
+        assert_eq!(body_framing(&headers), Err(StatusCode::BAD_REQUEST));
+
+        let headers = [
+            header("Transfer-Encoding", b"chunked"),
+            header("Content-Length", b"5"),
+        ];
+        assert_eq!(body_framing(&headers), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn rejects_conflicting_content_lengths() {
+        let headers = [
+            header("Content-Length", b"5"),
+            header("Content-Length", b"6"),
+        ];
+        assert_eq!(body_framing(&headers), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn rejects_invalid_content_lengths() {
+        for value in [&b""[..], b"abc", b"+5", b"-5", b"5, 5", b"0x10"].iter() {
+            let headers = [header("Content-Length", value)];
+            assert_eq!(body_framing(&headers), Err(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_not_ending_in_chunked() {
+        for value in [&b"gzip"[..], b"chunked, gzip", b"identity", b""].iter() {
+            let headers = [header("Transfer-Encoding", value)];
+            assert_eq!(body_framing(&headers), Err(StatusCode::BAD_REQUEST));
+        }
+
+        let headers = [
+            header("Transfer-Encoding", b"chunked"),
+            header("Transfer-Encoding", b"gzip"),
+        ];
+        assert_eq!(body_framing(&headers), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn rejects_chunked_applied_twice() {
+        let headers = [header("Transfer-Encoding", b"chunked, chunked")];
+        assert_eq!(body_framing(&headers), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn unsupported_codings_before_chunked_are_not_implemented() {
+        let headers = [
+            header("Transfer-Encoding", b"gzip"),
+            header("Transfer-Encoding", b"chunked"),
+        ];
+        assert_eq!(body_framing(&headers), Err(StatusCode::NOT_IMPLEMENTED));
+    }
+}
@@ -0,0 +1,1641 @@
+use crate::pyre_server::abc::ProtocolBuffers;
+use crate::pyre_server::switch::{Switchable, SwitchStatus};
+use crate::pyre_server::transport::EventLoopHandle;
+use crate::pyre_server::py_callback::{CallbackHandler, Permit};
+use crate::pyre_server::config::{ServerConfig, SharedConfig};
+use crate::pyre_server::responders::{
+    SenderPayload,
+    ReceiverPayload,
+    ResponseHeaders,
+    Payload,
+};
+use crate::pyre_server::responders::sender::SenderHandler;
+use crate::pyre_server::responders::receiver::ReceiverHandler;
+use crate::pyre_server::protocols::h1::write_header;
+use crate::pyre_server::protocols::scope;
+use crate::pyre_server::socket_io::WriteBuffer;
+use crate::pyre_server::net::Addr;
+
+use pyo3::{PyResult, Python, Py};
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+use std::cmp;
+use std::str;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use bytes::{Bytes, BytesMut, BufMut};
+use mio::Token;
+use rustc_hash::FxHashMap;
+
+use httparse::Header;
+use http::header::{CONNECTION, UPGRADE, DATE, SERVER};
+
+
+/// The connection preface every client starts the connection with.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The size of the frame header that precedes every frame.
+const FRAME_HEADER_SIZE: usize = 9;
+
+/// The max frame size the server accepts, this is the protocol default.
+const MAX_FRAME_SIZE: usize = 16_384;
+
+/// The initial flow control window of every stream and the connection.
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+
+/// The largest a flow control window is allowed to grow.
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+/// The largest dynamic table the client's encoder is allowed to use, this
+/// is the protocol default as the server never advertises another.
+const HEADER_TABLE_SIZE: usize = 4_096;
+
+/// The overhead counted for every header when limiting the size of a
+/// decoded header list, see RFC 7540 section 6.5.2.
+const HEADER_OVERHEAD: usize = 32;
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_PRIORITY: u8 = 0x2;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PUSH_PROMISE: u8 = 0x5;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+/// The error codes used by the server, see RFC 7540 section 7.
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xB;
+
+/// Headers that are specific to HTTP/1.x connections and are not
+/// allowed in HTTP/2.
+const CONNECTION_HEADERS: &[&[u8]] = &[
+    b"connection",
+    b"keep-alive",
+    b"proxy-connection",
+    b"transfer-encoding",
+    b"upgrade",
+];
+
+
+/// The `Upgrade: h2c` request handed over from the HTTP/1.1 protocol,
+/// this request becomes stream 1 of the new connection.
+pub struct H2Upgrade {
+    /// The ASGI `http` connection scope of the request.
+    pub scope: Py<PyDict>,
+
+    /// The decoded payload of the `HTTP2-Settings` header.
+    pub settings: Vec<u8>,
+
+    /// If the request was a `HEAD` request.
+    pub is_head: bool,
+
+    /// The in-flight permit the request is handed to the app with.
+    pub permit: Permit,
+}
+
+
+/// The state of the connection as a whole.
+#[derive(Copy, Clone, PartialEq)]
+enum ConnectionState {
+    /// Waiting on the client connection preface.
+    Preface,
+
+    /// Frames can be sent in both directions.
+    Open,
+
+    /// The connection is closed once the write buffer has been drained.
+    Closed,
+}
+
+
+/// A single request / response exchange on the connection.
+struct Stream {
+    /// The sender half handler for the stream's ASGI callbacks.
+    sender: SenderHandler,
+
+    /// The receiver half handler for the stream's ASGI callbacks.
+    receiver: ReceiverHandler,
+
+    /// Body chunks waiting on space in the receiver channel.
+    inbound: VecDeque<Payload>,
+
+    /// Body data waiting on space in the flow control windows.
+    outbound: WriteBuffer,
+
+    /// The amount of data the client is allowed to send on the stream.
+    recv_window: i64,
+
+    /// The amount of data the server is allowed to send on the stream.
+    send_window: i64,
+
+    /// If the response must not contain a body e.g. a `HEAD` request.
+    no_body: bool,
+
+    /// If the response headers have been written.
+    headers_sent: bool,
+
+    /// If the app has sent the final body chunk.
+    end_queued: bool,
+
+    /// If the client has finished sending the request.
+    remote_closed: bool,
+
+    /// If the server has finished sending the response.
+    local_closed: bool,
+}
+
+impl Stream {
+    fn new(
+        token: Token,
+        event_loop: EventLoopHandle,
+        send_window: i64,
+        no_body: bool,
+        config: &ServerConfig,
+    ) -> Self {
+        Self {
+            sender: SenderHandler::new(token, event_loop.clone()),
+            receiver: ReceiverHandler::new(token, event_loop, config),
+            inbound: VecDeque::new(),
+            outbound: WriteBuffer::default(),
+            recv_window: DEFAULT_WINDOW_SIZE,
+            send_window,
+            no_body,
+            headers_sent: false,
+            end_queued: false,
+            remote_closed: false,
+            local_closed: false,
+        }
+    }
+}
+
+
+/// The name and value pairs of a decoded header block.
+type HeaderList = Vec<(Vec<u8>, Vec<u8>)>;
+
+
+/// The request pseudo-headers and regular headers of a stream.
+struct RequestHead {
+    method: String,
+    path: String,
+    authority: Option<Vec<u8>>,
+    headers: HeaderList,
+}
+
+
+/// The protocol to add handling for cleartext HTTP/2 connections, each
+/// stream is given its own ASGI sender and receiver.
+pub struct H2Protocol {
+    /// The event loop handle, this handles all event loop interactions.
+    event_loop: EventLoopHandle,
+
+    /// The client token that identifies itself.
+    token: Token,
+
+    /// The python callback handler.
+    callback: CallbackHandler,
+
+    /// The shared server settings.
+    config: SharedConfig,
+
+    /// The state of the connection.
+    state: ConnectionState,
+
+    /// The streams that are currently open.
+    streams: FxHashMap<u32, Stream>,
+
+    /// The HPACK decoder for request headers.
+    decoder: hpack::Decoder<'static>,
+
+    /// The stream, END_STREAM flag and header block of a HEADERS frame
+    /// that is waiting on CONTINUATION frames.
+    continuation: Option<(u32, bool, Vec<u8>)>,
+
+    /// The highest stream id opened by the client.
+    last_stream_id: u32,
+
+    /// The amount of data the client is allowed to send on the connection.
+    conn_recv_window: i64,
+
+    /// The amount of data the server is allowed to send on the connection.
+    conn_send_window: i64,
+
+    /// The initial send window of new streams set by the client.
+    peer_initial_window: i64,
+
+    /// The max frame size the client accepts.
+    peer_max_frame_size: usize,
+
+    /// If either side has sent a GOAWAY frame, no new streams are
+    /// accepted and the connection closes once the open ones finish.
+    going_away: bool,
+
+    /// Frames written by the server itself e.g. SETTINGS acks that are
+    /// waiting to be written to the socket.
+    pending: BytesMut,
+
+    /// The remote address of the connected client.
+    client: Option<Addr>,
+
+    /// The local address the client connected to.
+    server: Option<Addr>,
+
+    /// If the connection is encrypted with TLS.
+    secure: bool,
+}
+
+impl H2Protocol {
+    /// Create a new H2Protocol instance.
+    pub fn new(
+        token: Token,
+        callback: CallbackHandler,
+        event_loop: EventLoopHandle,
+        config: SharedConfig,
+    ) -> Self {
+        Self {
+            token,
+            event_loop,
+            callback,
+            config,
+
+            state: ConnectionState::Closed,
+            streams: FxHashMap::default(),
+            decoder: hpack::Decoder::new(),
+            continuation: None,
+            last_stream_id: 0,
+            conn_recv_window: DEFAULT_WINDOW_SIZE,
+            conn_send_window: DEFAULT_WINDOW_SIZE,
+            peer_initial_window: DEFAULT_WINDOW_SIZE,
+            peer_max_frame_size: MAX_FRAME_SIZE,
+            going_away: false,
+            pending: BytesMut::new(),
+
+            client: None,
+            server: None,
+            secure: false,
+        }
+    }
+}
+
+impl H2Protocol {
+    /// Called when the protocol is in charge of a new socket / handle.
+    pub fn new_connection(
+        &mut self,
+        client: Addr,
+        server: Addr,
+        secure: bool,
+    ) -> PyResult<()> {
+        self.client = Some(client);
+        self.server = Some(server);
+        self.secure = secure;
+
+        Ok(())
+    }
+
+    /// Called when the connection has been switched to HTTP/2, this
+    /// sends the server preface and opens stream 1 if the connection
+    /// was upgraded from a HTTP/1.1 request.
+    pub fn open(&mut self, upgrade: Option<H2Upgrade>) -> PyResult<()> {
+        self.state = ConnectionState::Preface;
+        self.streams.clear();
+        self.decoder = hpack::Decoder::new();
+        self.continuation = None;
+        self.last_stream_id = 0;
+        self.conn_recv_window = DEFAULT_WINDOW_SIZE;
+        self.conn_send_window = DEFAULT_WINDOW_SIZE;
+        self.peer_initial_window = DEFAULT_WINDOW_SIZE;
+        self.peer_max_frame_size = MAX_FRAME_SIZE;
+        self.going_away = false;
+        self.pending.clear();
+
+        if upgrade.is_some() {
+            self.pending.put_slice(b"HTTP/1.1 101 Switching Protocols\r\n");
+            write_header(&mut self.pending, CONNECTION.as_ref(), b"Upgrade");
+            write_header(&mut self.pending, UPGRADE.as_ref(), b"h2c");
+            self.pending.put_slice(b"\r\n");
+        }
+
+        let max_streams = self.config.h2_max_concurrent_streams;
+        write_frame_head(&mut self.pending, 6, FRAME_SETTINGS, 0, 0);
+        self.pending.put_u16(SETTINGS_MAX_CONCURRENT_STREAMS);
+        self.pending.put_u32(max_streams);
+
+        self.event_loop.resume_writing(self.token);
+
+        if let Some(upgrade) = upgrade {
+            if let Err(code) = self.apply_settings(&upgrade.settings) {
+                self.fail(code);
+                return Ok(())
+            }
+
+            self.last_stream_id = 1;
+            self.open_stream(
+                1,
+                upgrade.scope,
+                upgrade.is_head,
+                true,
+                upgrade.permit,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Called when the connection is lost from the protocol in order to
+    /// properly reset state.
+    pub fn lost_connection(&mut self) -> PyResult<()> {
+        for stream in self.streams.values() {
+            stream.receiver.set_disconnected(true);
+        }
+
+        self.streams.clear();
+        self.state = ConnectionState::Closed;
+        self.continuation = None;
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// If the connection has no open streams.
+    pub fn is_awaiting_request(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// If the connection should be closed once the write buffer has
+    /// been drained.
+    pub fn should_close(&self) -> bool {
+        self.state == ConnectionState::Closed
+    }
+
+    /// Called when the server is shutting down, this sends a GOAWAY
+    /// frame so the client stops opening streams while the open ones
+    /// are allowed to finish.
+    pub fn shutdown(&mut self) {
+        if (self.state == ConnectionState::Closed) | self.going_away {
+            return
+        }
+
+        write_frame_head(&mut self.pending, 8, FRAME_GOAWAY, 0, 0);
+        self.pending.put_u32(self.last_stream_id);
+        self.pending.put_u32(NO_ERROR);
+
+        self.going_away = true;
+        self.event_loop.resume_writing(self.token);
+    }
+
+    /// Hands any body chunks waiting on the apps to their receivers,
+    /// this is invoked when an app asks for more of the body.
+    pub fn resume_streams(&mut self) {
+        let ids: Vec<u32> = self.streams.keys().copied().collect();
+        for id in ids {
+            self.deliver_inbound(id);
+        }
+    }
+}
+
+impl ProtocolBuffers for H2Protocol {
+    fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        loop {
+            match self.state {
+                ConnectionState::Preface => {
+                    if buffer.len() < PREFACE.len() {
+                        if !PREFACE.starts_with(buffer) {
+                            self.fail(PROTOCOL_ERROR);
+                        }
+
+                        return Ok(())
+                    }
+
+                    if !buffer.starts_with(PREFACE) {
+                        self.fail(PROTOCOL_ERROR);
+                        continue
+                    }
+
+                    let _ = buffer.split_to(PREFACE.len());
+                    self.state = ConnectionState::Open;
+                },
+                ConnectionState::Closed => {
+                    buffer.clear();
+                    return Ok(())
+                },
+                ConnectionState::Open => {},
+            }
+
+            if buffer.len() < FRAME_HEADER_SIZE {
+                return Ok(())
+            }
+
+            let len = (buffer[0] as usize) << 16
+                | (buffer[1] as usize) << 8
+                | buffer[2] as usize;
+
+            if len > MAX_FRAME_SIZE {
+                self.fail(FRAME_SIZE_ERROR);
+                continue
+            }
+
+            if buffer.len() < FRAME_HEADER_SIZE + len {
+                return Ok(())
+            }
+
+            let frame = buffer.split_to(FRAME_HEADER_SIZE + len);
+            let kind = frame[3];
+            let flags = frame[4];
+            let stream_id = u32::from_be_bytes(
+                [frame[5], frame[6], frame[7], frame[8]]
+            ) & 0x7FFF_FFFF;
+
+            self.on_frame(kind, flags, stream_id, &frame[FRAME_HEADER_SIZE..])?;
+        }
+    }
+
+    fn fill_write_buffer(&mut self, buffer: &mut WriteBuffer) -> PyResult<()> {
+        buffer.extend_from_slice(&self.pending);
+        self.pending.clear();
+
+        let ids: Vec<u32> = self.streams.keys().copied().collect();
+        for id in ids {
+            self.write_stream(buffer, id);
+        }
+
+        if self.going_away & self.streams.is_empty() {
+            self.state = ConnectionState::Closed;
+        }
+
+        self.event_loop.pause_writing(self.token);
+
+        Ok(())
+    }
+}
+
+impl Switchable for H2Protocol {
+    /// HTTP/2 connections can not be upgraded any further.
+    fn switch_protocol(&mut self) -> PyResult<SwitchStatus> {
+        Ok(SwitchStatus::NoSwitch)
+    }
+}
+
+impl H2Protocol {
+    /// Handles a single frame sent by the client.
+    fn on_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> PyResult<()> {
+        // A header block must be completed before any other frame.
+        if let Some((id, _, _)) = self.continuation.as_ref() {
+            if (kind != FRAME_CONTINUATION) | (stream_id != *id) {
+                self.fail(PROTOCOL_ERROR);
+                return Ok(())
+            }
+        }
+
+        match kind {
+            FRAME_DATA => self.on_data(flags, stream_id, payload),
+            FRAME_HEADERS => self.on_headers(flags, stream_id, payload)?,
+            FRAME_CONTINUATION => self.on_continuation(flags, payload)?,
+            FRAME_PRIORITY => {
+                if stream_id == 0 {
+                    self.fail(PROTOCOL_ERROR);
+                } else if payload.len() != 5 {
+                    self.reset_stream(stream_id, FRAME_SIZE_ERROR);
+                }
+            },
+            FRAME_RST_STREAM => {
+                if (stream_id == 0) | (stream_id > self.last_stream_id) {
+                    self.fail(PROTOCOL_ERROR);
+                } else if payload.len() != 4 {
+                    self.fail(FRAME_SIZE_ERROR);
+                } else if let Some(stream) = self.streams.remove(&stream_id) {
+                    stream.receiver.set_disconnected(true);
+                }
+            },
+            FRAME_SETTINGS => self.on_settings(flags, stream_id, payload),
+            FRAME_PUSH_PROMISE => self.fail(PROTOCOL_ERROR),
+            FRAME_PING => {
+                if stream_id != 0 {
+                    self.fail(PROTOCOL_ERROR);
+                } else if payload.len() != 8 {
+                    self.fail(FRAME_SIZE_ERROR);
+                } else if (flags & FLAG_ACK) == 0 {
+                    write_frame_head(&mut self.pending, 8, FRAME_PING, FLAG_ACK, 0);
+                    self.pending.put_slice(payload);
+                    self.event_loop.resume_writing(self.token);
+                }
+            },
+            FRAME_GOAWAY => {
+                if stream_id != 0 {
+                    self.fail(PROTOCOL_ERROR);
+                } else {
+                    // The open streams are allowed to finish first.
+                    self.going_away = true;
+                    self.event_loop.resume_writing(self.token);
+                }
+            },
+            FRAME_WINDOW_UPDATE => self.on_window_update(stream_id, payload),
+
+            // Unknown frame types must be ignored.
+            _ => {},
+        };
+
+        Ok(())
+    }
+
+    /// Handles a HEADERS frame which either opens a new stream or
+    /// contains the trailers of an existing one.
+    fn on_headers(
+        &mut self,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> PyResult<()> {
+        if stream_id == 0 {
+            self.fail(PROTOCOL_ERROR);
+            return Ok(())
+        }
+
+        let payload = match strip_padding(flags, payload) {
+            Some(payload) => payload,
+            None => {
+                self.fail(PROTOCOL_ERROR);
+                return Ok(())
+            },
+        };
+
+        let payload = if (flags & FLAG_PRIORITY) != 0 {
+            if payload.len() < 5 {
+                self.fail(FRAME_SIZE_ERROR);
+                return Ok(())
+            }
+
+            &payload[5..]
+        } else {
+            payload
+        };
+
+        let end_stream = (flags & FLAG_END_STREAM) != 0;
+        if (flags & FLAG_END_HEADERS) != 0 {
+            self.on_header_block(stream_id, end_stream, payload)
+        } else {
+            self.continuation = Some((stream_id, end_stream, payload.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// Handles a CONTINUATION frame adding to the current header block.
+    fn on_continuation(&mut self, flags: u8, payload: &[u8]) -> PyResult<()> {
+        let (stream_id, end_stream, mut block) = match self.continuation.take() {
+            Some(continuation) => continuation,
+            None => {
+                self.fail(PROTOCOL_ERROR);
+                return Ok(())
+            },
+        };
+
+        block.extend_from_slice(payload);
+        if block.len() > self.config.max_head_size {
+            self.fail(ENHANCE_YOUR_CALM);
+            return Ok(())
+        }
+
+        if (flags & FLAG_END_HEADERS) != 0 {
+            self.on_header_block(stream_id, end_stream, &block)
+        } else {
+            self.continuation = Some((stream_id, end_stream, block));
+            Ok(())
+        }
+    }
+
+    /// Handles a complete header block, invoking the app if the block
+    /// opens a new stream.
+    fn on_header_block(
+        &mut self,
+        stream_id: u32,
+        end_stream: bool,
+        block: &[u8],
+    ) -> PyResult<()> {
+        // The decoder has to see every block to keep its table in sync
+        // even if the stream ends up being refused.
+        let max_size = self.config.max_head_size;
+        let headers = match decode_header_block(&mut self.decoder, block, max_size) {
+            Ok(headers) => headers,
+            Err(code) => {
+                self.fail(code);
+                return Ok(())
+            },
+        };
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.remote_closed {
+                self.reset_stream(stream_id, STREAM_CLOSED);
+            } else if !end_stream {
+                self.fail(PROTOCOL_ERROR);
+            } else {
+                // Trailers mark the end of the request body.
+                stream.inbound.push_back((false, Bytes::new()));
+                stream.remote_closed = true;
+                self.deliver_inbound(stream_id);
+            }
+
+            return Ok(())
+        }
+
+        if (stream_id % 2 == 0) | (stream_id <= self.last_stream_id) {
+            self.fail(PROTOCOL_ERROR);
+            return Ok(())
+        }
+
+        self.last_stream_id = stream_id;
+
+        let max_streams = self.config.h2_max_concurrent_streams as usize;
+        if self.going_away | (self.streams.len() >= max_streams) {
+            self.reset_stream(stream_id, REFUSED_STREAM);
+            return Ok(())
+        }
+
+        // The client is free to retry the stream once the app is able
+        // to take on another request.
+        let permit = match self.callback.try_acquire() {
+            Some(permit) => permit,
+            None => {
+                self.reset_stream(stream_id, REFUSED_STREAM);
+                return Ok(())
+            },
+        };
+
+        let request = match parse_request_head(headers) {
+            Some(request) => request,
+            None => {
+                self.reset_stream(stream_id, PROTOCOL_ERROR);
+                return Ok(())
+            },
+        };
+
+        let scope = Python::with_gil(|py| -> PyResult<Py<PyDict>> {
+            let headers_list = PyList::empty(py);
+
+            let has_host = request.headers
+                .iter()
+                .any(|(name, _)| name == b"host");
+
+            // ASGI apps expect the authority to be given as the host.
+            if let (false, Some(authority)) = (has_host, &request.authority) {
+                headers_list.append((
+                    PyBytes::new(py, b"host"),
+                    PyBytes::new(py, authority),
+                ))?;
+            }
+
+            for (name, value) in request.headers.iter() {
+                headers_list.append((
+                    PyBytes::new(py, name),
+                    PyBytes::new(py, value),
+                ))?;
+            }
+
+            let scope = scope::http_scope(
+                py,
+                &request.method,
+                &request.path,
+                "2",
+                headers_list,
+                self.client.as_ref(),
+                self.server.as_ref(),
+                self.secure,
+            )?;
+
+            Ok(Py::from(scope))
+        })?;
+
+        let is_head = request.method == "HEAD";
+        self.open_stream(stream_id, scope, is_head, end_stream, permit)
+    }
+
+    /// Opens a new stream invoking the app with its own sender and
+    /// receiver.
+    fn open_stream(
+        &mut self,
+        stream_id: u32,
+        scope: Py<PyDict>,
+        is_head: bool,
+        end_stream: bool,
+        permit: Permit,
+    ) -> PyResult<()> {
+        let mut stream = Stream::new(
+            self.token,
+            self.event_loop.clone(),
+            self.peer_initial_window,
+            is_head,
+            &self.config,
+        );
+
+        if end_stream {
+            stream.inbound.push_back((false, Bytes::new()));
+            stream.remote_closed = true;
+        }
+
+        let sender = stream.sender.make_handle();
+        let receiver = stream.receiver.make_handle();
+        let exit = stream.sender.make_exit_handle();
+
+        self.streams.insert(stream_id, stream);
+        self.deliver_inbound(stream_id);
+
+        self.callback.dispatch(
+            self.token,
+            permit,
+            (scope, receiver, sender),
+            exit,
+        );
+
+        Ok(())
+    }
+
+    /// Handles a DATA frame containing part of a request body.
+    fn on_data(&mut self, flags: u8, stream_id: u32, payload: &[u8]) {
+        if stream_id == 0 {
+            return self.fail(PROTOCOL_ERROR)
+        }
+
+        let len = payload.len();
+        self.conn_recv_window -= len as i64;
+        if self.conn_recv_window < 0 {
+            return self.fail(FLOW_CONTROL_ERROR)
+        }
+
+        let data = match strip_padding(flags, payload) {
+            Some(data) => data,
+            None => return self.fail(PROTOCOL_ERROR),
+        };
+
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if !stream.remote_closed => stream,
+            _ => {
+                // The data will never be consumed so the connection
+                // window is given straight back.
+                self.conn_recv_window += len as i64;
+                write_window_update(&mut self.pending, 0, len);
+
+                if stream_id > self.last_stream_id {
+                    self.fail(PROTOCOL_ERROR);
+                } else {
+                    self.reset_stream(stream_id, STREAM_CLOSED);
+                }
+
+                return
+            },
+        };
+
+        stream.recv_window -= len as i64;
+        if stream.recv_window < 0 {
+            self.conn_recv_window += len as i64;
+            write_window_update(&mut self.pending, 0, len);
+            return self.reset_stream(stream_id, FLOW_CONTROL_ERROR)
+        }
+
+        let end_stream = (flags & FLAG_END_STREAM) != 0;
+        if !data.is_empty() | end_stream {
+            stream.inbound.push_back((!end_stream, Bytes::copy_from_slice(data)));
+        }
+        stream.remote_closed = end_stream;
+
+        // The padding is never handed to the app.
+        let padding = len - data.len();
+        if padding > 0 {
+            stream.recv_window += padding as i64;
+            self.conn_recv_window += padding as i64;
+
+            write_window_update(&mut self.pending, 0, padding);
+            if !end_stream {
+                write_window_update(&mut self.pending, stream_id, padding);
+            }
+        }
+
+        self.deliver_inbound(stream_id);
+    }
+
+    /// Hands as many waiting body chunks to the app as the receiver has
+    /// space for, the flow control windows are re-opened for anything
+    /// that has been handed over.
+    fn deliver_inbound(&mut self, stream_id: u32) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let mut consumed = 0;
+        loop {
+            while !stream.receiver.is_full() {
+                let payload = match stream.inbound.pop_front() {
+                    Some(payload) => payload,
+                    None => break,
+                };
+
+                consumed += payload.1.len();
+                let _ = stream.receiver.send(ReceiverPayload::Body(payload));
+            }
+
+            if stream.inbound.is_empty() {
+                break
+            }
+
+            // The rest is delivered once the app has received enough of
+            // what it was given, unless it already has.
+            stream.receiver.set_paused(true);
+            if stream.receiver.is_full() {
+                break
+            }
+
+            stream.receiver.set_paused(false);
+        }
+
+        if consumed == 0 {
+            return
+        }
+
+        self.conn_recv_window += consumed as i64;
+        write_window_update(&mut self.pending, 0, consumed);
+
+        if !stream.remote_closed {
+            stream.recv_window += consumed as i64;
+            write_window_update(&mut self.pending, stream_id, consumed);
+        }
+
+        self.event_loop.resume_writing(self.token);
+    }
+
+    /// Handles a SETTINGS frame from the client.
+    fn on_settings(&mut self, flags: u8, stream_id: u32, payload: &[u8]) {
+        if stream_id != 0 {
+            return self.fail(PROTOCOL_ERROR)
+        }
+
+        if (flags & FLAG_ACK) != 0 {
+            if !payload.is_empty() {
+                self.fail(FRAME_SIZE_ERROR);
+            }
+
+            return
+        }
+
+        if payload.len() % 6 != 0 {
+            return self.fail(FRAME_SIZE_ERROR)
+        }
+
+        if let Err(code) = self.apply_settings(payload) {
+            return self.fail(code)
+        }
+
+        write_frame_head(&mut self.pending, 0, FRAME_SETTINGS, FLAG_ACK, 0);
+        self.event_loop.resume_writing(self.token);
+    }
+
+    /// Applies the settings sent by the client.
+    ///
+    /// Returns the error code to fail the connection with if a setting
+    /// is invalid.
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), u32> {
+        for setting in payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes(
+                [setting[2], setting[3], setting[4], setting[5]]
+            );
+
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(PROTOCOL_ERROR)
+                },
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW_SIZE {
+                        return Err(FLOW_CONTROL_ERROR)
+                    }
+
+                    // The change applies to every open stream.
+                    let delta = value as i64 - self.peer_initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                    }
+
+                    self.peer_initial_window = value as i64;
+                },
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16_384..=16_777_215).contains(&value) {
+                        return Err(PROTOCOL_ERROR)
+                    }
+
+                    self.peer_max_frame_size = value as usize;
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a WINDOW_UPDATE frame opening up a send window.
+    fn on_window_update(&mut self, stream_id: u32, payload: &[u8]) {
+        if payload.len() != 4 {
+            return self.fail(FRAME_SIZE_ERROR)
+        }
+
+        let increment = u32::from_be_bytes(
+            [payload[0], payload[1], payload[2], payload[3]]
+        ) & 0x7FFF_FFFF;
+
+        if increment == 0 {
+            return if stream_id == 0 {
+                self.fail(PROTOCOL_ERROR)
+            } else {
+                self.reset_stream(stream_id, PROTOCOL_ERROR)
+            }
+        }
+
+        if stream_id == 0 {
+            self.conn_send_window += increment as i64;
+            if self.conn_send_window > MAX_WINDOW_SIZE {
+                return self.fail(FLOW_CONTROL_ERROR)
+            }
+        } else if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.send_window += increment as i64;
+            if stream.send_window > MAX_WINDOW_SIZE {
+                return self.reset_stream(stream_id, FLOW_CONTROL_ERROR)
+            }
+        }
+
+        // Any data waiting on the window can now be written.
+        self.event_loop.resume_writing(self.token);
+    }
+
+    /// Writes the app's response events for the stream to the buffer
+    /// as far as the flow control windows allow.
+    fn write_stream(&mut self, buffer: &mut WriteBuffer, stream_id: u32) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        // Checked first so every event sent before the app exited has
+        // been written by the time it is handled.
+        let exited = stream.sender.app_exited();
+
+        while let Ok(payload) = stream.sender.recv() {
+            match payload {
+                SenderPayload::Start(status, headers) => {
+                    if !stream.headers_sent {
+                        write_headers(
+                            buffer,
+                            stream_id,
+                            status,
+                            &headers,
+                            &self.config.server_header,
+                            self.peer_max_frame_size,
+                        );
+
+                        stream.headers_sent = true;
+                    }
+                },
+                SenderPayload::Body((more_body, body)) => {
+                    if stream.headers_sent & !stream.end_queued {
+                        if !stream.no_body {
+                            stream.outbound.put_chunk(body);
+                        }
+
+                        stream.end_queued = !more_body;
+                    }
+                },
+
+                // WebSocket events have no meaning for a HTTP request.
+                SenderPayload::Accept(..)
+                | SenderPayload::Send(..)
+                | SenderPayload::Close(..) => {},
+            }
+        }
+
+        // The app exited without completing the response, this is a
+        // `500` if the headers have not been sent otherwise the stream
+        // is reset.
+        if exited.is_some() & !stream.end_queued {
+            if stream.headers_sent {
+                write_frame_head(buffer, 4, FRAME_RST_STREAM, 0, stream_id);
+                buffer.put_u32(INTERNAL_ERROR);
+
+                stream.receiver.set_disconnected(true);
+                self.streams.remove(&stream_id);
+                return
+            }
+
+            let headers = vec![(b"content-type".to_vec(), b"text/plain".to_vec())];
+            write_headers(
+                buffer,
+                stream_id,
+                500,
+                &headers,
+                &self.config.server_header,
+                self.peer_max_frame_size,
+            );
+
+            if !stream.no_body {
+                stream.outbound.extend_from_slice(b"Internal Server Error");
+            }
+
+            stream.headers_sent = true;
+            stream.end_queued = true;
+        }
+
+        while stream.headers_sent & !stream.local_closed {
+            let window = cmp::min(self.conn_send_window, stream.send_window);
+            let len = cmp::min(
+                cmp::max(window, 0) as usize,
+                cmp::min(stream.outbound.len(), self.peer_max_frame_size),
+            );
+
+            let is_last = stream.end_queued & (len == stream.outbound.len());
+            if (len == 0) & !is_last {
+                break
+            }
+
+            let flags = if is_last { FLAG_END_STREAM } else { 0 };
+            write_frame_head(buffer, len, FRAME_DATA, flags, stream_id);
+            buffer.append(stream.outbound.split_to(len));
+
+            self.conn_send_window -= len as i64;
+            stream.send_window -= len as i64;
+            stream.local_closed = is_last;
+        }
+
+        if !stream.local_closed {
+            return
+        }
+
+        // The rest of the request body is no longer needed once the
+        // response is complete.
+        if !stream.remote_closed {
+            write_frame_head(buffer, 4, FRAME_RST_STREAM, 0, stream_id);
+            buffer.put_u32(NO_ERROR);
+        }
+
+        stream.receiver.set_disconnected(true);
+        self.streams.remove(&stream_id);
+    }
+
+    /// Resets a single stream with the given error code leaving the
+    /// rest of the connection open.
+    fn reset_stream(&mut self, stream_id: u32, code: u32) {
+        write_frame_head(&mut self.pending, 4, FRAME_RST_STREAM, 0, stream_id);
+        self.pending.put_u32(code);
+
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            stream.receiver.set_disconnected(true);
+        }
+
+        self.event_loop.resume_writing(self.token);
+    }
+
+    /// Fails the whole connection after a protocol error sending a
+    /// GOAWAY frame with the given code.
+    fn fail(&mut self, code: u32) {
+        if self.state == ConnectionState::Closed {
+            return
+        }
+
+        write_frame_head(&mut self.pending, 8, FRAME_GOAWAY, 0, 0);
+        self.pending.put_u32(self.last_stream_id);
+        self.pending.put_u32(code);
+
+        for stream in self.streams.values() {
+            stream.receiver.set_disconnected(true);
+        }
+
+        self.streams.clear();
+        self.continuation = None;
+        self.state = ConnectionState::Closed;
+        self.event_loop.resume_writing(self.token);
+    }
+}
+
+/// Checks if the request is a `Upgrade: h2c` request.
+///
+/// Returns the decoded `HTTP2-Settings` payload if it is.
+pub fn h2c_settings(headers: &[Header]) -> Option<Vec<u8>> {
+    let mut is_h2c = false;
+    let mut is_upgrade = false;
+    let mut settings = None;
+
+    for header in headers.iter() {
+        let lowered = header.value.to_ascii_lowercase();
+        let value = str::from_utf8(&lowered).unwrap_or("");
+
+        if header.name == UPGRADE {
+            is_h2c |= value.split(',').any(|v| v.trim() == "h2c");
+        } else if header.name == CONNECTION {
+            is_upgrade |= value.split(',').any(|v| v.trim() == "upgrade");
+        } else if header.name.eq_ignore_ascii_case("http2-settings") {
+            settings = Some(header.value);
+        }
+    }
+
+    if !is_h2c | !is_upgrade {
+        return None
+    }
+
+    let settings = base64::decode_config(
+        settings?,
+        base64::URL_SAFE_NO_PAD,
+    ).ok()?;
+
+    if settings.len() % 6 != 0 {
+        return None
+    }
+
+    Some(settings)
+}
+
+/// Decodes a header block one field at a time so the size of the decoded
+/// header list can be limited, the decoder expands the whole block up
+/// front and a small block can reference a large table entry many times.
+///
+/// Returns the error code to fail the connection with if the block is
+/// invalid or the headers are larger than `max_size`.
+fn decode_header_block(
+    decoder: &mut hpack::Decoder,
+    block: &[u8],
+    max_size: usize,
+) -> Result<HeaderList, u32> {
+    let mut headers = Vec::new();
+    let mut size = 0;
+    let mut rest = block;
+
+    while !rest.is_empty() {
+        let (len, table_size) = field_len(rest).ok_or(COMPRESSION_ERROR)?;
+
+        // The decoder lets the table grow to any size the client asks for.
+        if matches!(table_size, Some(table_size) if table_size > HEADER_TABLE_SIZE) {
+            return Err(COMPRESSION_ERROR)
+        }
+
+        let (field, remaining) = rest.split_at(len);
+        rest = remaining;
+
+        let decoded = decoder.decode(field).map_err(|_| COMPRESSION_ERROR)?;
+        for (name, value) in decoded {
+            size += name.len() + value.len() + HEADER_OVERHEAD;
+            if size > max_size {
+                return Err(ENHANCE_YOUR_CALM)
+            }
+
+            headers.push((name, value));
+        }
+    }
+
+    Ok(headers)
+}
+
+/// Finds the length of the field at the start of a header block without
+/// decoding it.
+///
+/// Returns the length and the new table size if the field is a dynamic
+/// table size update, or `None` if the field is truncated.
+fn field_len(block: &[u8]) -> Option<(usize, Option<usize>)> {
+    let first = *block.first()?;
+
+    let prefix_bits = if (first & 0x80) != 0 {
+        // A indexed field is only the index.
+        let (_, len) = decode_integer(block, 7)?;
+        return Some((len, None))
+    } else if (first & 0x40) != 0 {
+        6
+    } else if (first & 0x20) != 0 {
+        let (table_size, len) = decode_integer(block, 5)?;
+        return Some((len, Some(table_size)))
+    } else {
+        4
+    };
+
+    // A literal field has a literal name if the name index is zero.
+    let (index, mut len) = decode_integer(block, prefix_bits)?;
+    if index == 0 {
+        len += string_len(&block[len..])?;
+    }
+
+    len += string_len(&block[len..])?;
+
+    Some((len, None))
+}
+
+/// Finds the length of a string literal including its length prefix.
+fn string_len(block: &[u8]) -> Option<usize> {
+    let (len, prefix) = decode_integer(block, 7)?;
+    let end = prefix.checked_add(len)?;
+    if end > block.len() {
+        return None
+    }
+
+    Some(end)
+}
+
+/// Decodes a HPACK integer with the given prefix size.
+///
+/// Returns the value and the amount of bytes it took, or `None` if the
+/// integer is truncated or longer than the decoder accepts.
+fn decode_integer(block: &[u8], prefix_bits: u8) -> Option<(usize, usize)> {
+    let max = (1usize << prefix_bits) - 1;
+    let value = *block.first()? as usize & max;
+    if value < max {
+        return Some((value, 1))
+    }
+
+    let mut value = value;
+    for (i, &byte) in block[1..].iter().take(4).enumerate() {
+        value += ((byte & 0x7F) as usize) << (7 * i);
+        if (byte & 0x80) == 0 {
+            return Some((value, i + 2))
+        }
+    }
+
+    None
+}
+
+/// Splits the decoded request headers into the pseudo-headers and
+/// regular headers.
+///
+/// Returns `None` if the request is malformed.
+fn parse_request_head(headers: HeaderList) -> Option<RequestHead> {
+    let mut method = None;
+    let mut path = None;
+    let mut scheme = None;
+    let mut authority = None;
+    let mut regular = Vec::with_capacity(headers.len());
+
+    for (name, value) in headers {
+        if name.starts_with(b":") {
+            // Pseudo-headers must come before every regular header.
+            if !regular.is_empty() {
+                return None
+            }
+
+            let value = String::from_utf8(value).ok()?;
+            match name.as_slice() {
+                b":method" => method = Some(value),
+                b":path" => path = Some(value),
+                b":scheme" => scheme = Some(value),
+                b":authority" => authority = Some(value.into_bytes()),
+                _ => return None,
+            }
+
+            continue
+        }
+
+        let is_lowercase = name.iter().all(|c| !c.is_ascii_uppercase());
+        if !is_lowercase | CONNECTION_HEADERS.contains(&name.as_slice()) {
+            return None
+        }
+
+        regular.push((name, value));
+    }
+
+    let method = method?;
+    let path = path.filter(|path| !path.is_empty())?;
+    scheme?;
+
+    Some(RequestHead {
+        method,
+        path,
+        authority,
+        headers: regular,
+    })
+}
+
+/// Writes the response HEADERS frame, and any CONTINUATION frames if
+/// the header block is larger than the max frame size.
+fn write_headers(
+    buffer: &mut BytesMut,
+    stream_id: u32,
+    status: u16,
+    headers: &ResponseHeaders,
+    server_header: &Option<String>,
+    max_frame_size: usize,
+) {
+    let mut block = Vec::new();
+    encode_header(&mut block, b":status", status.to_string().as_bytes());
+
+    let mut has_date = false;
+    let mut has_server = false;
+    for (name, value) in headers.iter() {
+        let name = name.to_ascii_lowercase();
+        if CONNECTION_HEADERS.contains(&name.as_slice()) {
+            continue
+        }
+
+        has_date |= name == DATE.as_str().as_bytes();
+        has_server |= name == SERVER.as_str().as_bytes();
+        encode_header(&mut block, &name, value);
+    }
+
+    if !has_server {
+        if let Some(server) = server_header.as_ref() {
+            encode_header(&mut block, SERVER.as_ref(), server.as_bytes());
+        }
+    }
+
+    if !has_date {
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        encode_header(&mut block, DATE.as_ref(), date.as_bytes());
+    }
+
+    let mut chunks = block.chunks(max_frame_size).peekable();
+    let mut kind = FRAME_HEADERS;
+    while let Some(chunk) = chunks.next() {
+        let flags = if chunks.peek().is_none() { FLAG_END_HEADERS } else { 0 };
+        write_frame_head(buffer, chunk.len(), kind, flags, stream_id);
+        buffer.put_slice(chunk);
+
+        kind = FRAME_CONTINUATION;
+    }
+}
+
+/// Encodes a header as a literal without indexing so the client's
+/// dynamic table is never used.
+fn encode_header(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0x00);
+    encode_integer(block, name.len(), 7);
+    block.extend_from_slice(name);
+    encode_integer(block, value.len(), 7);
+    block.extend_from_slice(value);
+}
+
+/// Encodes a HPACK integer with the given prefix size.
+fn encode_integer(block: &mut Vec<u8>, value: usize, prefix_bits: u8) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        block.push(value as u8);
+        return
+    }
+
+    block.push(max as u8);
+
+    let mut value = value - max;
+    while value >= 128 {
+        block.push((value % 128 + 128) as u8);
+        value /= 128;
+    }
+
+    block.push(value as u8);
+}
+
+/// Removes the padding from a frame's payload if it is padded.
+///
+/// Returns `None` if the padding is longer than the payload.
+fn strip_padding(flags: u8, payload: &[u8]) -> Option<&[u8]> {
+    if (flags & FLAG_PADDED) == 0 {
+        return Some(payload)
+    }
+
+    let (&padding, rest) = payload.split_first()?;
+    let padding = padding as usize;
+    if padding > rest.len() {
+        return None
+    }
+
+    Some(&rest[..rest.len() - padding])
+}
+
+/// Writes the 9 byte header of a frame.
+fn write_frame_head(
+    buffer: &mut BytesMut,
+    len: usize,
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+) {
+    buffer.put_uint(len as u64, 3);
+    buffer.put_u8(kind);
+    buffer.put_u8(flags);
+    buffer.put_u32(stream_id & 0x7FFF_FFFF);
+}
+
+/// Writes a WINDOW_UPDATE frame for the given increment.
+fn write_window_update(buffer: &mut BytesMut, stream_id: u32, increment: usize) {
+    write_frame_head(buffer, 4, FRAME_WINDOW_UPDATE, 0, stream_id);
+    buffer.put_u32(increment as u32);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    fn protocol() -> H2Protocol {
+        let poll = mio::Poll::new().unwrap();
+        let waker = mio::Waker::new(poll.registry(), Token(0)).unwrap();
+        let event_loop = EventLoopHandle::from_queue_and_waker(
+            Arc::default(),
+            Arc::new(waker),
+        );
+
+        let callback = Python::with_gil(|py| {
+            CallbackHandler::new(py.None(), None, None)
+        });
+
+        H2Protocol::new(
+            Token(1),
+            callback,
+            event_loop,
+            Arc::new(ServerConfig::default()),
+        )
+    }
+
+    fn settings(pairs: &[(u16, u32)]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (id, value) in pairs.iter() {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+
+        payload
+    }
+
+    fn literal(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+        // Literal with incremental indexing and a new name.
+        block.push(0x40);
+        encode_integer(block, name.len(), 7);
+        block.extend_from_slice(name);
+        encode_integer(block, value.len(), 7);
+        block.extend_from_slice(value);
+    }
+
+    #[test]
+    fn encodes_integers() {
+        // The examples from RFC 7541 appendix C.1.
+        let cases: &[(usize, u8, &[u8])] = &[
+            (10, 5, &[10]),
+            (1337, 5, &[31, 154, 10]),
+            (42, 8, &[42]),
+            (127, 7, &[127, 0]),
+        ];
+
+        for (value, prefix_bits, expected) in cases.iter() {
+            let mut block = Vec::new();
+            encode_integer(&mut block, *value, *prefix_bits);
+            assert_eq!(&block, expected);
+
+            assert_eq!(
+                decode_integer(&block, *prefix_bits),
+                Some((*value, block.len())),
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_or_long_integers() {
+        assert_eq!(decode_integer(&[], 5), None);
+        assert_eq!(decode_integer(&[31, 154], 5), None);
+        assert_eq!(decode_integer(&[31, 0xFF, 0xFF, 0xFF, 0xFF, 0x01], 5), None);
+    }
+
+    #[test]
+    fn strips_padding() {
+        assert_eq!(strip_padding(0, b"abc"), Some(&b"abc"[..]));
+        assert_eq!(strip_padding(FLAG_PADDED, b"\x02abc\0\0"), Some(&b"abc"[..]));
+        assert_eq!(strip_padding(FLAG_PADDED, b"\x03abc"), Some(&b""[..]));
+        assert_eq!(strip_padding(FLAG_PADDED, b"\x04abc"), None);
+        assert_eq!(strip_padding(FLAG_PADDED, b""), None);
+    }
+
+    #[test]
+    fn applies_initial_window_to_open_streams() {
+        let mut protocol = protocol();
+        let stream = Stream::new(
+            protocol.token,
+            protocol.event_loop.clone(),
+            DEFAULT_WINDOW_SIZE - 100,
+            false,
+            &protocol.config,
+        );
+        protocol.streams.insert(1, stream);
+
+        let payload = settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 100_000)]);
+        assert_eq!(protocol.apply_settings(&payload), Ok(()));
+
+        assert_eq!(protocol.peer_initial_window, 100_000);
+        assert_eq!(protocol.streams[&1].send_window, 100_000 - 100);
+    }
+
+    #[test]
+    fn applies_max_frame_size() {
+        let mut protocol = protocol();
+
+        let payload = settings(&[(SETTINGS_MAX_FRAME_SIZE, 32_768), (0xFF, 1)]);
+        assert_eq!(protocol.apply_settings(&payload), Ok(()));
+        assert_eq!(protocol.peer_max_frame_size, 32_768);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let cases = [
+            (SETTINGS_ENABLE_PUSH, 2, PROTOCOL_ERROR),
+            (SETTINGS_INITIAL_WINDOW_SIZE, 1 << 31, FLOW_CONTROL_ERROR),
+            (SETTINGS_MAX_FRAME_SIZE, 16_383, PROTOCOL_ERROR),
+            (SETTINGS_MAX_FRAME_SIZE, 1 << 24, PROTOCOL_ERROR),
+        ];
+
+        for (id, value, code) in cases.iter() {
+            let mut protocol = protocol();
+            let payload = settings(&[(*id, *value)]);
+            assert_eq!(protocol.apply_settings(&payload), Err(*code));
+        }
+    }
+
+    #[test]
+    fn decodes_header_blocks_sharing_the_table() {
+        let mut encoder = hpack::Encoder::new();
+        let mut decoder = hpack::Decoder::new();
+        let headers = vec![
+            (b":method".to_vec(), b"GET".to_vec()),
+            (b":path".to_vec(), b"/".to_vec()),
+            (b"x-custom".to_vec(), b"value".to_vec()),
+        ];
+
+        let block = encoder.encode(&headers);
+        assert_eq!(decode_header_block(&mut decoder, &block, 1024), Ok(headers));
+
+        let mut first = Vec::new();
+        literal(&mut first, b"x-indexed", b"value");
+        let decoded = decode_header_block(&mut decoder, &first, 1024).unwrap();
+        assert_eq!(decoded, vec![(b"x-indexed".to_vec(), b"value".to_vec())]);
+
+        // The first entry of the dynamic table.
+        let decoded = decode_header_block(&mut decoder, &[0xBE], 1024).unwrap();
+        assert_eq!(decoded, vec![(b"x-indexed".to_vec(), b"value".to_vec())]);
+    }
+
+    #[test]
+    fn limits_decoded_header_list_size() {
+        let mut decoder = hpack::Decoder::new();
+        let mut block = Vec::new();
+        literal(&mut block, b"x-bomb", &[b'a'; 4000]);
+        block.extend(std::iter::repeat(0xBE).take(1000));
+
+        assert!(block.len() < MAX_FRAME_SIZE);
+        assert_eq!(
+            decode_header_block(&mut decoder, &block, 64 * 1024),
+            Err(ENHANCE_YOUR_CALM),
+        );
+    }
+
+    #[test]
+    fn rejects_large_table_size_updates() {
+        let mut decoder = hpack::Decoder::new();
+
+        let mut block = Vec::new();
+        encode_integer(&mut block, HEADER_TABLE_SIZE, 5);
+        block[0] |= 0x20;
+        assert_eq!(decode_header_block(&mut decoder, &block, 1024), Ok(vec![]));
+
+        let mut block = Vec::new();
+        encode_integer(&mut block, HEADER_TABLE_SIZE + 1, 5);
+        block[0] |= 0x20;
+        assert_eq!(
+            decode_header_block(&mut decoder, &block, 1024),
+            Err(COMPRESSION_ERROR),
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_header_blocks() {
+        let mut block = Vec::new();
+        literal(&mut block, b"x-name", b"value");
+
+        for end in 1..block.len() {
+            let mut decoder = hpack::Decoder::new();
+            assert_eq!(
+                decode_header_block(&mut decoder, &block[..end], 1024),
+                Err(COMPRESSION_ERROR),
+            );
+        }
+    }
+}
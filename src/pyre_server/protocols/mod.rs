@@ -1 +1,4 @@
 pub mod h1;
+pub mod ws;
+pub mod h2;
+pub mod scope;
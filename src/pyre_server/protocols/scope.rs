@@ -0,0 +1,123 @@
+use pyo3::{PyResult, Python};
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+use std::str;
+
+use crate::pyre_server::net::Addr;
+
+
+/// Builds the ASGI `http` connection scope shared by every HTTP version.
+///
+/// The `headers` list should already contain the lowercased
+/// `(name, value)` byte pairs of the request.
+#[allow(clippy::too_many_arguments)]
+pub fn http_scope<'a>(
+    py: Python<'a>,
+    method: &str,
+    path: &str,
+    http_version: &str,
+    headers: &'a PyList,
+    client: Option<&Addr>,
+    server: Option<&Addr>,
+    secure: bool,
+) -> PyResult<&'a PyDict> {
+    let (raw_path, query_string) = match path.find('?') {
+        Some(index) => (&path[..index], &path[index + 1..]),
+        None => (path, ""),
+    };
+
+    let decoded = percent_decode(raw_path.as_bytes());
+
+    let asgi = PyDict::new(py);
+    asgi.set_item("version", "3.0")?;
+    asgi.set_item("spec_version", "2.1")?;
+
+    let scope = PyDict::new(py);
+    scope.set_item("type", "http")?;
+    scope.set_item("asgi", asgi)?;
+    scope.set_item("http_version", http_version)?;
+    scope.set_item("method", method)?;
+    scope.set_item("scheme", if secure { "https" } else { "http" })?;
+    scope.set_item("path", String::from_utf8_lossy(&decoded))?;
+    scope.set_item("raw_path", PyBytes::new(py, raw_path.as_bytes()))?;
+    scope.set_item(
+        "query_string",
+        PyBytes::new(py, query_string.as_bytes())
+    )?;
+    scope.set_item("root_path", "")?;
+    scope.set_item("headers", headers)?;
+    scope.set_item("client", client.and_then(addr_to_tuple))?;
+    scope.set_item("server", server.and_then(addr_to_tuple))?;
+
+    // Files given by the app are sent by the kernel where possible.
+    let extensions = PyDict::new(py);
+    #[cfg(unix)]
+    extensions.set_item("http.response.zerocopysend", PyDict::new(py))?;
+    scope.set_item("extensions", extensions)?;
+
+    Ok(scope)
+}
+
+/// Converts a socket address into the `(host, port)` pair used by ASGI,
+/// a Unix socket is given as `(path, None)` and is `None` if unnamed.
+pub fn addr_to_tuple(addr: &Addr) -> Option<(String, Option<u16>)> {
+    match addr {
+        Addr::Inet(addr) => Some((addr.ip().to_string(), Some(addr.port()))),
+
+        #[cfg(unix)]
+        Addr::Unix(path) => path.as_ref()
+            .map(|path| (path.to_string_lossy().into_owned(), None)),
+    }
+}
+
+/// Decodes any `%XX` escapes in the request path, invalid escapes are
+/// left as they are.
+fn percent_decode(raw: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(raw.len());
+
+    let mut i = 0;
+    while i < raw.len() {
+        // `from_str_radix` alone would accept a sign e.g. `%+5`.
+        let is_escape = (raw[i] == b'%')
+            && (i + 2 < raw.len())
+            && raw[i + 1].is_ascii_hexdigit()
+            && raw[i + 2].is_ascii_hexdigit();
+
+        if is_escape {
+            let hex = str::from_utf8(&raw[i + 1..i + 3]).ok()
+                .and_then(|v| u8::from_str_radix(v, 16).ok());
+
+            if let Some(byte) = hex {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+
+        decoded.push(raw[i]);
+        i += 1;
+    }
+
+    decoded
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode(b"/a%20b%2Fc"), b"/a b/c");
+        assert_eq!(percent_decode(b"/%e2%82%AC"), "/\u{20ac}".as_bytes());
+    }
+
+    #[test]
+    fn leaves_invalid_escapes() {
+        assert_eq!(percent_decode(b"/%+5"), b"/%+5");
+        assert_eq!(percent_decode(b"/%-1x"), b"/%-1x");
+        assert_eq!(percent_decode(b"/%zz"), b"/%zz");
+        assert_eq!(percent_decode(b"/%4"), b"/%4");
+        assert_eq!(percent_decode(b"/%"), b"/%");
+    }
+}
@@ -0,0 +1,916 @@
+use crate::pyre_server::abc::ProtocolBuffers;
+use crate::pyre_server::switch::{Switchable, SwitchStatus};
+use crate::pyre_server::transport::EventLoopHandle;
+use crate::pyre_server::py_callback::{CallbackHandler, Permit};
+use crate::pyre_server::config::SharedConfig;
+use crate::pyre_server::responders::{
+    SenderPayload,
+    ReceiverPayload,
+    ResponseHeaders,
+    Message,
+};
+use crate::pyre_server::responders::sender::SenderHandler;
+use crate::pyre_server::responders::receiver::ReceiverHandler;
+use crate::pyre_server::protocols::h1::write_header;
+use crate::pyre_server::socket_io::WriteBuffer;
+
+use pyo3::{PyResult, Python, Py};
+use pyo3::types::{PyDict, PyList};
+
+use std::mem;
+use std::str;
+use std::time::SystemTime;
+
+use bytes::{BytesMut, BufMut};
+use mio::Token;
+use sha1::Sha1;
+
+use httparse::Header;
+use http::StatusCode;
+use http::header::{
+    CONNECTION,
+    UPGRADE,
+    SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION,
+    SEC_WEBSOCKET_ACCEPT,
+    SEC_WEBSOCKET_PROTOCOL,
+    CONTENT_LENGTH,
+    DATE,
+    SERVER,
+};
+
+
+/// The GUID appended to the client's key to produce the accept key.
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// The close codes used by the server, see RFC 6455 section 7.4.1.
+const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const NO_STATUS_RECEIVED: u16 = 1005;
+const ABNORMAL_CLOSURE: u16 = 1006;
+const INVALID_PAYLOAD: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+const INTERNAL_ERROR: u16 = 1011;
+
+/// The longest close reason that fits in a close frame after the code,
+/// control frames are limited to 125 bytes.
+const MAX_CLOSE_REASON: usize = 123;
+
+
+/// The upgrade request handed over from the HTTP/1.1 protocol.
+pub struct Handshake {
+    /// The ASGI `websocket` connection scope.
+    pub scope: Py<PyDict>,
+
+    /// The value of the `Sec-WebSocket-Accept` response header.
+    pub accept_key: String,
+
+    /// The in-flight permit the connection is handed to the app with.
+    pub permit: Permit,
+}
+
+
+/// The state of the WebSocket connection.
+#[derive(Copy, Clone, PartialEq)]
+enum WebSocketState {
+    /// Waiting on the app to accept or reject the connection.
+    Handshake,
+
+    /// Messages can be sent in both directions.
+    Open,
+
+    /// A close frame has been sent and the server is waiting on the
+    /// client's close frame.
+    Closing,
+
+    /// The connection is closed once the write buffer has been drained.
+    Closed,
+}
+
+
+/// A single frame read from the client.
+struct Frame {
+    /// If this is the final frame of the message.
+    fin: bool,
+
+    /// The frame's opcode.
+    opcode: u8,
+
+    /// The unmasked payload data.
+    payload: Vec<u8>,
+}
+
+
+/// The protocol to add handling for RFC 6455 WebSockets once a HTTP/1.1
+/// connection has been upgraded.
+pub struct WebSocketProtocol {
+    /// The event loop handle, this handles all event loop interactions.
+    event_loop: EventLoopHandle,
+
+    /// The client token that identifies itself.
+    token: Token,
+
+    /// The python callback handler.
+    callback: CallbackHandler,
+
+    /// The sender half handler for ASGI callbacks.
+    sender: SenderHandler,
+
+    /// The receiver half handler for ASGI callbacks.
+    receiver: ReceiverHandler,
+
+    /// The shared server settings.
+    config: SharedConfig,
+
+    /// The state of the connection.
+    state: WebSocketState,
+
+    /// The accept key sent in the handshake response.
+    accept_key: String,
+
+    /// The opcode and data of a fragmented message that is still
+    /// being received.
+    fragment: Option<(u8, Vec<u8>)>,
+
+    /// Frames written by the server itself e.g. pongs that are waiting
+    /// to be written to the socket.
+    pending: BytesMut,
+}
+
+impl WebSocketProtocol {
+    /// Create a new WebSocketProtocol instance.
+    pub fn new(
+        token: Token,
+        callback: CallbackHandler,
+        event_loop: EventLoopHandle,
+        config: SharedConfig,
+    ) -> Self {
+        let sender = SenderHandler::new(
+            token,
+            event_loop.clone()
+        );
+        let receiver = ReceiverHandler::websocket(
+            token,
+            event_loop.clone(),
+            &config,
+        );
+
+        Self {
+            token,
+            event_loop,
+            callback,
+            sender,
+            receiver,
+            config,
+
+            state: WebSocketState::Closed,
+            accept_key: String::new(),
+            fragment: None,
+            pending: BytesMut::new(),
+        }
+    }
+}
+
+impl WebSocketProtocol {
+    /// Called when the connection has been upgraded, this invokes the
+    /// app with the `websocket` scope and a `websocket.connect` event.
+    pub fn open(&mut self, handshake: Handshake) -> PyResult<()> {
+        self.receiver.reset();
+        self.sender.reset();
+
+        self.state = WebSocketState::Handshake;
+        self.accept_key = handshake.accept_key;
+        self.fragment = None;
+        self.pending.clear();
+
+        let _ = self.receiver.send(ReceiverPayload::Connect);
+
+        let sender = self.sender.make_handle();
+        let receiver = self.receiver.make_handle();
+        self.callback.dispatch(
+            self.token,
+            handshake.permit,
+            (handshake.scope, receiver, sender),
+            self.sender.make_exit_handle(),
+        );
+
+        Ok(())
+    }
+
+    /// Called when the connection is lost from the protocol in order to
+    /// properly reset state.
+    pub fn lost_connection(&mut self) -> PyResult<()> {
+        // The app has already been given the close code otherwise.
+        if (self.state == WebSocketState::Handshake)
+            | (self.state == WebSocketState::Open) {
+            self.receiver.set_close_code(ABNORMAL_CLOSURE);
+        }
+
+        self.receiver.set_disconnected(true);
+        self.state = WebSocketState::Closed;
+        self.fragment = None;
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// If the connection is no longer being used by the app, a closing
+    /// connection is left to the keep alive timeout if the client never
+    /// completes the close.
+    pub fn is_awaiting_request(&self) -> bool {
+        (self.state == WebSocketState::Closing)
+            | (self.state == WebSocketState::Closed)
+    }
+
+    /// The amount of message data handed to the app that it has not
+    /// received yet.
+    pub fn queued_bytes(&self) -> usize {
+        self.receiver.queued()
+    }
+
+    /// If data in the read buffer is left waiting on the app rather than
+    /// being processed, frames are only read once the app accepts.
+    pub fn is_blocked(&self) -> bool {
+        self.state == WebSocketState::Handshake
+    }
+
+    /// Sets whether reading has been paused until the app receives the
+    /// messages waiting on it.
+    pub fn set_backlog_paused(&self, paused: bool) {
+        self.receiver.set_paused(paused);
+    }
+
+    /// If the connection should be closed once the write buffer has
+    /// been drained.
+    pub fn should_close(&self) -> bool {
+        self.state == WebSocketState::Closed
+    }
+
+    /// Called when the server is shutting down, an open connection is
+    /// sent a close frame with `1001 Going Away` and a connection the app
+    /// has not accepted yet is rejected with `503 Service Unavailable`,
+    /// either way the app is told it has disconnected.
+    pub fn shutdown(&mut self) {
+        match self.state {
+            WebSocketState::Handshake => {
+                let mut pending = mem::take(&mut self.pending);
+                self.reject(&mut pending, StatusCode::SERVICE_UNAVAILABLE);
+                self.pending = pending;
+            },
+            WebSocketState::Open => {
+                write_frame(&mut self.pending, OP_CLOSE, &GOING_AWAY.to_be_bytes());
+                self.state = WebSocketState::Closing;
+            },
+            _ => return,
+        }
+
+        self.receiver.set_close_code(GOING_AWAY);
+        self.receiver.set_disconnected(true);
+
+        self.event_loop.resume_writing(self.token);
+    }
+}
+
+impl ProtocolBuffers for WebSocketProtocol {
+    fn data_received(&mut self, buffer: &mut BytesMut) -> PyResult<()> {
+        loop {
+            match self.state {
+                // Clients must wait on the handshake response before
+                // sending any frames.
+                WebSocketState::Handshake => return Ok(()),
+                WebSocketState::Closed => {
+                    buffer.clear();
+                    return Ok(())
+                },
+                _ => {},
+            }
+
+            // The app has to consume some of the messages before we read
+            // more, the data is left in the buffer until then.
+            if self.receiver.is_full() {
+                self.receiver.pause_while_full();
+                return Ok(())
+            }
+
+            let max_size = self.config.ws_max_size;
+            match parse_frame(buffer, max_size) {
+                Ok(Some(frame)) => self.on_frame(frame),
+                Ok(None) => return Ok(()),
+                Err(code) => self.fail(code),
+            }
+        }
+    }
+
+    fn fill_write_buffer(&mut self, buffer: &mut WriteBuffer) -> PyResult<()> {
+        buffer.extend_from_slice(&self.pending);
+        self.pending.clear();
+
+        // Checked first so every event sent before the app exited has
+        // been written by the time it is handled.
+        let exited = self.sender.app_exited();
+
+        while let Ok(payload) = self.sender.recv() {
+            match payload {
+                SenderPayload::Accept(subprotocol, headers) => {
+                    self.on_accept(buffer, subprotocol, &headers);
+                },
+                SenderPayload::Send(message) => {
+                    if self.state == WebSocketState::Open {
+                        match message {
+                            Message::Text(text) => {
+                                write_frame(buffer, OP_TEXT, text.as_bytes());
+                            },
+                            Message::Binary(data) => {
+                                write_frame(buffer, OP_BINARY, &data);
+                            },
+                        }
+                    }
+                },
+                SenderPayload::Close(code, reason) => {
+                    self.on_app_close(buffer, code, &reason);
+                },
+
+                // HTTP events have no meaning once upgraded.
+                SenderPayload::Start(..) | SenderPayload::Body(..) => {},
+            }
+        }
+
+        if let Some(failed) = exited {
+            self.on_app_exit(buffer, failed);
+        }
+
+        self.event_loop.pause_writing(self.token);
+
+        Ok(())
+    }
+}
+
+impl Switchable for WebSocketProtocol {
+    /// WebSockets can not be upgraded any further.
+    fn switch_protocol(&mut self) -> PyResult<SwitchStatus> {
+        Ok(SwitchStatus::NoSwitch)
+    }
+}
+
+impl WebSocketProtocol {
+    /// Handles a single frame sent by the client.
+    fn on_frame(&mut self, frame: Frame) {
+        match frame.opcode {
+            OP_CONTINUATION => {
+                let (opcode, mut data) = match self.fragment.take() {
+                    Some(fragment) => fragment,
+                    None => return self.fail(PROTOCOL_ERROR),
+                };
+
+                data.extend_from_slice(&frame.payload);
+                if data.len() > self.config.ws_max_size {
+                    return self.fail(MESSAGE_TOO_BIG)
+                }
+
+                if frame.fin {
+                    self.on_message(opcode, data);
+                } else {
+                    self.fragment = Some((opcode, data));
+                }
+            },
+            OP_TEXT | OP_BINARY => {
+                // A new message can not start until the fragmented
+                // one has finished.
+                if self.fragment.is_some() {
+                    return self.fail(PROTOCOL_ERROR)
+                }
+
+                if frame.fin {
+                    self.on_message(frame.opcode, frame.payload);
+                } else {
+                    self.fragment = Some((frame.opcode, frame.payload));
+                }
+            },
+            OP_CLOSE => self.on_close(&frame.payload),
+            OP_PING => {
+                write_frame(&mut self.pending, OP_PONG, &frame.payload);
+                self.event_loop.resume_writing(self.token);
+            },
+            OP_PONG => {},
+            _ => self.fail(PROTOCOL_ERROR),
+        }
+    }
+
+    /// Sends a complete message to the app.
+    fn on_message(&mut self, opcode: u8, data: Vec<u8>) {
+        // The app has already closed its side of the connection.
+        if self.state != WebSocketState::Open {
+            return
+        }
+
+        let message = if opcode == OP_TEXT {
+            match String::from_utf8(data) {
+                Ok(text) => Message::Text(text),
+                Err(_) => return self.fail(INVALID_PAYLOAD),
+            }
+        } else {
+            Message::Binary(data)
+        };
+
+        let _ = self.receiver.send(ReceiverPayload::Message(message));
+    }
+
+    /// Handles a close frame sent by the client, the close is echoed
+    /// back if the server has not already sent one.
+    fn on_close(&mut self, payload: &[u8]) {
+        let code = match payload.len() {
+            0 => NO_STATUS_RECEIVED,
+            1 => return self.fail(PROTOCOL_ERROR),
+            _ => u16::from_be_bytes([payload[0], payload[1]]),
+        };
+
+        if (code != NO_STATUS_RECEIVED) && !is_valid_close_code(code) {
+            return self.fail(PROTOCOL_ERROR)
+        }
+
+        if (payload.len() > 2) && str::from_utf8(&payload[2..]).is_err() {
+            return self.fail(INVALID_PAYLOAD)
+        }
+
+        if self.state == WebSocketState::Open {
+            let echo = if code == NO_STATUS_RECEIVED { &[] } else { &payload[..2] };
+            write_frame(&mut self.pending, OP_CLOSE, echo);
+
+            self.receiver.set_close_code(code);
+            self.receiver.set_disconnected(true);
+        }
+
+        self.state = WebSocketState::Closed;
+        self.event_loop.resume_writing(self.token);
+    }
+
+    /// Fails the connection after a protocol error sending a close frame
+    /// with the given code and telling the app it has disconnected.
+    fn fail(&mut self, code: u16) {
+        if self.state == WebSocketState::Open {
+            write_frame(&mut self.pending, OP_CLOSE, &code.to_be_bytes());
+
+            self.receiver.set_close_code(code);
+            self.receiver.set_disconnected(true);
+        }
+
+        self.state = WebSocketState::Closed;
+        self.fragment = None;
+        self.event_loop.resume_writing(self.token);
+    }
+
+    /// Writes the handshake response once the app accepts the connection.
+    fn on_accept(
+        &mut self,
+        buffer: &mut BytesMut,
+        subprotocol: Option<String>,
+        headers: &ResponseHeaders,
+    ) {
+        if self.state != WebSocketState::Handshake {
+            return
+        }
+
+        buffer.put_slice(b"HTTP/1.1 101 Switching Protocols\r\n");
+        write_header(buffer, UPGRADE.as_ref(), b"websocket");
+        write_header(buffer, CONNECTION.as_ref(), b"Upgrade");
+        write_header(
+            buffer,
+            SEC_WEBSOCKET_ACCEPT.as_ref(),
+            self.accept_key.as_bytes(),
+        );
+
+        if let Some(subprotocol) = subprotocol {
+            write_header(
+                buffer,
+                SEC_WEBSOCKET_PROTOCOL.as_ref(),
+                subprotocol.as_bytes(),
+            );
+        }
+
+        for (name, value) in headers.iter() {
+            write_header(buffer, name, value);
+        }
+
+        self.write_server_headers(buffer);
+        buffer.put_slice(b"\r\n");
+
+        self.state = WebSocketState::Open;
+
+        // Any frames sent straight after the handshake are waiting
+        // in the read buffer.
+        self.event_loop.resume_reading(self.token);
+    }
+
+    /// Handles the app exiting without closing the connection, the
+    /// connection is closed with `1011 Internal Error` or rejected with a
+    /// `500 Internal Server Error` if the app raised.
+    fn on_app_exit(&mut self, buffer: &mut BytesMut, failed: bool) {
+        match (self.state, failed) {
+            (WebSocketState::Handshake, true) => {
+                self.reject(buffer, StatusCode::INTERNAL_SERVER_ERROR);
+                self.receiver.set_disconnected(true);
+            },
+            (_, true) => self.on_app_close(buffer, INTERNAL_ERROR, ""),
+            (_, false) => self.on_app_close(buffer, NORMAL_CLOSURE, ""),
+        }
+    }
+
+    /// Handles the app closing the connection, this rejects the
+    /// connection with a `403 Forbidden` if it has not been accepted yet.
+    fn on_app_close(&mut self, buffer: &mut BytesMut, code: u16, reason: &str) {
+        match self.state {
+            WebSocketState::Handshake => {
+                self.reject(buffer, StatusCode::FORBIDDEN);
+            },
+            WebSocketState::Open => {
+                let code = if is_valid_close_code(code) {
+                    code
+                } else {
+                    NORMAL_CLOSURE
+                };
+
+                let mut payload = code.to_be_bytes().to_vec();
+                payload.extend_from_slice(truncate_reason(reason).as_bytes());
+                write_frame(buffer, OP_CLOSE, &payload);
+
+                self.state = WebSocketState::Closing;
+            },
+            _ => return,
+        }
+
+        self.receiver.set_close_code(code);
+        self.receiver.set_disconnected(true);
+    }
+
+    /// Rejects the handshake with the given status closing the connection.
+    fn reject(&mut self, buffer: &mut BytesMut, status: StatusCode) {
+        buffer.put_slice(format!(
+            "HTTP/1.1 {} {}\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("Unknown"),
+        ).as_bytes());
+
+        write_header(buffer, CONTENT_LENGTH.as_ref(), b"0");
+        write_header(buffer, CONNECTION.as_ref(), b"close");
+        self.write_server_headers(buffer);
+        buffer.put_slice(b"\r\n");
+
+        self.state = WebSocketState::Closed;
+    }
+
+    /// Writes the `Server` and `Date` headers to the handshake response.
+    fn write_server_headers(&self, buffer: &mut BytesMut) {
+        if let Some(server) = self.config.server_header.as_ref() {
+            write_header(buffer, SERVER.as_ref(), server.as_bytes());
+        }
+
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        write_header(buffer, DATE.as_ref(), date.as_bytes());
+    }
+}
+
+/// Checks if the request is a WebSocket upgrade request.
+///
+/// Returns the accept key for the handshake response if it is, or the
+/// error status to respond with if the upgrade request is invalid.
+pub fn handshake_accept_key(
+    method: &str,
+    version: u8,
+    headers: &[Header],
+) -> Result<Option<String>, StatusCode> {
+    let mut is_websocket = false;
+    let mut is_upgrade = false;
+    let mut key = None;
+    let mut websocket_version = None;
+
+    for header in headers.iter() {
+        let lowered = header.value.to_ascii_lowercase();
+        let value = str::from_utf8(&lowered).unwrap_or("");
+
+        if header.name == UPGRADE {
+            is_websocket |= value.split(',').any(|v| v.trim() == "websocket");
+        } else if header.name == CONNECTION {
+            is_upgrade |= value.split(',').any(|v| v.trim() == "upgrade");
+        } else if header.name == SEC_WEBSOCKET_KEY {
+            key = Some(header.value);
+        } else if header.name == SEC_WEBSOCKET_VERSION {
+            websocket_version = Some(header.value);
+        }
+    }
+
+    if !is_websocket | !is_upgrade {
+        return Ok(None)
+    }
+
+    if (method != "GET") | (version == 0) {
+        return Err(StatusCode::BAD_REQUEST)
+    }
+
+    if websocket_version != Some(b"13") {
+        return Err(StatusCode::BAD_REQUEST)
+    }
+
+    let key = key.ok_or(StatusCode::BAD_REQUEST)?;
+
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(WEBSOCKET_GUID);
+
+    Ok(Some(base64::encode(hasher.digest().bytes())))
+}
+
+/// Converts a `http` scope into a ASGI `websocket` scope.
+pub fn upgrade_scope(
+    py: Python,
+    scope: &PyDict,
+    headers: &[Header],
+    secure: bool,
+) -> PyResult<()> {
+    let subprotocols = PyList::empty(py);
+    for header in headers.iter() {
+        if header.name != SEC_WEBSOCKET_PROTOCOL {
+            continue
+        }
+
+        let value = String::from_utf8_lossy(header.value);
+        for subprotocol in value.split(',').map(str::trim) {
+            if !subprotocol.is_empty() {
+                subprotocols.append(subprotocol)?;
+            }
+        }
+    }
+
+    scope.set_item("type", "websocket")?;
+    scope.set_item("scheme", if secure { "wss" } else { "ws" })?;
+    scope.set_item("subprotocols", subprotocols)?;
+    scope.del_item("method")?;
+
+    Ok(())
+}
+
+/// Parses a single frame from the buffer.
+///
+/// Returns `None` if the frame is incomplete or the close code to fail
+/// the connection with if the frame is invalid.
+fn parse_frame(
+    buffer: &mut BytesMut,
+    max_size: usize,
+) -> Result<Option<Frame>, u16> {
+    if buffer.len() < 2 {
+        return Ok(None)
+    }
+
+    let fin = (buffer[0] & 0x80) != 0;
+    let reserved = buffer[0] & 0x70;
+    let opcode = buffer[0] & 0x0F;
+    let masked = (buffer[1] & 0x80) != 0;
+
+    // No extensions are negotiated and clients must always mask.
+    if (reserved != 0) | !masked {
+        return Err(PROTOCOL_ERROR)
+    }
+
+    let (len, offset) = match buffer[1] & 0x7F {
+        126 => {
+            if buffer.len() < 4 {
+                return Ok(None)
+            }
+
+            (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4)
+        },
+        127 => {
+            if buffer.len() < 10 {
+                return Ok(None)
+            }
+
+            let mut len = [0; 8];
+            len.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(len), 10)
+        },
+        len => (len as u64, 2),
+    };
+
+    // Control frames can not be fragmented or have a large payload.
+    if (opcode >= OP_CLOSE) & (!fin | (len > 125)) {
+        return Err(PROTOCOL_ERROR)
+    }
+
+    if len > max_size as u64 {
+        return Err(MESSAGE_TOO_BIG)
+    }
+
+    let len = len as usize;
+    if buffer.len() < offset + 4 + len {
+        return Ok(None)
+    }
+
+    let frame = buffer.split_to(offset + 4 + len);
+    let mask = &frame[offset..offset + 4];
+    let payload = frame[offset + 4..]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+
+    Ok(Some(Frame { fin, opcode, payload }))
+}
+
+/// Writes a single unmasked frame to the buffer.
+fn write_frame(buffer: &mut BytesMut, opcode: u8, payload: &[u8]) {
+    buffer.put_u8(0x80 | opcode);
+
+    let len = payload.len();
+    if len < 126 {
+        buffer.put_u8(len as u8);
+    } else if len <= u16::MAX as usize {
+        buffer.put_u8(126);
+        buffer.put_u16(len as u16);
+    } else {
+        buffer.put_u8(127);
+        buffer.put_u64(len as u64);
+    }
+
+    buffer.put_slice(payload);
+}
+
+/// Cuts the close reason down to fit in a close frame without splitting
+/// a character, the reason must be valid UTF-8.
+fn truncate_reason(reason: &str) -> &str {
+    if reason.len() <= MAX_CLOSE_REASON {
+        return reason
+    }
+
+    let mut end = MAX_CLOSE_REASON;
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &reason[..end]
+}
+
+/// If the close code can be sent in a close frame.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xFA, 0x21, 0x3D];
+
+    /// Builds a masked frame the way a client sends it.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> BytesMut {
+        let mut unmasked = BytesMut::new();
+        write_frame(&mut unmasked, opcode, payload);
+        if !fin {
+            unmasked[0] &= 0x7F;
+        }
+
+        let offset = unmasked.len() - payload.len();
+        let mut frame = BytesMut::from(&unmasked[..offset]);
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+
+        frame
+    }
+
+    #[test]
+    fn parses_masked_frame() {
+        let mut buffer = client_frame(true, OP_TEXT, b"Hello");
+        assert_eq!(&buffer[..2], &[0x81, 0x85]);
+
+        let frame = parse_frame(&mut buffer, 1024).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OP_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn parses_extended_lengths() {
+        for len in [126, 65535, 65536].iter() {
+            let payload = vec![7; *len];
+            let mut buffer = client_frame(true, OP_BINARY, &payload);
+
+            let frame = parse_frame(&mut buffer, 1 << 20).unwrap().unwrap();
+            assert_eq!(frame.payload, payload);
+        }
+    }
+
+    #[test]
+    fn waits_on_partial_frame() {
+        let frame = client_frame(true, OP_BINARY, &[1; 300]);
+
+        for end in 0..frame.len() {
+            let mut buffer = BytesMut::from(&frame[..end]);
+            assert!(parse_frame(&mut buffer, 1024).unwrap().is_none());
+            assert_eq!(buffer.len(), end);
+        }
+    }
+
+    #[test]
+    fn parses_fragments_in_order() {
+        let mut buffer = client_frame(false, OP_TEXT, b"Hel");
+        buffer.extend_from_slice(&client_frame(true, OP_PING, b"")[..]);
+        buffer.extend_from_slice(&client_frame(true, OP_CONTINUATION, b"lo")[..]);
+
+        let first = parse_frame(&mut buffer, 1024).unwrap().unwrap();
+        assert!(!first.fin);
+        assert_eq!((first.opcode, &first.payload[..]), (OP_TEXT, &b"Hel"[..]));
+
+        let ping = parse_frame(&mut buffer, 1024).unwrap().unwrap();
+        assert_eq!(ping.opcode, OP_PING);
+
+        let last = parse_frame(&mut buffer, 1024).unwrap().unwrap();
+        assert!(last.fin);
+        assert_eq!((last.opcode, &last.payload[..]), (OP_CONTINUATION, &b"lo"[..]));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn rejects_unmasked_frame() {
+        let mut buffer = BytesMut::new();
+        write_frame(&mut buffer, OP_TEXT, b"Hello");
+
+        assert_eq!(parse_frame(&mut buffer, 1024).err(), Some(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn rejects_reserved_bits() {
+        let mut buffer = client_frame(true, OP_TEXT, b"Hello");
+        buffer[0] |= 0x40;
+
+        assert_eq!(parse_frame(&mut buffer, 1024).err(), Some(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn rejects_invalid_control_frames() {
+        let mut buffer = client_frame(false, OP_PING, b"");
+        assert_eq!(parse_frame(&mut buffer, 1024).err(), Some(PROTOCOL_ERROR));
+
+        let mut buffer = client_frame(true, OP_CLOSE, &[0; 126]);
+        assert_eq!(parse_frame(&mut buffer, 1024).err(), Some(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn rejects_frame_over_max_size() {
+        let mut buffer = client_frame(true, OP_BINARY, &[0; 200]);
+        assert_eq!(parse_frame(&mut buffer, 100).err(), Some(MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn writes_frame_lengths() {
+        let cases = [(0, 2), (125, 2), (126, 4), (65535, 4), (65536, 10)];
+
+        for (len, header) in cases.iter() {
+            let mut buffer = BytesMut::new();
+            write_frame(&mut buffer, OP_BINARY, &vec![0; *len]);
+
+            assert_eq!(buffer[0], 0x80 | OP_BINARY);
+            assert_eq!(buffer.len(), header + len);
+        }
+
+        let mut buffer = BytesMut::new();
+        write_frame(&mut buffer, OP_CLOSE, &NORMAL_CLOSURE.to_be_bytes());
+        assert_eq!(&buffer[..], &[0x88, 0x02, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn validates_close_codes() {
+        for code in [1000, 1001, 1003, 1007, 1011, 3000, 4999].iter() {
+            assert!(is_valid_close_code(*code), "{}", code);
+        }
+
+        for code in [0, 999, 1004, 1005, 1006, 1012, 1015, 2999, 5000].iter() {
+            assert!(!is_valid_close_code(*code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn truncates_close_reason_on_char_boundary() {
+        assert_eq!(truncate_reason("bye"), "bye");
+
+        let reason = "a".repeat(200);
+        assert_eq!(truncate_reason(&reason).len(), MAX_CLOSE_REASON);
+
+        // Each character is 3 bytes so 123 bytes is exactly 41 of them.
+        let reason = "€".repeat(50);
+        assert_eq!(truncate_reason(&reason), "€".repeat(41));
+
+        let reason = format!("a{}", "€".repeat(50));
+        let truncated = truncate_reason(&reason);
+        assert_eq!(truncated.len(), 121);
+        assert!(truncated.ends_with('€'));
+    }
+}
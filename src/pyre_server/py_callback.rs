@@ -1,28 +1,423 @@
-use pyo3::{PyObject, Python, IntoPy, Py, PyResult};
+use pyo3::prelude::*;
 use pyo3::types::PyTuple;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::{PyObject, Python, IntoPy, Py, PyResult, PyErr, AsPyPointer};
 
-use std::sync::Arc;
+use mio::Token;
+use rustc_hash::FxHashMap;
 
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-/// A cheaply cloneable helper function that wraps a python callback.
+use crate::pyre_server::transport::EventLoopHandle;
+use crate::pyre_server::responders::sender::ExitHandle;
+use crate::pyre_server::logging;
+
+
+/// The error given when a async app is used without a event loop.
+const NO_LOOP_ERROR: &str =
+    "the app is async but no event loop was given to run it on, pass `loop`";
+
+/// The limit on the number of requests being handled by the app at
+/// once, this is shared between every worker.
+struct InFlight {
+    /// The number of permits currently held.
+    count: AtomicUsize,
+
+    /// The max number of permits, there is no limit if this is `None`.
+    max: Option<usize>,
+
+    /// The clients waiting on a permit to be given back before they
+    /// read their next request.
+    waiting: Mutex<Vec<(Token, EventLoopHandle)>>,
+}
+
+impl InFlight {
+    /// Takes a permit if the limit has not been reached.
+    fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let max = self.max.unwrap_or(usize::MAX);
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                if count < max { Some(count + 1) } else { None }
+            })
+            .ok()?;
+
+        Some(Permit { in_flight: self.clone() })
+    }
+}
+
+
+/// A single slot in the in-flight limit, this is held for as long as
+/// the app is handling the request and given back once dropped.
+pub struct Permit {
+    in_flight: Arc<InFlight>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.in_flight.count.fetch_sub(1, Ordering::AcqRel);
+
+        // Every waiting client tries again as some of them may have
+        // been closed since they started waiting.
+        let waiting = mem::take(&mut *self.in_flight.waiting.lock().unwrap());
+        for (token, event_loop) in waiting {
+            event_loop.resume_reading(token);
+        }
+    }
+}
+
+
+/// A cheaply cloneable helper that dispatches requests to the python
+/// app, this either calls the app directly or runs it as a task on a
+/// asyncio event loop.
 #[derive(Clone)]
 pub struct CallbackHandler {
     /// The python callback itself.
     cb: Arc<PyObject>,
+
+    /// The asyncio event loop the app is run on, the callback is called
+    /// directly on the server thread if this is `None`.
+    event_loop: Option<Arc<PyObject>>,
+
+    /// The limit on the requests being handled at once.
+    in_flight: Arc<InFlight>,
+
+    /// The tasks currently running the app for each client.
+    tasks: Arc<Mutex<FxHashMap<Token, Vec<PyObject>>>>,
 }
 
 impl CallbackHandler {
     /// Creates a new instance of this struct wrapping the PyObject in a
     /// arc to make for cheap clones.
-    pub fn new(cb: PyObject) -> Self {
-        Self { cb: Arc::new(cb) }
+    pub fn new(
+        cb: PyObject,
+        event_loop: Option<PyObject>,
+        max_in_flight: Option<usize>,
+    ) -> Self {
+        let in_flight = InFlight {
+            count: AtomicUsize::new(0),
+            max: max_in_flight,
+            waiting: Mutex::new(Vec::new()),
+        };
+
+        Self {
+            cb: Arc::new(cb),
+            event_loop: event_loop.map(Arc::new),
+            in_flight: Arc::new(in_flight),
+            tasks: Arc::default(),
+        }
+    }
+
+    /// A handler sharing the app and the in-flight limit but tracking
+    /// its own tasks, each worker has its own set of client tokens.
+    pub fn for_worker(&self) -> Self {
+        Self {
+            tasks: Arc::default(),
+            ..self.clone()
+        }
     }
 
-    /// Invokes the callback by acquiring the gil internally.
+    /// Takes a permit to dispatch a request if the in-flight limit has
+    /// not been reached.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        self.in_flight.try_acquire()
+    }
+
+    /// Takes a permit to dispatch a request, if the in-flight limit has
+    /// been reached the client is woken with a `resume_reading` once a
+    /// permit is given back.
+    pub fn acquire_or_wait(
+        &self,
+        token: Token,
+        event_loop: &EventLoopHandle,
+    ) -> Option<Permit> {
+        // The lock is held while checking so a permit can not be given
+        // back between the check and the client starting to wait.
+        let mut waiting = self.in_flight.waiting.lock().unwrap();
+
+        let permit = self.in_flight.try_acquire();
+        if permit.is_none() {
+            waiting.push((token, event_loop.clone()));
+        }
+
+        permit
+    }
+
+    /// Invokes the callback by acquiring the gil internally, this is
+    /// used for the scopes that are not tied to a client.
     pub fn invoke(&self, args: impl IntoPy<Py<PyTuple>>) -> PyResult<()> {
         Python::with_gil(|py| -> PyResult<()> {
-           let _ = self.cb.call1(py, args)?;
-            Ok(())
+            let args = args.into_py(py);
+
+            let event_loop = match self.event_loop.as_ref() {
+                Some(event_loop) => event_loop.clone(),
+                None => return self.call_directly(py, args.as_ref(py)),
+            };
+
+            let task = AppTask {
+                cb: self.cb.clone(),
+                event_loop,
+                args: Some(args),
+                request: None,
+            };
+
+            task.schedule(py)
+        })
+    }
+
+    /// Hands a request to the app, any exception raised by the app is
+    /// logged and the protocol is told once the app has exited so it
+    /// can finish the response.
+    pub fn dispatch(
+        &self,
+        token: Token,
+        permit: Permit,
+        args: impl IntoPy<Py<PyTuple>>,
+        exit: ExitHandle,
+    ) {
+        Python::with_gil(|py| {
+            let args = args.into_py(py);
+
+            let event_loop = match self.event_loop.as_ref() {
+                Some(event_loop) => event_loop.clone(),
+                None => {
+                    // The app is expected to hand off anything it does
+                    // not finish straight away.
+                    if let Err(e) = self.call_directly(py, args.as_ref(py)) {
+                        log_app_error(py, token, &e);
+                        exit.exited(true);
+                    }
+
+                    drop(permit);
+                    return
+                },
+            };
+
+            let task = AppTask {
+                cb: self.cb.clone(),
+                event_loop,
+                args: Some(args),
+                request: Some(RequestTask {
+                    token,
+                    permit,
+                    exit: exit.clone(),
+                    tasks: self.tasks.clone(),
+                }),
+            };
+
+            // The task and its permit are dropped if it is not scheduled.
+            if let Err(e) = task.schedule(py) {
+                log_app_error(py, token, &e);
+                exit.exited(true);
+            }
         })
     }
-}
\ No newline at end of file
+
+    /// Calls the app on the server thread when there is no event loop,
+    /// a awaitable returned by the app is an error as it would never
+    /// be run.
+    fn call_directly(&self, py: Python, args: &PyTuple) -> PyResult<()> {
+        let returned = self.cb.call1(py, args)?;
+
+        let inspect = py.import("inspect")?;
+        if inspect.call_method1("isawaitable", (returned.clone_ref(py),))?.is_true()? {
+            // Closing it stops Python warning it was never awaited.
+            let _ = returned.call_method0(py, "close");
+            return Err(PyRuntimeError::new_err(NO_LOOP_ERROR))
+        }
+
+        Ok(())
+    }
+
+    /// Cancels any tasks still running the app for the client, this is
+    /// used when the server closes the connection itself.
+    pub fn cancel(&self, token: Token) {
+        let tasks = match self.tasks.lock().unwrap().remove(&token) {
+            Some(tasks) => tasks,
+            None => return,
+        };
+
+        let event_loop = match self.event_loop.as_ref() {
+            Some(event_loop) => event_loop,
+            None => return,
+        };
+
+        Python::with_gil(|py| {
+            for task in tasks {
+                let cancelled = task.getattr(py, "cancel")
+                    .and_then(|cancel| event_loop.call_method1(
+                        py,
+                        "call_soon_threadsafe",
+                        (cancel,),
+                    ));
+
+                if let Err(e) = cancelled {
+                    logging::log_exception(py, "Failed cancelling app task", &e, None);
+                }
+            }
+        });
+    }
+}
+
+
+/// The request a task is handling.
+struct RequestTask {
+    /// The token of the client that sent the request.
+    token: Token,
+
+    /// The in-flight permit given back once the app exits.
+    permit: Permit,
+
+    /// Tells the protocol the app has exited.
+    exit: ExitHandle,
+
+    /// The tasks of every client the task is tracked in.
+    tasks: Arc<Mutex<FxHashMap<Token, Vec<PyObject>>>>,
+}
+
+
+/// Runs the app as a task on the asyncio event loop, this is scheduled
+/// from the server thread and started on the event loop's thread.
+#[pyclass]
+pub struct AppTask {
+    /// The python callback itself.
+    cb: Arc<PyObject>,
+
+    /// The asyncio event loop the task is run on.
+    event_loop: Arc<PyObject>,
+
+    /// The arguments the app is called with, these are taken once
+    /// the task has started.
+    args: Option<Py<PyTuple>>,
+
+    /// The request being handled or `None` for scopes that are not
+    /// tied to a client.
+    request: Option<RequestTask>,
+}
+
+impl AppTask {
+    /// Schedules the task to be started on the event loop.
+    fn schedule(self, py: Python) -> PyResult<()> {
+        let event_loop = self.event_loop.clone();
+        let start = Py::new(py, self)?.getattr(py, "start")?;
+        event_loop.call_method1(py, "call_soon_threadsafe", (start,))?;
+
+        Ok(())
+    }
+
+    /// Called once the app has exited, this gives back the permit and
+    /// tells the protocol so it can finish the response.
+    fn finish(&mut self, py: Python, error: Option<PyErr>) {
+        let request = match self.request.take() {
+            Some(request) => request,
+            None => return,
+        };
+
+        if let Some(e) = error.as_ref() {
+            log_app_error(py, request.token, e);
+        }
+
+        request.exit.exited(error.is_some());
+        drop(request.permit);
+    }
+}
+
+#[pymethods]
+impl AppTask {
+    /// Calls the app and wraps the coroutine it returns in a task, this
+    /// is invoked on the event loop's thread.
+    fn start(mut slf: PyRefMut<Self>, py: Python) -> PyResult<()> {
+        let args = match slf.args.take() {
+            Some(args) => args,
+            None => return Ok(()),
+        };
+
+        let task = slf.cb.call1(py, args.as_ref(py))
+            .and_then(|coro| {
+                slf.event_loop.call_method1(py, "create_task", (coro,))
+            });
+
+        let task = match task {
+            Ok(task) => task,
+            Err(e) => {
+                slf.finish(py, Some(e));
+                return Ok(())
+            },
+        };
+
+        if let Some(request) = slf.request.as_ref() {
+            request.tasks.lock()
+                .unwrap()
+                .entry(request.token)
+                .or_default()
+                .push(task.clone_ref(py));
+        }
+
+        let this: Py<Self> = slf.into();
+        let done = this.getattr(py, "done")?;
+        task.call_method1(py, "add_done_callback", (done,))?;
+
+        Ok(())
+    }
+
+    /// Invoked by asyncio once the task has finished.
+    fn done(&mut self, py: Python, task: PyObject) -> PyResult<()> {
+        if let Some(request) = self.request.as_ref() {
+            let mut tasks = request.tasks.lock().unwrap();
+            if let Some(running) = tasks.get_mut(&request.token) {
+                running.retain(|other| other.as_ptr() != task.as_ptr());
+
+                if running.is_empty() {
+                    tasks.remove(&request.token);
+                }
+            }
+        }
+
+        // The exception is always retrieved so asyncio does not report
+        // it a second time.
+        let error = if task.call_method0(py, "cancelled")?.is_true(py)? {
+            None
+        } else {
+            let exception = task.call_method0(py, "exception")?;
+            if exception.is_none(py) {
+                None
+            } else {
+                Some(PyErr::from_instance(exception.as_ref(py)))
+            }
+        };
+
+        self.finish(py, error);
+
+        Ok(())
+    }
+}
+
+
+/// Checks the app can be called directly on the server thread, this is
+/// used when no event loop is given as a coroutine function would only
+/// return a coroutine that is never run.
+pub fn check_sync_app(py: Python, cb: &PyObject) -> PyResult<()> {
+    let inspect = py.import("inspect")?;
+    let cb = cb.as_ref(py);
+
+    // Apps are often instances with a async `__call__`.
+    let mut is_async = inspect.call_method1("iscoroutinefunction", (cb,))?
+        .is_true()?;
+    if let Ok(call) = cb.getattr("__call__") {
+        is_async |= inspect.call_method1("iscoroutinefunction", (call,))?
+            .is_true()?;
+    }
+
+    if is_async {
+        return Err(PyValueError::new_err(NO_LOOP_ERROR))
+    }
+
+    Ok(())
+}
+
+/// Logs a exception raised by the app while handling a request from
+/// the client with the given token.
+fn log_app_error(py: Python, token: Token, error: &PyErr) {
+    logging::log_exception(py, "Exception in ASGI application", error, Some(token));
+}
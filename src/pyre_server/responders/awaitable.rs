@@ -0,0 +1,164 @@
+use pyo3::prelude::*;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::wrap_pyfunction;
+use pyo3::AsPyPointer;
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use crate::pyre_server::logging;
+
+
+/// A awaitable that has already been completed, awaiting this
+/// immediately returns the set value.
+#[pyclass]
+pub struct Ready {
+    value: Option<PyObject>,
+}
+
+impl Ready {
+    /// Creates a completed awaitable resolving to the given value.
+    pub fn new(value: PyObject) -> Self {
+        Self { value: Some(value) }
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for Ready {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for Ready {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> IterNextOutput<PyObject, PyObject> {
+        let value = slf.value
+            .take()
+            .unwrap_or_else(|| slf.py().None());
+
+        IterNextOutput::Return(value)
+    }
+}
+
+
+/// A asyncio future created on the running event loop, unlike the
+/// future itself this can be resolved from any thread.
+pub struct LoopFuture {
+    /// The event loop the future belongs to.
+    event_loop: PyObject,
+
+    /// The pending future.
+    future: PyObject,
+}
+
+impl LoopFuture {
+    /// Creates a pending future on the currently running event loop.
+    ///
+    /// Raises a `RuntimeError` if there is no running event loop.
+    pub fn new(py: Python) -> PyResult<Self> {
+        let event_loop = py.import("asyncio")?.call0("get_running_loop")?;
+        let future = event_loop.call_method0("create_future")?;
+
+        Ok(Self {
+            event_loop: event_loop.into(),
+            future: future.into(),
+        })
+    }
+
+    /// The future to hand to python to be awaited.
+    pub fn future(&self, py: Python) -> PyObject {
+        self.future.clone_ref(py)
+    }
+
+    /// Resolves the future with the given value, this is scheduled on
+    /// the future's own event loop.
+    pub fn set_result(&self, py: Python, value: PyObject) -> PyResult<()> {
+        let resolve = wrap_pyfunction!(resolve)(py)?;
+        self.event_loop.call_method1(
+            py,
+            "call_soon_threadsafe",
+            (resolve, self.future.clone_ref(py), value),
+        )?;
+
+        Ok(())
+    }
+}
+
+
+/// Wakes the tasks waiting on the server from the server's own thread,
+/// this is shared between the protocol and the handles given to the app.
+///
+/// Every waiting task is woken at once as more than one task can await
+/// the same handle, each of them checks again and waits again if it is
+/// still not ready.
+#[derive(Clone, Default)]
+pub struct Waker {
+    /// The futures the tasks are waiting on.
+    waiters: Arc<Mutex<Vec<LoopFuture>>>,
+}
+
+impl Waker {
+    /// Creates a future for the current task to wait on until it is
+    /// woken, this is yielded by the awaitable so asyncio suspends the
+    /// task until the future is resolved.
+    ///
+    /// Returns `None` if there is no running asyncio event loop.
+    pub fn register(&self, py: Python) -> PyResult<Option<PyObject>> {
+        let waiter = match LoopFuture::new(py) {
+            Ok(waiter) => waiter,
+            Err(e) if e.is_instance::<PyRuntimeError>(py) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let future = waiter.future(py);
+        future.as_ref(py).setattr("_asyncio_future_blocking", true)?;
+        self.waiters.lock().unwrap().push(waiter);
+
+        Ok(Some(future))
+    }
+
+    /// Forgets the given registered future, this is used when the
+    /// awaitable becomes ready before yielding it.
+    pub fn clear(&self, future: &PyObject) {
+        self.waiters.lock().unwrap()
+            .retain(|waiter| waiter.future.as_ptr() != future.as_ptr());
+    }
+
+    /// Wakes every waiting task, the GIL is only acquired if a task is
+    /// waiting.
+    pub fn wake(&self) {
+        // The lock is released before acquiring the GIL as the app's
+        // thread holds the GIL while registering.
+        let waiters = mem::take(&mut *self.waiters.lock().unwrap());
+        if waiters.is_empty() {
+            return
+        }
+
+        Python::with_gil(|py| {
+            for waiter in waiters {
+                if let Err(e) = waiter.set_result(py, py.None()) {
+                    logging::log_exception(py, "Failed waking waiting task", &e, None);
+                }
+            }
+        });
+    }
+}
+
+
+/// Resolves the future unless it has already been cancelled.
+#[pyfunction]
+fn resolve(future: &PyAny, value: PyObject) -> PyResult<()> {
+    if !future.call_method0("done")?.is_true()? {
+        future.call_method1("set_result", (value,))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use pyo3::prelude::*;
+use pyo3::{ffi, AsPyPointer, PyBufferProtocol, PyNativeType};
+use pyo3::buffer::PyBuffer;
+use pyo3::types::PyBytes;
+use pyo3::exceptions::PyValueError;
+
+use bytes::Bytes;
+
+use std::slice;
+use std::os::raw::{c_int, c_void};
+
+use crate::pyre_server::socket_io::FileRegion;
+
+
+/// The size past which a body is kept by reference rather than copied,
+/// keeping a reference means acquiring the GIL again to release it once
+/// written which costs more than copying a small body.
+const MIN_ZERO_COPY: usize = 16 * 1024;
+
+
+/// A exported Python buffer, this keeps the object alive and its buffer
+/// exported until the bytes have been written.
+struct PyBufferOwner(PyBuffer<u8>);
+
+impl AsRef<[u8]> for PyBufferOwner {
+    fn as_ref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                self.0.buf_ptr() as *const u8,
+                self.0.len_bytes(),
+            )
+        }
+    }
+}
+
+
+/// Extracts a body given by the app from any object supporting the
+/// buffer protocol e.g. `bytes`, `bytearray` or `memoryview`.
+///
+/// Large contiguous read-only buffers e.g. `bytes` are referenced rather
+/// than copied, writable buffers are always copied as the app is free to
+/// change or resize them once the event has been sent.
+pub fn extract_body(obj: &PyAny) -> PyResult<Bytes> {
+    let buffer = PyBuffer::<u8>::get(obj)?;
+
+    if !buffer.is_c_contiguous() || !buffer.readonly() {
+        return Ok(Bytes::from(buffer.to_vec(obj.py())?))
+    }
+
+    let owner = PyBufferOwner(buffer);
+    if owner.as_ref().len() < MIN_ZERO_COPY {
+        return Ok(Bytes::copy_from_slice(owner.as_ref()))
+    }
+
+    Ok(Bytes::from_owner(owner))
+}
+
+/// Extracts the region of a file given by the app in a
+/// `http.response.zerocopysend` event.
+///
+/// The file's descriptor is duplicated so it stays open until the region
+/// has been written even if the app closes the file straight away. The
+/// region starts at the file's current position unless a `offset` is
+/// given and runs to the end of the file unless a `count` is given.
+#[cfg(unix)]
+pub fn extract_file(
+    file: &PyAny,
+    offset: Option<i64>,
+    count: Option<i64>,
+) -> PyResult<FileRegion> {
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::Seek;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::{FromRawFd, RawFd};
+
+    let fd: RawFd = file.call_method0("fileno")?.extract()?;
+    if fd < 0 {
+        return Err(PyValueError::new_err("invalid file descriptor"))
+    }
+
+    // The app's descriptor is only borrowed so must not be closed.
+    let borrowed = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut file = borrowed.try_clone()?;
+
+    let offset = match offset {
+        Some(offset) => u64::try_from(offset).map_err(|_| {
+            PyValueError::new_err("offset must not be negative")
+        })?,
+        None => file.stream_position()?,
+    };
+
+    let count = match count {
+        Some(count) => Some(u64::try_from(count).map_err(|_| {
+            PyValueError::new_err("count must not be negative")
+        })?),
+        None => None,
+    };
+
+    let available = file.metadata()?.len().saturating_sub(offset);
+    let len = count.map_or(available, |count| count.min(available));
+    let len = usize::try_from(len).map_err(|_| {
+        PyValueError::new_err("file region is too large to send")
+    })?;
+
+    Ok(FileRegion::new(file, offset, len))
+}
+
+/// Files can only be sent on unix platforms.
+#[cfg(not(unix))]
+pub fn extract_file(
+    _file: &PyAny,
+    _offset: Option<i64>,
+    _count: Option<i64>,
+) -> PyResult<FileRegion> {
+    Err(PyValueError::new_err(
+        "http.response.zerocopysend is not supported on this platform"
+    ))
+}
+
+/// Converts a request body into the object given to the app, either a
+/// `memoryview` over the server's own buffer or a copy as `bytes`.
+pub fn body_to_py(py: Python, body: Bytes, as_view: bool) -> PyResult<PyObject> {
+    if !as_view {
+        return Ok(PyBytes::new(py, &body).into())
+    }
+
+    let view = Py::new(py, BodyView { body })?;
+    unsafe {
+        PyObject::from_owned_ptr_or_err(
+            py,
+            ffi::PyMemoryView_FromObject(view.as_ptr()),
+        )
+    }
+}
+
+
+/// Exposes a frozen request body through the buffer protocol so the app
+/// can be given a `memoryview` of it without copying.
+#[pyclass]
+pub struct BodyView {
+    body: Bytes,
+}
+
+#[pyproto]
+impl PyBufferProtocol for BodyView {
+    fn bf_getbuffer(
+        slf: PyRefMut<Self>,
+        view: *mut ffi::Py_buffer,
+        flags: c_int,
+    ) -> PyResult<()> {
+        let ret = unsafe {
+            ffi::PyBuffer_FillInfo(
+                view,
+                slf.as_ptr(),
+                slf.body.as_ptr() as *mut c_void,
+                slf.body.len() as ffi::Py_ssize_t,
+                1,
+                flags,
+            )
+        };
+
+        if ret == -1 {
+            return Err(PyErr::fetch(slf.py()))
+        }
+
+        Ok(())
+    }
+
+    fn bf_releasebuffer(
+        _slf: PyRefMut<Self>,
+        _view: *mut ffi::Py_buffer,
+    ) -> PyResult<()> {
+        Ok(())
+    }
+}
@@ -1,5 +1,60 @@
 pub mod sender;
 pub mod receiver;
+pub mod awaitable;
+pub mod body;
+
+use bytes::Bytes;
+
+use crate::pyre_server::socket_io::Chunk;
 
 /// The payload that gets sent to the receiver half of the channel.
-pub type Payload = (bool, Vec<u8>);
+pub type Payload = (bool, Bytes);
+
+/// The more_body flag and body chunk sent by the app.
+pub type ResponseBody = (bool, Chunk);
+
+/// The response headers sent by the app in `http.response.start`.
+pub type ResponseHeaders = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A complete WebSocket message sent to or from the app.
+pub enum Message {
+    /// A text message, this is always valid UTF-8.
+    Text(String),
+
+    /// A binary message.
+    Binary(Vec<u8>),
+}
+
+/// The ASGI events that get sent to the receiver half of the channel.
+pub enum ReceiverPayload {
+    /// The `http.request` event containing the more_body flag
+    /// and the body chunk itself.
+    Body(Payload),
+
+    /// The `websocket.connect` event sent before the app accepts.
+    Connect,
+
+    /// The `websocket.receive` event containing a complete message.
+    Message(Message),
+}
+
+/// The ASGI events that get sent to the sender half of the channel.
+pub enum SenderPayload {
+    /// The `http.response.start` event containing the status code
+    /// and the response headers.
+    Start(u16, ResponseHeaders),
+
+    /// The `http.response.body` or `http.response.zerocopysend` event
+    /// containing the more_body flag and the body chunk itself.
+    Body(ResponseBody),
+
+    /// The `websocket.accept` event containing the selected subprotocol
+    /// and any extra headers for the handshake response.
+    Accept(Option<String>, ResponseHeaders),
+
+    /// The `websocket.send` event containing the message.
+    Send(Message),
+
+    /// The `websocket.close` event containing the close code and reason.
+    Close(u16, String),
+}
@@ -1,81 +1,409 @@
 use pyo3::prelude::*;
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::types::{PyDict, PyBytes};
 
 use crossbeam::channel::{Sender, Receiver, bounded, TrySendError};
-use bytes::Bytes;
 use mio::Token;
 
-use crate::pyre_server::responders::Payload;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+
+use crate::pyre_server::responders::{ReceiverPayload, Message};
+use crate::pyre_server::responders::awaitable::Waker;
+use crate::pyre_server::responders::body::body_to_py;
 use crate::pyre_server::transport::EventLoopHandle;
+use crate::pyre_server::config::ServerConfig;
+
+
+/// The close code given to the app when the connection is lost without
+/// a close frame.
+const ABNORMAL_CLOSURE: u16 = 1006;
+
+
+/// The amount of data sent to the app that it has not received yet, the
+/// protocol pauses reading once too much is waiting and reading is
+/// resumed once the app has drained it below the low water mark.
+#[derive(Clone)]
+struct Backlog {
+    token: Token,
+    event_loop: EventLoopHandle,
+    queued: Arc<AtomicUsize>,
+    paused: Arc<AtomicBool>,
+    low_water: usize,
+}
+
+impl Backlog {
+    fn new(token: Token, event_loop: EventLoopHandle, low_water: usize) -> Self {
+        Self {
+            token,
+            event_loop,
+            queued: Arc::new(AtomicUsize::new(0)),
+            paused: Arc::new(AtomicBool::new(false)),
+            low_water,
+        }
+    }
+
+    /// The amount of data waiting on the app.
+    fn len(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    /// Marks the data as waiting on the app.
+    fn added(&self, amount: usize) {
+        self.queued.fetch_add(amount, Ordering::AcqRel);
+    }
+
+    /// Marks the data as no longer waiting on the app.
+    fn removed(&self, amount: usize) {
+        self.queued.fetch_sub(amount, Ordering::AcqRel);
+    }
+
+    /// Resumes reading if it was paused for the backlog and the app has
+    /// drained the data waiting on it below the low water mark.
+    fn resume_if_drained(&self) {
+        if self.len() > self.low_water {
+            return
+        }
+
+        // Only the first drain after a pause resumes reading.
+        if self.paused.swap(false, Ordering::AcqRel) {
+            self.event_loop.resume_reading(self.token);
+        }
+    }
+}
 
 
 /// The callable class that handling communication back to the server protocol.
 #[pyclass]
 pub struct DataReceiver {
-    token: Token,
-    event_loop: EventLoopHandle,
-    rx: Receiver<Payload>,
+    rx: Receiver<ReceiverPayload>,
+    disconnected: Arc<AtomicBool>,
+    close_code: Option<Arc<AtomicU16>>,
+    waker: Waker,
+    backlog: Backlog,
+    body_views: bool,
 }
 
 impl DataReceiver {
     /// Create a new handler with the given sender.
-    pub fn new(
-        token: Token,
-        event_loop: EventLoopHandle,
-        rx: Receiver<Payload>,
+    fn new(
+        rx: Receiver<ReceiverPayload>,
+        disconnected: Arc<AtomicBool>,
+        close_code: Option<Arc<AtomicU16>>,
+        waker: Waker,
+        backlog: Backlog,
+        body_views: bool,
     ) -> Self {
-        Self { rx, event_loop, token }
+        Self {
+            rx,
+            disconnected,
+            close_code,
+            waker,
+            backlog,
+            body_views,
+        }
     }
 }
 
 #[pymethods]
 impl DataReceiver {
-    /// Invoked by python passing more_body which represents if there
-    /// is any more body to expect or not, and the body itself.
+    /// Invoked by python to receive the next ASGI event, this returns a
+    /// awaitable that resolves to either a `http.request` event or a
+    /// `http.disconnect` event, or the `websocket.*` equivalents.
     #[call]
-    fn __call__(&self) -> PyResult<()> {
-        self.event_loop.resume_reading(self.token);
+    fn __call__(&self) -> PyResult<ReceiveAwaitable> {
+        Ok(ReceiveAwaitable {
+            rx: self.rx.clone(),
+            disconnected: self.disconnected.clone(),
+            close_code: self.close_code.clone(),
+            waker: self.waker.clone(),
+            backlog: self.backlog.clone(),
+            body_views: self.body_views,
+        })
+    }
+}
 
-        Ok(())
+
+/// The awaitable returned by `DataReceiver`, this suspends the task on
+/// a asyncio future until either a event is available or the connection
+/// is disconnected.
+#[pyclass]
+pub struct ReceiveAwaitable {
+    rx: Receiver<ReceiverPayload>,
+    disconnected: Arc<AtomicBool>,
+    close_code: Option<Arc<AtomicU16>>,
+    waker: Waker,
+    backlog: Backlog,
+    body_views: bool,
+}
+
+impl ReceiveAwaitable {
+    /// Takes the next event if there is one, once the channel is empty
+    /// and the request disconnected this is the disconnect event.
+    fn poll<'a>(&self, py: Python<'a>) -> PyResult<Option<&'a PyDict>> {
+        if let Ok(payload) = self.rx.try_recv() {
+            self.backlog.removed(payload_len(&payload));
+            self.backlog.resume_if_drained();
+
+            return payload_to_event(py, payload, self.body_views).map(Some)
+        }
+
+        if !self.disconnected.load(Ordering::Relaxed) {
+            return Ok(None)
+        }
+
+        let event = PyDict::new(py);
+        match self.close_code.as_ref() {
+            Some(code) => {
+                event.set_item("type", "websocket.disconnect")?;
+                event.set_item("code", code.load(Ordering::Relaxed))?;
+            },
+            None => event.set_item("type", "http.disconnect")?,
+        };
+
+        Ok(Some(event))
+    }
+}
+
+#[pyproto]
+impl PyAsyncProtocol for ReceiveAwaitable {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
     }
 }
 
+#[pyproto]
+impl PyIterProtocol for ReceiveAwaitable {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(
+        slf: PyRef<Self>,
+    ) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        let py = slf.py();
+
+        if let Some(event) = slf.poll(py)? {
+            return Ok(IterNextOutput::Return(event.into()))
+        }
+
+        // Without a running event loop a bare yield hands control back
+        // to whatever is driving the coroutine.
+        let future = match slf.waker.register(py)? {
+            Some(future) => future,
+            None => return Ok(IterNextOutput::Yield(py.None())),
+        };
+
+        // A event sent before the future was registered would never
+        // wake the task.
+        if let Some(event) = slf.poll(py)? {
+            slf.waker.clear(&future);
+            return Ok(IterNextOutput::Return(event.into()))
+        }
+
+        Ok(IterNextOutput::Yield(future))
+    }
+}
+
+/// The amount of data the payload holds.
+fn payload_len(payload: &ReceiverPayload) -> usize {
+    match payload {
+        ReceiverPayload::Body((_, body)) => body.len(),
+        ReceiverPayload::Connect => 0,
+        ReceiverPayload::Message(Message::Text(text)) => text.len(),
+        ReceiverPayload::Message(Message::Binary(data)) => data.len(),
+    }
+}
+
+/// Converts the payload sent by the protocol into a ASGI receive event.
+fn payload_to_event(
+    py: Python<'_>,
+    payload: ReceiverPayload,
+    body_views: bool,
+) -> PyResult<&PyDict> {
+    let event = PyDict::new(py);
+
+    match payload {
+        ReceiverPayload::Body((more_body, body)) => {
+            event.set_item("type", "http.request")?;
+            event.set_item("body", body_to_py(py, body, body_views)?)?;
+            event.set_item("more_body", more_body)?;
+        },
+        ReceiverPayload::Connect => {
+            event.set_item("type", "websocket.connect")?;
+        },
+        ReceiverPayload::Message(Message::Text(text)) => {
+            event.set_item("type", "websocket.receive")?;
+            event.set_item("text", text)?;
+        },
+        ReceiverPayload::Message(Message::Binary(data)) => {
+            event.set_item("type", "websocket.receive")?;
+            event.set_item("bytes", PyBytes::new(py, &data))?;
+        },
+    }
+
+    Ok(event)
+}
+
 
 pub struct ReceiverHandler {
     /// The sender half for sending body chunks.
-    receiver_tx: Sender<Payload>,
+    receiver_tx: Sender<ReceiverPayload>,
 
     /// The receiver half for sending body chunks.
-    receiver_rx: Receiver<Payload>,
+    receiver_rx: Receiver<ReceiverPayload>,
 
-    token: Token,
+    /// Marks the request as disconnected once the response is complete
+    /// or the connection has been lost.
+    disconnected: Arc<AtomicBool>,
 
-    event_loop: EventLoopHandle,
+    /// The close code given to the app on disconnect, this is only set
+    /// for WebSocket connections.
+    close_code: Option<Arc<AtomicU16>>,
+
+    /// Wakes the app once a event is available.
+    waker: Waker,
+
+    /// The data sent to the app that it has not received yet.
+    backlog: Backlog,
+
+    /// If request bodies are given to the app as `memoryview`s rather
+    /// than copied into `bytes`.
+    body_views: bool,
 }
 
 impl ReceiverHandler {
     pub fn new(
         token: Token,
-        event_loop: EventLoopHandle
+        event_loop: EventLoopHandle,
+        config: &ServerConfig,
     ) -> Self {
         let (tx, rx) = bounded(10);
         Self {
             receiver_tx: tx,
             receiver_rx: rx,
-            token,
-            event_loop,
+            disconnected: Arc::new(AtomicBool::new(false)),
+            close_code: None,
+            waker: Waker::default(),
+            backlog: Backlog::new(token, event_loop, config.read_low_water),
+            body_views: config.body_memoryview,
         }
     }
 
-    pub fn make_handle(&self) -> DataReceiver{
+    /// Creates a new handler that produces `websocket.*` events rather
+    /// than `http.*` events.
+    pub fn websocket(
+        token: Token,
+        event_loop: EventLoopHandle,
+        config: &ServerConfig,
+    ) -> Self {
+        let mut handler = Self::new(token, event_loop, config);
+        handler.close_code = Some(Arc::new(AtomicU16::new(ABNORMAL_CLOSURE)));
+        handler.body_views = false;
+
+        handler
+    }
+
+    pub fn make_handle(&self) -> DataReceiver {
         DataReceiver::new(
-            self.token,
-            self.event_loop.clone(),
-            self.receiver_rx.clone()
+            self.receiver_rx.clone(),
+            self.disconnected.clone(),
+            self.close_code.clone(),
+            self.waker.clone(),
+            self.backlog.clone(),
+            self.body_views,
         )
     }
 
-    pub fn send(&self, data: Payload) -> Result<(), TrySendError<Payload>> {
-        self.receiver_tx.try_send(data)
+    pub fn send(
+        &self,
+        payload: ReceiverPayload,
+    ) -> Result<(), TrySendError<ReceiverPayload>> {
+        // Counted before sending so the app can never receive it first.
+        let len = payload_len(&payload);
+        self.backlog.added(len);
+
+        if let Err(e) = self.receiver_tx.try_send(payload) {
+            self.backlog.removed(len);
+            return Err(e)
+        }
+
+        self.waker.wake();
+
+        Ok(())
+    }
+
+    /// The amount of data sent to the app that it has not received yet.
+    pub fn queued(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// Sets whether reading has been paused until the app drains the
+    /// data waiting on it.
+    pub fn set_paused(&self, paused: bool) {
+        self.backlog.paused.store(paused, Ordering::Release);
     }
-}
\ No newline at end of file
+
+    /// Replaces the channel and disconnect flag ready for a new request,
+    /// any handles from the previous request keep the old ones.
+    pub fn reset(&mut self) {
+        // Anything still waiting on the previous request sees it has
+        // been disconnected.
+        self.waker.wake();
+        self.waker = Waker::default();
+
+        let (tx, rx) = bounded(10);
+        self.receiver_tx = tx;
+        self.receiver_rx = rx;
+        self.disconnected = Arc::new(AtomicBool::new(false));
+        self.backlog = Backlog::new(
+            self.backlog.token,
+            self.backlog.event_loop.clone(),
+            self.backlog.low_water,
+        );
+
+        if self.close_code.is_some() {
+            self.close_code = Some(Arc::new(AtomicU16::new(ABNORMAL_CLOSURE)));
+        }
+    }
+
+    /// If the channel is full and waiting on the app to consume
+    /// some of the body.
+    pub fn is_full(&self) -> bool {
+        self.receiver_tx.is_full()
+    }
+
+    /// Pauses reading while the channel is full, reading is resumed once
+    /// the app has received enough of the events waiting on it.
+    pub fn pause_while_full(&self) {
+        self.set_paused(true);
+        self.backlog.event_loop.pause_reading(self.backlog.token);
+
+        // The app may have emptied the channel before the pause was
+        // queued which would leave reading paused forever.
+        if !self.is_full() {
+            self.set_paused(false);
+            self.backlog.event_loop.resume_reading(self.backlog.token);
+        }
+    }
+
+    /// Sets whether or not the app should be told the request has
+    /// been disconnected once all body chunks are consumed.
+    pub fn set_disconnected(&self, disconnected: bool) {
+        self.disconnected.store(disconnected, Ordering::Relaxed);
+
+        if disconnected {
+            self.waker.wake();
+        }
+    }
+
+    /// Sets the close code given to the app once the WebSocket has been
+    /// disconnected, this does nothing for HTTP requests.
+    pub fn set_close_code(&self, code: u16) {
+        if let Some(close_code) = self.close_code.as_ref() {
+            close_code.store(code, Ordering::Relaxed);
+        }
+    }
+}
@@ -1,12 +1,28 @@
 use pyo3::prelude::*;
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::PyIterProtocol;
+use pyo3::class::pyasync::PyAsyncProtocol;
+use pyo3::class::iter::IterNextOutput;
+use pyo3::types::PyDict;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 
-use crossbeam::channel::{Sender, Receiver, bounded, TryRecvError};
+use crossbeam::channel::{
+    Sender,
+    Receiver,
+    bounded,
+    TryRecvError,
+    TrySendError,
+};
 
-use bytes::Bytes;
-
-use crate::pyre_server::responders::Payload;
+use crate::pyre_server::responders::{
+    SenderPayload,
+    ResponseHeaders,
+    Message,
+};
+use crate::pyre_server::responders::awaitable::{Ready, Waker};
+use crate::pyre_server::responders::body::{extract_body, extract_file};
 use crate::pyre_server::transport::EventLoopHandle;
+use crate::pyre_server::socket_io::Chunk;
+use bytes::Bytes;
 use mio::Token;
 
 
@@ -17,7 +33,12 @@ pub struct DataSender {
 
     event_loop: EventLoopHandle,
 
-    tx: Sender<Payload>,
+    tx: Sender<SenderPayload>,
+
+    waker: Waker,
+
+    /// If the app has sent `websocket.accept`.
+    accepted: bool,
 }
 
 impl DataSender {
@@ -25,35 +46,360 @@ impl DataSender {
     pub fn new(
         token: Token,
         event_loop: EventLoopHandle,
-        tx: Sender<Payload>,
+        tx: Sender<SenderPayload>,
+        waker: Waker,
     ) -> Self {
-        Self { tx, event_loop, token }
+        Self { tx, event_loop, token, waker, accepted: false }
     }
 }
 
 #[pymethods]
 impl DataSender {
-    /// Invoked by python passing more_body which represents if there
-    /// is any more body to expect or not, and the body itself.
+    /// Invoked by python passing a ASGI `http.response.*` or
+    /// `websocket.*` event, this returns a awaitable that completes
+    /// once the event has been handed to the server.
     #[call]
-    fn __call__(&self, more_body: bool, body: Vec<u8>) -> PyResult<()> {
-        self.event_loop.resume_writing(self.token);
+    fn __call__(&mut self, py: Python, event: &PyDict) -> PyResult<PyObject> {
+        let payload = event_to_payload(event)?;
 
-        if let Err(e) = self.tx.send((more_body, body)) {
-            return Err(PyRuntimeError::new_err(format!("{:?}", e)))
+        match payload {
+            SenderPayload::Accept(..) => self.accepted = true,
+            SenderPayload::Send(..) if !self.accepted => {
+                return Err(PyRuntimeError::new_err(
+                    "websocket.send event sent before websocket.accept"
+                ))
+            },
+            _ => {},
         }
 
-        Ok(())
+        // The event is handed over straight away if there is space so
+        // the events keep the order they were sent in.
+        let payload = match try_send(&self.tx, payload)? {
+            Some(payload) => payload,
+            None => {
+                self.event_loop.resume_writing(self.token);
+                return Ok(Ready::new(py.None()).into_py(py))
+            },
+        };
+
+        let awaitable = SendAwaitable {
+            token: self.token,
+            event_loop: self.event_loop.clone(),
+            tx: self.tx.clone(),
+            waker: self.waker.clone(),
+            payload: Some(payload),
+        };
+
+        Ok(awaitable.into_py(py))
+    }
+}
+
+
+/// The awaitable returned by `DataSender` when the channel is full, this
+/// suspends the task on a asyncio future until the protocol has written
+/// some of the waiting events.
+#[pyclass]
+pub struct SendAwaitable {
+    token: Token,
+    event_loop: EventLoopHandle,
+    tx: Sender<SenderPayload>,
+    waker: Waker,
+
+    /// The event waiting on space in the channel.
+    payload: Option<SenderPayload>,
+}
+
+#[pyproto]
+impl PyAsyncProtocol for SendAwaitable {
+    fn __await__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for SendAwaitable {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(
+        mut slf: PyRefMut<Self>,
+    ) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        Python::with_gil(|py| slf.poll_send(py))
     }
 }
 
+impl SendAwaitable {
+    /// Tries to hand the event to the protocol, registering the task to
+    /// be woken if the channel is still full.
+    fn poll_send(
+        &mut self,
+        py: Python,
+    ) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        let payload = match self.payload.take() {
+            Some(payload) => payload,
+            None => return Ok(IterNextOutput::Return(py.None())),
+        };
+
+        let payload = match try_send(&self.tx, payload)? {
+            Some(payload) => payload,
+            None => {
+                self.event_loop.resume_writing(self.token);
+                return Ok(IterNextOutput::Return(py.None()))
+            },
+        };
+
+        // Without a running event loop a bare yield hands control back
+        // to whatever is driving the coroutine.
+        let future = match self.waker.register(py)? {
+            Some(future) => future,
+            None => {
+                self.payload = Some(payload);
+                return Ok(IterNextOutput::Yield(py.None()))
+            },
+        };
+
+        // The protocol may have made space before the future was
+        // registered in which case it would never wake the task.
+        match try_send(&self.tx, payload)? {
+            Some(payload) => {
+                self.payload = Some(payload);
+                Ok(IterNextOutput::Yield(future))
+            },
+            None => {
+                self.waker.clear(&future);
+                self.event_loop.resume_writing(self.token);
+                Ok(IterNextOutput::Return(py.None()))
+            },
+        }
+    }
+}
+
+/// Hands the payload to the protocol if there is space in the channel.
+///
+/// Returns the payload back if the channel is full.
+fn try_send(
+    tx: &Sender<SenderPayload>,
+    payload: SenderPayload,
+) -> PyResult<Option<SenderPayload>> {
+    match tx.try_send(payload) {
+        Ok(()) => Ok(None),
+        Err(TrySendError::Full(payload)) => Ok(Some(payload)),
+        Err(TrySendError::Disconnected(_)) => Err(PyRuntimeError::new_err(
+            "the connection has been closed"
+        )),
+    }
+}
+
+/// Converts a ASGI send event dictionary into the payload that gets
+/// sent to the protocol.
+fn event_to_payload(event: &PyDict) -> PyResult<SenderPayload> {
+    let kind: &str = match event.get_item("type") {
+        Some(kind) => kind.extract()?,
+        None => return Err(PyRuntimeError::new_err(
+            "ASGI event is missing the 'type' key"
+        )),
+    };
+
+    match kind {
+        "http.response.start" => {
+            let status: u16 = match event.get_item("status") {
+                Some(status) => status.extract()?,
+                None => return Err(PyRuntimeError::new_err(
+                    "http.response.start event is missing the 'status' key"
+                )),
+            };
+
+            // Anything else would not make a valid status line.
+            if !(100..=999).contains(&status) {
+                return Err(PyValueError::new_err(format!(
+                    "invalid response status {}", status
+                )))
+            }
+
+            let headers = match event.get_item("headers") {
+                Some(headers) => extract_headers(headers)?,
+                None => Vec::new(),
+            };
+
+            Ok(SenderPayload::Start(status, headers))
+        },
+        "http.response.body" => {
+            let body = match event.get_item("body") {
+                Some(body) => extract_body(body)?,
+                None => Bytes::new(),
+            };
+
+            let more_body = match event.get_item("more_body") {
+                Some(more_body) => more_body.extract::<bool>()?,
+                None => false,
+            };
+
+            Ok(SenderPayload::Body((more_body, body.into())))
+        },
+        "http.response.zerocopysend" => {
+            let file = match event.get_item("file") {
+                Some(file) => file,
+                None => return Err(PyRuntimeError::new_err(
+                    "http.response.zerocopysend event is missing the 'file' key"
+                )),
+            };
+
+            let offset = match event.get_item("offset") {
+                Some(offset) => offset.extract::<Option<i64>>()?,
+                None => None,
+            };
+
+            let count = match event.get_item("count") {
+                Some(count) => count.extract::<Option<i64>>()?,
+                None => None,
+            };
+
+            let more_body = match event.get_item("more_body") {
+                Some(more_body) => more_body.extract::<bool>()?,
+                None => false,
+            };
+
+            let region = extract_file(file, offset, count)?;
+
+            Ok(SenderPayload::Body((more_body, Chunk::File(region))))
+        },
+        "websocket.accept" => {
+            let subprotocol: Option<String> = match event.get_item("subprotocol") {
+                Some(subprotocol) => subprotocol.extract()?,
+                None => None,
+            };
+
+            if let Some(subprotocol) = subprotocol.as_ref() {
+                check_header_value(subprotocol.as_bytes())?;
+            }
+
+            let headers = match event.get_item("headers") {
+                Some(headers) => extract_headers(headers)?,
+                None => Vec::new(),
+            };
+
+            Ok(SenderPayload::Accept(subprotocol, headers))
+        },
+        "websocket.send" => {
+            if let Some(data) = event.get_item("bytes") {
+                if !data.is_none() {
+                    let data = data.extract::<&[u8]>()?.to_vec();
+                    return Ok(SenderPayload::Send(Message::Binary(data)))
+                }
+            }
+
+            if let Some(text) = event.get_item("text") {
+                if !text.is_none() {
+                    let text = text.extract::<String>()?;
+                    return Ok(SenderPayload::Send(Message::Text(text)))
+                }
+            }
+
+            Err(PyRuntimeError::new_err(
+                "websocket.send event must contain either 'bytes' or 'text'"
+            ))
+        },
+        "websocket.close" => {
+            let code = match event.get_item("code") {
+                Some(code) => code.extract::<u16>()?,
+                None => 1000,
+            };
+
+            let reason = match event.get_item("reason") {
+                Some(reason) => reason.extract::<Option<String>>()?,
+                None => None,
+            };
+
+            Ok(SenderPayload::Close(code, reason.unwrap_or_default()))
+        },
+        other => Err(PyRuntimeError::new_err(format!(
+            "Unexpected ASGI event type {:?}", other
+        ))),
+    }
+}
+
+/// Tells the protocol the app has exited, this is given to the app's
+/// task so the protocol can finish a response the app left incomplete.
+#[derive(Clone)]
+pub struct ExitHandle {
+    token: Token,
+
+    event_loop: EventLoopHandle,
+
+    tx: Sender<bool>,
+}
+
+impl ExitHandle {
+    /// Marks the app as exited, `failed` is set if it raised.
+    pub fn exited(&self, failed: bool) {
+        let _ = self.tx.try_send(failed);
+        self.event_loop.resume_writing(self.token);
+    }
+}
+
+
+/// Extracts the iterable of `[name, value]` byte pairs given by the app.
+fn extract_headers(headers: &PyAny) -> PyResult<ResponseHeaders> {
+    let mut extracted = Vec::new();
+    for pair in headers.iter()? {
+        let pair = pair?;
+        let name: &[u8] = pair.get_item(0)?.extract()?;
+        let value: &[u8] = pair.get_item(1)?.extract()?;
+
+        check_header_name(name)?;
+        check_header_value(value)?;
+
+        extracted.push((name.to_vec(), value.to_vec()));
+    }
+
+    Ok(extracted)
+}
+
+/// Checks the header name is a RFC 7230 token, the headers are written
+/// to the socket as they are given.
+fn check_header_name(name: &[u8]) -> PyResult<()> {
+    let is_token = !name.is_empty() && name.iter().all(|&b| {
+        b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    });
+
+    if !is_token {
+        return Err(PyValueError::new_err(format!(
+            "invalid header name {:?}", String::from_utf8_lossy(name)
+        )))
+    }
+
+    Ok(())
+}
+
+/// Checks the header value cannot end the header early, this would let
+/// the value add headers or split the response.
+fn check_header_value(value: &[u8]) -> PyResult<()> {
+    if value.iter().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+        return Err(PyValueError::new_err(format!(
+            "invalid header value {:?}", String::from_utf8_lossy(value)
+        )))
+    }
+
+    Ok(())
+}
+
 
 pub struct SenderHandler {
     /// The sender half for sending body chunks.
-    sender_tx: Sender<Payload>,
+    sender_tx: Sender<SenderPayload>,
 
     /// The receiver half for sending body chunks.
-    sender_rx: Receiver<Payload>,
+    sender_rx: Receiver<SenderPayload>,
+
+    /// The sender half for the app signalling it has exited.
+    exit_tx: Sender<bool>,
+
+    /// The receiver half for the app signalling it has exited.
+    exit_rx: Receiver<bool>,
+
+    /// Wakes the app once there is space in the channel.
+    waker: Waker,
 
     token: Token,
 
@@ -63,9 +409,13 @@ pub struct SenderHandler {
 impl SenderHandler {
     pub fn new(token: Token, event_loop: EventLoopHandle) -> Self {
         let (tx, rx) = bounded(10);
+        let (exit_tx, exit_rx) = bounded(1);
         Self {
             sender_tx: tx,
             sender_rx: rx,
+            exit_tx,
+            exit_rx,
+            waker: Waker::default(),
             token,
             event_loop,
         }
@@ -75,11 +425,83 @@ impl SenderHandler {
         DataSender::new(
             self.token,
             self.event_loop.clone(),
-            self.sender_tx.clone()
+            self.sender_tx.clone(),
+            self.waker.clone(),
         )
     }
 
-    pub fn recv(&self) -> Result<Payload, TryRecvError> {
-        self.sender_rx.try_recv()
+    pub fn make_exit_handle(&self) -> ExitHandle {
+        ExitHandle {
+            token: self.token,
+            event_loop: self.event_loop.clone(),
+            tx: self.exit_tx.clone(),
+        }
+    }
+
+    pub fn recv(&self) -> Result<SenderPayload, TryRecvError> {
+        let payload = self.sender_rx.try_recv()?;
+        self.waker.wake();
+
+        Ok(payload)
+    }
+
+    /// Sends a event on behalf of the app, this is used by the protocol
+    /// to respond without invoking the app.
+    pub fn send(
+        &self,
+        payload: SenderPayload,
+    ) -> Result<(), TrySendError<SenderPayload>> {
+        self.sender_tx.try_send(payload)
+    }
+
+    /// If the app has exited, this is `Some(true)` if it raised.
+    pub fn app_exited(&self) -> Option<bool> {
+        self.exit_rx.try_recv().ok()
+    }
+
+    /// Replaces the channel ready for a new request, any handles from
+    /// the previous request can no longer send events.
+    pub fn reset(&mut self) {
+        // Anything still waiting to send on the previous request finds
+        // the channel has been closed.
+        self.waker.wake();
+        self.waker = Waker::default();
+
+        let (tx, rx) = bounded(10);
+        self.sender_tx = tx;
+        self.sender_rx = rx;
+
+        let (exit_tx, exit_rx) = bounded(1);
+        self.exit_tx = exit_tx;
+        self.exit_rx = exit_rx;
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_token_names() {
+        assert!(check_header_name(b"content-type").is_ok());
+        assert!(check_header_name(b"X-Custom_1.2~!").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        for name in [&b""[..], b"bad name", b"bad:", b"bad\r\n", b"caf\xc3\xa9"] {
+            assert!(check_header_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_line_breaks_in_values() {
+        assert!(check_header_value(b"/next?a=b c").is_ok());
+        assert!(check_header_value(b"").is_ok());
+
+        for value in [&b"/\r\nset-cookie: a=b"[..], b"a\nb", b"a\rb", b"a\0b"] {
+            assert!(check_header_value(value).is_err(), "{:?}", value);
+        }
+    }
+}
@@ -1,29 +1,59 @@
-use mio::net::{TcpStream, TcpListener};
 use mio::{Poll, Events, Token, Interest, Waker};
 use mio::event::Event;
+use log::{debug, error, warn};
 
-use std::net::SocketAddr;
 use std::io;
-use std::error::Error;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use rustc_hash::FxHashMap;
+use slab::Slab;
 
 use crate::pyre_server::client::Client;
 use crate::pyre_server::transport::{UpdatesQueue, EventUpdate, EventLoopHandle};
 use crate::pyre_server::py_callback::CallbackHandler;
+use crate::pyre_server::config::{SharedConfig, SharedListenerConfig};
+use crate::pyre_server::buffer_pool::BufferPool;
+use crate::pyre_server::timer::TimerWheel;
+use crate::pyre_server::lifespan::Lifespan;
+use crate::pyre_server::net::{Addr, Listener, Stream};
+use crate::pyre_server::error::{PyreError, PyreResult};
 
 
-/// The standard server identifier token.
-const SERVER: Token = Token(0);
-
 /// The wakeup event that checks updates.
-const CHECK_UPDATE: Token = Token(1);
+const CHECK_UPDATE: Token = Token(0);
+
+/// The token of the first listener, each listener is given the next
+/// token along in the order they were bound.
+const FIRST_LISTENER: usize = 1;
+
+/// The max number of listeners a server can accept connections from.
+pub const MAX_LISTENERS: usize = 64;
+
+/// The value added to a client's slot index in its token, the tokens
+/// below this are used by the server itself.
+const FIRST_CLIENT: usize = FIRST_LISTENER + MAX_LISTENERS;
+
+/// The number of low bits of a client's token that hold its slot index,
+/// the high bits hold the generation of the connection.
+const SLOT_BITS: u32 = usize::BITS / 2;
+
+/// The mask of the slot index bits of a client's token.
+const SLOT_MASK: usize = (1 << SLOT_BITS) - 1;
+
+/// The max number of clients a server can hold, any more would not fit
+/// in the slot index bits of a token.
+const MAX_CLIENTS: usize = SLOT_MASK + 1 - FIRST_CLIENT;
 
 /// The MAX events that can be enqueued at any one time.
 const EVENTS_MAX: usize = 128;
 
+/// The length of time each slot of the keep alive timer wheel covers.
+const TIMER_RESOLUTION: Duration = Duration::from_millis(250);
+
+/// The amount of slots in the keep alive timer wheel.
+const TIMER_SLOTS: usize = 256;
+
 
 /// The state that has updated on the socket showing its readiness.
 pub enum SocketPollState {
@@ -40,24 +70,76 @@ pub enum SocketPollState {
 }
 
 
-/// A simple incremental counter that produces new tokens with its
-/// given internal counter.
+/// The client counts of a running server, these are updated by the
+/// server and can be read from any thread.
+#[derive(Default)]
+pub struct ServerStats {
+    /// The number of connections currently being handled.
+    pub clients: AtomicUsize,
+
+    /// The number of clients kept around to handle new connections.
+    pub idle: AtomicUsize,
+}
+
+
+/// A cheaply cloneable handle for stopping a running server and reading
+/// its stats from another thread.
+#[derive(Clone)]
+pub struct ServerControl {
+    /// The event loop handle the shutdown is sent through.
+    event_loop: EventLoopHandle,
+
+    /// The client counts of the server.
+    pub stats: Arc<ServerStats>,
+}
+
+impl ServerControl {
+    /// Tells the server to shutdown, it stops accepting connections and
+    /// exits once the open ones have finished or the grace period ends.
+    pub fn stop(&self) {
+        self.event_loop.shutdown();
+    }
+}
+
+
+/// A incremental counter that produces the tokens of new connections.
+///
+/// Each token is made up of the index of the client's slot and a
+/// generation that changes with every connection, this means anything
+/// left over from the previous connection handled by a slot e.g. queued
+/// event updates, timers or the app's handles no longer matches it.
 struct TokenCounter {
-    internal: usize,
+    generation: usize,
 }
 
 impl TokenCounter {
-    /// Make a new counter that starts at `0`
+    /// Make a new counter that starts at generation `0`.
     fn new() -> Self {
-        Self { internal: 2 }
+        Self { generation: 0 }
     }
 
-    /// Get a new token from the counter.
-    fn next(&mut self) -> Token {
-        self.internal += 1;
-        let id = self.internal;
+    /// Get a new token for a connection handled by the given slot.
+    fn next(&mut self, slot: usize) -> Token {
+        self.generation = self.generation.wrapping_add(1)
+            & (usize::MAX >> SLOT_BITS);
 
-        Token(id)
+        Token((self.generation << SLOT_BITS) | (slot + FIRST_CLIENT))
+    }
+}
+
+/// The index of the slot the client with the given token is held in.
+fn slot_of(token: Token) -> usize {
+    (token.0 & SLOT_MASK).wrapping_sub(FIRST_CLIENT)
+}
+
+/// The index of the listener with the given token, this is `None` for
+/// any token that does not belong to a listener.
+fn listener_of(token: Token) -> Option<usize> {
+    let index = token.0.wrapping_sub(FIRST_LISTENER);
+    if index < MAX_LISTENERS {
+        Some(index)
+    } else {
+        None
     }
 }
 
@@ -68,9 +150,13 @@ impl TokenCounter {
 /// as the state machine is driven by the events emitted from the
 /// LowLevelServer.
 pub struct HighLevelServer {
-    /// The mapping that stores all active clients, this is used to
+    /// The slots that store all active clients, this is used to
     /// invoke the relevant callbacks when the event loop state changes.
-    clients: FxHashMap<Token, Client>,
+    clients: Slab<Client<Stream>>,
+
+    /// The tokens of the idle clients waiting to handle a new connection,
+    /// clients that have been removed since are skipped once reached.
+    idle: Vec<Token>,
 
     /// The incremental counter that is used to generate new tokens.
     counter: TokenCounter,
@@ -80,6 +166,21 @@ pub struct HighLevelServer {
 
     /// The python callbacks to invoke on a request.
     callbacks: CallbackHandler,
+
+    /// The server settings shared with every client.
+    config: SharedConfig,
+
+    /// The read and write buffers shared with every client.
+    buffers: BufferPool,
+
+    /// The keep alive deadlines of every client.
+    timers: TimerWheel,
+
+    /// The max time a client can be inactive between requests.
+    keep_alive_timeout: Duration,
+
+    /// The client counts shared with the server's control handle.
+    stats: Arc<ServerStats>,
 }
 
 impl HighLevelServer {
@@ -88,70 +189,102 @@ impl HighLevelServer {
     pub fn new(
         event_loop: EventLoopHandle,
         callbacks: CallbackHandler,
+        config: SharedConfig,
+        buffers: BufferPool,
+        keep_alive_timeout: Duration,
+        stats: Arc<ServerStats>,
     ) -> Self {
-        let clients = FxHashMap::default();
+        let clients = Slab::new();
         let counter = TokenCounter::new();
+        let timers = TimerWheel::new(TIMER_RESOLUTION, TIMER_SLOTS);
 
         Self {
             clients,
+            idle: Vec::new(),
             counter,
             event_loop,
             callbacks,
+            config,
+            buffers,
+            timers,
+            keep_alive_timeout,
+            stats,
         }
     }
 
-    /// Finds the first client that is classed as 'idle' which is
-    /// then selected to be used as the handler of the new connection.
-    fn get_idle_client(&self) -> Option<Token> {
-        for (key, client) in self.clients.iter() {
-            if client.is_idle {
-                return Some(*key)
+    /// Takes the slot of the most recently idled client which is then
+    /// used as the handler of the new connection.
+    fn take_idle_slot(&mut self) -> Option<usize> {
+        while let Some(token) = self.idle.pop() {
+            if self.has_client(&token) {
+                return Some(slot_of(token))
             }
         }
 
         None
     }
 
-    /// Selects a index using either an existing idle protocol instance
-    /// or making a new protocol by returning a index that does not exist in
-    /// the clients hashmap.
-    fn select_token(&mut self) -> Token {
-        match self.get_idle_client() {
-            Some(token) => token,
-            None => {
-                let token = self.counter.next();
+    /// Invoked when ever a client is accepted from one of the listeners.
+    ///
+    /// Returns the token of the new connection, `connection_made` must
+    /// be invoked with it straight after. This is `None` if the server
+    /// cannot hold another client, the connection is closed straight away.
+    fn client_accepted(
+        &mut self,
+        stream: Stream,
+        addr: Addr,
+        listener: SharedListenerConfig,
+    ) -> Option<Token> {
+
+        let token = match self.take_idle_slot() {
+            Some(slot) => {
+                let token = self.counter.next(slot);
+                self.clients[slot].handle_new(token, stream, addr, listener);
+                self.stats.idle.fetch_sub(1, Ordering::Relaxed);
 
                 token
-            }
-        }
-    }
+            },
+            None => {
+                let entry = self.clients.vacant_entry();
+                if entry.key() >= MAX_CLIENTS {
+                    warn!(
+                        clients = entry.key();
+                        "Rejecting connection, the server cannot hold any more clients",
+                    );
+                    return None
+                }
 
-    /// Invoked when ever a client is accepted from the listener.
-    fn client_accepted(
-        &mut self,
-        stream: TcpStream,
-        addr: SocketAddr,
-    ) -> Result<(), Box<dyn Error>> {
+                let token = self.counter.next(entry.key());
 
-        let token = self.select_token();
+                entry.insert(Client::build_from(
+                    token,
+                    stream,
+                    addr,
+                    listener,
+                    self.event_loop.clone(),
+                    self.callbacks.clone(),
+                    self.config.clone(),
+                    self.buffers.clone(),
+                ));
 
-        if let Some(client) = self.clients.get_mut(&token) {
-            client.handle_new(
-                stream,
-                addr
-            );
-        } else {
-            let client = Client::build_from(
-                token,
-                stream,
-                addr,
-                self.event_loop.clone(),
-                self.callbacks.clone(),
-            );
+                token
+            },
+        };
 
-            self.clients.insert(token, client);
-        }
+        // Any timer of a re-used client belongs to its old token.
+        let deadline = Instant::now() + self.keep_alive_timeout;
+        self.timers.schedule(token, deadline);
+        self.clients[slot_of(token)].timer_deadline = Some(deadline);
+
+        self.stats.clients.fetch_add(1, Ordering::Relaxed);
+
+        Some(token)
+    }
 
+    /// Hands the newly accepted connection to its client's protocol and
+    /// starts reading from it.
+    fn connection_made(&mut self, token: Token) -> PyreResult<()> {
+        self.get_client(&token)?.connection_made()?;
         self.event_loop.resume_reading(token);
 
         Ok(())
@@ -163,44 +296,155 @@ impl HighLevelServer {
         &mut self,
         token: Token,
         state: SocketPollState
-    ) -> Result<(), Box<dyn Error>> {
-        let client = self.clients.get_mut(&token)
-            .expect("No client at token.");
+    ) -> PyreResult<()> {
+        let client = self.clients.get_mut(slot_of(token))
+            .filter(|client| client.token() == token)
+            .ok_or(PyreError::UnknownClient(token))?;
 
+        let was_idle = client.is_idle;
         match state {
             SocketPollState::Read => client.read_ready()?,
             SocketPollState::Write => client.write_ready()?,
             SocketPollState::Shutdown => client.sock_shutdown()?,
         };
 
+        if !was_idle & client.is_idle {
+            self.idle.push(token);
+            self.stats.clients.fetch_sub(1, Ordering::Relaxed);
+            self.stats.idle.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // A partially read request may need checking before the
+        // currently scheduled timer is due.
+        if let Some(deadline) = client.request_deadline() {
+            let is_sooner = client.timer_deadline
+                .map(|current| deadline < current)
+                .unwrap_or(true);
+
+            if is_sooner {
+                client.timer_deadline = Some(deadline);
+                self.timers.schedule(token, deadline);
+            }
+        }
+
         Ok(())
     }
 
-    /// Invoked every n seconds checking for keep alive on sockets.
-    fn keep_alive_tick(&mut self) {
-        println!("Keep alive");
-        for (_, client) in self.clients.iter_mut() {
-            if let Err(e) = client.check_keep_alive() {
-                eprintln!("Exception handling client: {:?}", e);
+    /// Invoked on every tick of the timer wheel checking for requests
+    /// that have missed their deadline and clients that have been
+    /// inactive for longer than the keep alive timeout.
+    ///
+    /// Returns the tokens of every client that has expired.
+    fn keep_alive_tick(&mut self, now: Instant) -> Vec<Token> {
+        let mut expired = Vec::new();
+
+        for (token, deadline) in self.timers.advance(now) {
+            let client = match self.clients.get_mut(slot_of(token)) {
+                Some(client) if client.token() == token => client,
+                _ => continue,
+            };
+
+            // The client has been rescheduled since this timer was set.
+            if client.timer_deadline != Some(deadline) {
+                continue
+            }
+
+            match client.check_timeouts(now, self.keep_alive_timeout) {
+                Some(deadline) => {
+                    client.timer_deadline = Some(deadline);
+                    self.timers.schedule(token, deadline);
+                },
+                None => expired.push(token),
             };
         }
+
+        expired
+    }
+
+    /// The time left until the next keep alive tick is due.
+    fn next_tick_in(&self, now: Instant) -> Duration {
+        self.timers.next_tick_in(now)
+    }
+
+    /// Removes the client from the server dropping its stream, the app
+    /// is not left running for a connection that no longer exists.
+    fn remove_client(&mut self, token: &Token) -> Option<Client<Stream>> {
+        if !self.has_client(token) {
+            return None
+        }
+
+        let client = self.clients.remove(slot_of(*token));
+        self.callbacks.cancel(*token);
+
+        if client.is_idle {
+            self.stats.idle.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            self.stats.clients.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        Some(client)
     }
 
-    fn get_client(&mut self, token: &Token) -> &mut Client {
-        self.clients.get_mut(token)
-            .expect("Failed to get client from token.")
+    /// Tells every connected client the server is shutting down so
+    /// they close once their current request has been handled.
+    fn shutdown(&mut self) {
+        for (_, client) in self.clients.iter_mut() {
+            client.shutdown();
+        }
+    }
+
+    /// If every client has finished with its connection.
+    fn is_drained(&self) -> bool {
+        self.clients.iter().all(|(_, client)| client.is_idle)
+    }
+
+    /// Closes every client, this is used once the server is stopping.
+    ///
+    /// Every client is closed even if some fail, the first error is
+    /// returned once they have all been closed.
+    fn close_all(&mut self) -> PyreResult<()> {
+        let mut result = Ok(());
+        for (_, client) in self.clients.iter_mut() {
+            let closed = client.close();
+            if result.is_ok() {
+                result = closed;
+            }
+
+            // The app is not left running for a connection that no
+            // longer exists.
+            self.callbacks.cancel(client.token());
+        }
+
+        self.clients.clear();
+        self.idle.clear();
+        self.stats.clients.store(0, Ordering::Relaxed);
+        self.stats.idle.store(0, Ordering::Relaxed);
+
+        result
+    }
+
+    /// Whether or not a client exists at the given token, the token of
+    /// a previous connection handled by the same slot does not match.
+    fn has_client(&self, token: &Token) -> bool {
+        self.clients.get(slot_of(*token))
+            .map(|client| client.token() == *token)
+            .unwrap_or(false)
+    }
+
+    fn get_client(&mut self, token: &Token) -> PyreResult<&mut Client<Stream>> {
+        self.clients.get_mut(slot_of(*token))
+            .filter(|client| client.token() == *token)
+            .ok_or(PyreError::UnknownClient(*token))
     }
 }
 
 
-/// The low-level polling side of the server, this is built from a
-/// `mio::net::TcpListener` and handles running the event loop itself.
+/// The low-level polling side of the server, this is built from one or
+/// more `Listener`s and handles running the event loop itself.
 pub struct LowLevelServer {
-    /// This server's SocketAddr containing hostname, address and port.
-    host: SocketAddr,
-
-    /// The TcpListener itself that the event loop polls off to begin with.
-    listener: TcpListener,
+    /// The listeners the event loop accepts new connections from along
+    /// with the settings of the connections accepted from each.
+    listeners: Vec<(Listener, SharedListenerConfig)>,
 
     /// A cheaply cloneable reference to the main poller of the event loop.
     poll: Poll,
@@ -212,23 +456,39 @@ pub struct LowLevelServer {
     /// interactions.
     high_level: HighLevelServer,
 
-    /// The max time between data handling.
-    keep_alive_timeout: Duration,
+    /// The handle used to stop the server from another thread.
+    control: ServerControl,
+
+    /// The max time open connections are given to finish once the
+    /// server starts shutting down.
+    shutdown_timeout: Duration,
+
+    /// The time the server stops waiting on open connections, this is
+    /// set once the server starts shutting down.
+    shutdown_deadline: Option<Instant>,
+
+    /// The ASGI lifespan scope run around the event loop.
+    lifespan: Lifespan,
 }
 
 impl LowLevelServer {
-    /// Builds a server instance from a given addr string e.g.
-    /// `127.0.0.1:8080`, this has the potential to raise an io Error
-    /// as it binds to the socket in the process of building this server.
-    pub fn from_addr(
-        addr: String,
+    /// Builds a server instance around listeners that have already been
+    /// bound, this has the potential to raise an io Error as it creates
+    /// the poller in the process of building this server.
+    pub fn from_listeners(
+        listeners: Vec<(Listener, SharedListenerConfig)>,
         keep_alive_timeout: Duration,
         callbacks: CallbackHandler,
+        config: SharedConfig,
+        buffers: BufferPool,
+        lifespan: Lifespan,
     ) -> io::Result<Self> {
-        let host = addr.parse()
-            .expect("Failed to build SocketAddr from addr");
-
-        let listener = TcpListener::bind(host)?;
+        if listeners.is_empty() | (listeners.len() > MAX_LISTENERS) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a server needs between 1 and {} listeners", MAX_LISTENERS),
+            ))
+        }
 
         let poll = Poll::new()?;
 
@@ -239,54 +499,115 @@ impl LowLevelServer {
             CHECK_UPDATE
         )?;
 
+        let waker = Arc::new(waker);
         let transport = EventLoopHandle::from_queue_and_waker(
             updates.clone(),
-            Arc::new(waker),
+            waker,
         );
 
+        let control = ServerControl {
+            event_loop: transport.clone(),
+            stats: Arc::new(ServerStats::default()),
+        };
+
+        let shutdown_timeout = config.shutdown_timeout;
+
         let high_level = HighLevelServer::new(
             transport,
             callbacks,
+            config,
+            buffers,
+            keep_alive_timeout,
+            control.stats.clone(),
         );
 
         Ok(Self {
-            host,
-            listener,
+            listeners,
             poll,
             updates,
             high_level,
-            keep_alive_timeout,
+            control,
+            shutdown_timeout,
+            shutdown_deadline: None,
+            lifespan,
         })
     }
 
+    /// A handle for stopping the server once it has been started.
+    pub fn control(&self) -> ServerControl {
+        self.control.clone()
+    }
+
+    /// The addresses the listeners are bound to in the order they were
+    /// given.
+    pub fn local_addrs(&self) -> io::Result<Vec<Addr>> {
+        self.listeners.iter()
+            .map(|(listener, _)| listener.local_addr())
+            .collect()
+    }
+
     /// Starts the event loop on the given thread, this is blocking and
-    /// will never exit unless ther`e is an error that causes and abruptly
+    /// will not exit until the server has been shutdown with its control
+    /// handle and drained or there is an error that causes and abruptly
     /// stops the loop.
-    pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
+    ///
+    /// The ASGI lifespan startup is completed before any connections
+    /// are accepted, the server does not start if the app fails it.
+    pub fn start(&mut self) -> PyreResult<()> {
+        self.lifespan.startup()?;
+
+        let result = self.run();
+        let drained = self.on_drained();
+
+        result.and(drained)
+    }
+
+    /// The last step of the shutdown once the in-flight requests have
+    /// finished or the grace period has passed, anything still open is
+    /// closed and the app is sent `lifespan.shutdown` before `start`
+    /// returns.
+    fn on_drained(&mut self) -> PyreResult<()> {
+        let closed = self.high_level.close_all();
+        self.lifespan.shutdown(self.shutdown_timeout)?;
+
+        closed
+    }
+
+    /// Runs the event loop until the server has been shutdown and
+    /// drained, only errors from polling or the listeners themselves
+    /// stop the loop early.
+    fn run(&mut self) -> PyreResult<()> {
         let mut events = Events::with_capacity(EVENTS_MAX);
 
-        self.poll.registry()
-            .register(
-            &mut self.listener,
-            SERVER,
-            Interest::READABLE
-            )?;
+        for (index, (listener, _)) in self.listeners.iter_mut().enumerate() {
+            self.poll.registry()
+                .register(
+                listener,
+                Token(FIRST_LISTENER + index),
+                Interest::READABLE
+                )?;
+        }
 
         loop {
-            let status = self.poll.poll(
-                &mut events,
-                Some(self.keep_alive_timeout)
-            );
-
-            if let Err(e) = status {
-                if e.kind() == io::ErrorKind::WouldBlock {
-                    self.high_level.keep_alive_tick();
-                } else {
-                    eprintln!("{:?}", e);
+            let now = Instant::now();
+            let mut timeout = self.high_level.next_tick_in(now);
+
+            if let Some(deadline) = self.shutdown_deadline {
+                if self.high_level.is_drained() | (deadline <= now) {
+                    break
                 }
+
+                timeout = timeout.min(deadline - now);
             }
 
+            match self.poll.poll(&mut events, Some(timeout)) {
+                Ok(()) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.into()),
+            };
+
             self.process_events(&events)?;
+            self.on_keep_alive_tick();
         }
 
         Ok(())
@@ -296,55 +617,129 @@ impl LowLevelServer {
     ///
     /// This is always invoked after poll() has completed and the events
     /// list has been filled.
-    fn process_events(&mut self, events: &Events) -> Result<(), Box<dyn Error>> {
+    fn process_events(&mut self, events: &Events) -> PyreResult<()> {
         for event in events.iter() {
             match event.token() {
-                SERVER => self.on_client_incoming()?,
                 CHECK_UPDATE => self.on_update_wakeup()?,
-                _ => self.on_socket_state_change(event)?,
+                token => match listener_of(token) {
+                    Some(index) => self.on_client_incoming(index),
+                    None => {
+                        if let Err(e) = self.on_socket_state_change(event) {
+                            self.client_failed(token, e);
+                        }
+                    },
+                },
             }
         }
 
         Ok(())
     }
 
-    /// Handles a client waiting to be accepted from the listener.
-    fn on_client_incoming(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Handles a client waiting to be accepted from the listener at the
+    /// given index.
+    fn on_client_incoming(&mut self, index: usize) {
         loop {
-            let (client, addr) = match self.listener.accept() {
+            let (listener, config) = match self.listeners.get(index) {
+                Some(entry) => entry,
+                None => return,
+            };
+
+            let (client, addr) = match listener.accept() {
                 Ok(pair) => pair,
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     break;
                 },
                 Err(e) => {
-                    eprintln!("Failed accepting from listener: {:?}", e);
-                    return Ok(());
+                    warn!(listener = index; "Failed accepting from listener: {}", e);
+                    return;
                 },
             };
 
-            self.high_level.client_accepted(client, addr)?;
+            let config = config.clone();
+            let token = match self.high_level.client_accepted(client, addr, config) {
+                Some(token) => token,
+                None => continue,
+            };
+
+            if let Err(e) = self.high_level.connection_made(token) {
+                self.client_failed(token, e);
+            }
         };
+    }
 
-        Ok(())
+    /// Closes and removes any clients that have been inactive for longer
+    /// than the keep alive timeout.
+    fn on_keep_alive_tick(&mut self) {
+        let expired = self.high_level.keep_alive_tick(Instant::now());
+
+        for token in expired {
+            debug!(token = token.0; "Connection expired");
+
+            if let Err(e) = self.close_client(token) {
+                warn!(token = token.0; "Failed closing expired connection: {}", e);
+            }
+        }
     }
 
     /// Handles any update events received e.g. adding reading and writers.
-    fn on_update_wakeup(&mut self) -> Result<(), Box<dyn Error>> {
+    ///
+    /// A failed update only closes the client it was for, updates for
+    /// the whole server stop the event loop if they fail.
+    fn on_update_wakeup(&mut self) -> PyreResult<()> {
         while let Some(update) = self.updates.pop() {
-            self.handle_update(update)?;
+            let token = update.token();
+
+            match (self.handle_update(update), token) {
+                (Ok(()), _) => {},
+                (Err(e), Some(token)) => self.client_failed(token, e),
+                (Err(e), None) => return Err(e),
+            };
         }
 
         Ok(())
     }
 
+    /// Closes the client after a error handling its connection, the
+    /// rest of the server carries on as normal.
+    fn client_failed(&mut self, token: Token, error: PyreError) {
+        error!(token = token.0; "Closing connection after error: {}", error);
+
+        if let Err(e) = self.close_client(token) {
+            warn!(token = token.0; "Failed closing connection: {}", e);
+        }
+    }
+
+    /// Stops polling the client's socket and removes the client from the
+    /// server closing its connection.
+    fn close_client(&mut self, token: Token) -> PyreResult<()> {
+        if !self.high_level.has_client(&token) {
+            return Ok(())
+        }
+
+        // The client is removed even if the poll could not be updated,
+        // closing the socket takes it out of the poll regardless.
+        let paused = self.pause_reading(token)
+            .and_then(|_| self.pause_writing(token));
+
+        if let Some(mut client) = self.high_level.remove_client(&token) {
+            client.close()?;
+        }
+
+        paused
+    }
+
     /// Handles any other event other than the server listener being
     /// readable.
     fn on_socket_state_change(
         &mut self,
         event: &Event
-    )  -> Result<(), Box<dyn Error>> {
+    )  -> PyreResult<()> {
 
         let token = event.token();
+        if !self.high_level.has_client(&token) {
+            return Ok(())
+        }
+
         if event.is_readable() {
             self.high_level.socket_state_update(
                 token,
@@ -372,36 +767,99 @@ impl LowLevelServer {
 }
 
 impl LowLevelServer {
-    fn handle_update(&mut self, update: EventUpdate) -> io::Result<()> {
+    fn handle_update(
+        &mut self,
+        update: EventUpdate,
+    ) -> PyreResult<()> {
+        // The client may have been removed since the update was queued.
+        match update.token() {
+            Some(token) if !self.high_level.has_client(&token) => {
+                return Ok(())
+            },
+            _ => {},
+        }
+
         match update {
             EventUpdate::PauseReading(token) => self.pause_reading(
                 token,
-            ),
+            )?,
             EventUpdate::PauseWriting(token) => self.pause_writing(
                 token
-            ),
-            EventUpdate::ResumeReading(token) => self.resume_reading(
-                token
-            ),
-            EventUpdate::ResumeWriting(token) => self.resume_writing(
-                token
-            ),
+            )?,
+            EventUpdate::ResumeReading(token) => {
+                self.resume_reading(token)?;
+                self.high_level.get_client(&token)?.reading_resumed()?;
+            },
+            EventUpdate::ResumeWriting(token) => {
+                let was_writing = self.high_level.get_client(&token)?.is_writing;
+                self.resume_writing(token)?;
+
+                // Registering the socket produces a event if it is
+                // writable but a socket that is already registered
+                // will not produce a new one.
+                if was_writing {
+                    self.high_level.socket_state_update(
+                        token,
+                        SocketPollState::Write,
+                    )?;
+                }
+            },
+            EventUpdate::Release(token) => self.release_stream(token)?,
+            EventUpdate::Shutdown => self.begin_shutdown()?,
+        };
+
+        Ok(())
+    }
+
+    /// Stops accepting new connections and tells the open ones to
+    /// finish, the event loop exits once they have or the grace
+    /// period ends.
+    fn begin_shutdown(&mut self) -> PyreResult<()> {
+        if self.shutdown_deadline.is_some() {
+            return Ok(())
+        }
+
+        for (listener, _) in self.listeners.iter_mut() {
+            self.poll.registry().deregister(listener)?;
         }
+
+        self.shutdown_deadline = Some(Instant::now() + self.shutdown_timeout);
+        self.high_level.shutdown();
+
+        Ok(())
+    }
+
+    /// Stops polling the socket of a client whose connection has been
+    /// closed by the remote and closes it, the client is kept to handle
+    /// a new connection.
+    fn release_stream(&mut self, token: Token) -> PyreResult<()> {
+        // The socket is closed even if the poll could not be updated,
+        // closing it takes it out of the poll regardless.
+        let paused = self.pause_reading(token)
+            .and_then(|_| self.pause_writing(token));
+
+        self.high_level.get_client(&token)?.release_stream();
+
+        paused
     }
 
-    fn pause_reading(&mut self, token: Token) -> io::Result<()> {
-        let client = self.high_level.get_client(&token);
+    fn pause_reading(&mut self, token: Token) -> PyreResult<()> {
+        let client = self.high_level.get_client(&token)?;
+        let stream = match client.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
 
         // Only need to change something if its actually doing it.
         if client.is_reading {
             if client.is_writing {
                 self.poll.registry().reregister(
-                    &mut client.stream,
+                    stream,
                     token,
                     Interest::WRITABLE,
                 )?;
             } else {
-                self.poll.registry().deregister(&mut client.stream)?;
+                self.poll.registry().deregister(stream)?;
             }
 
             client.is_reading = false;
@@ -410,19 +868,23 @@ impl LowLevelServer {
         Ok(())
     }
 
-    fn pause_writing(&mut self, token: Token) -> io::Result<()> {
-        let client = self.high_level.get_client(&token);
+    fn pause_writing(&mut self, token: Token) -> PyreResult<()> {
+        let client = self.high_level.get_client(&token)?;
+        let stream = match client.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
 
         // Only need to change something if its actually doing it.
         if client.is_writing {
             if client.is_reading {
                 self.poll.registry().reregister(
-                    &mut client.stream,
+                    stream,
                     token,
                     Interest::READABLE,
                 )?;
             } else {
-                self.poll.registry().deregister(&mut client.stream)?;
+                self.poll.registry().deregister(stream)?;
             }
 
             client.is_writing = false;
@@ -431,20 +893,24 @@ impl LowLevelServer {
         Ok(())
     }
 
-    fn resume_reading(&mut self, token: Token) -> io::Result<()> {
-        let client = self.high_level.get_client(&token);
+    fn resume_reading(&mut self, token: Token) -> PyreResult<()> {
+        let client = self.high_level.get_client(&token)?;
+        let stream = match client.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
 
         // Only need to change something if its actually doing it.
-        if !client.is_reading {
+        if !client.is_reading & !client.is_idle {
             if client.is_writing {
                 self.poll.registry().reregister(
-                    &mut client.stream,
+                    stream,
                     token,
                     Interest::READABLE | Interest::WRITABLE,
                 )?;
             } else {
                 self.poll.registry().register(
-                    &mut client.stream,
+                    stream,
                     token,
                     Interest::READABLE,
                 )?;
@@ -456,20 +922,24 @@ impl LowLevelServer {
         Ok(())
     }
 
-    fn resume_writing(&mut self, token: Token) -> io::Result<()> {
-        let client = self.high_level.get_client(&token);
+    fn resume_writing(&mut self, token: Token) -> PyreResult<()> {
+        let client = self.high_level.get_client(&token)?;
+        let stream = match client.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
 
         // Only need to change something if its actually doing it.
-        if !client.is_writing {
+        if !client.is_writing & !client.is_idle {
             if client.is_reading {
                 self.poll.registry().reregister(
-                    &mut client.stream,
+                    stream,
                     token,
                     Interest::READABLE | Interest::WRITABLE,
                 )?;
             } else {
                 self.poll.registry().register(
-                    &mut client.stream,
+                    stream,
                     token,
                     Interest::WRITABLE,
                 )?;
@@ -480,4 +950,62 @@ impl LowLevelServer {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_round_trips_through_token() {
+        let mut counter = TokenCounter::new();
+        for slot in [0, 1, 1000, MAX_CLIENTS - 1] {
+            assert_eq!(slot_of(counter.next(slot)), slot);
+        }
+    }
+
+    #[test]
+    fn client_tokens_are_not_listeners() {
+        let mut counter = TokenCounter::new();
+        for slot in [0, MAX_CLIENTS - 1] {
+            assert_eq!(listener_of(counter.next(slot)), None);
+        }
+
+        assert_eq!(listener_of(Token(FIRST_LISTENER)), Some(0));
+        assert_eq!(listener_of(Token(FIRST_CLIENT - 1)), Some(MAX_LISTENERS - 1));
+    }
+
+    #[test]
+    fn reused_slot_gets_new_token() {
+        let mut counter = TokenCounter::new();
+        let first = counter.next(3);
+        let second = counter.next(3);
+
+        assert_ne!(first, second);
+        assert_eq!(slot_of(first), slot_of(second));
+    }
+
+    #[test]
+    fn generation_wraps_without_touching_slot() {
+        let mut counter = TokenCounter::new();
+        counter.generation = usize::MAX >> SLOT_BITS;
+
+        let token = counter.next(7);
+        assert_eq!(counter.generation, 0);
+        assert_eq!(token, Token(7 + FIRST_CLIENT));
+        assert_eq!(slot_of(token), 7);
+
+        let token = counter.next(7);
+        assert_eq!(token.0 >> SLOT_BITS, 1);
+        assert_eq!(slot_of(token), 7);
+    }
+
+    #[test]
+    fn max_clients_fill_slot_bits() {
+        let mut counter = TokenCounter::new();
+        let token = counter.next(MAX_CLIENTS - 1);
+        assert_eq!(token.0 & SLOT_MASK, SLOT_MASK);
+        assert_eq!(token.0 >> SLOT_BITS, 1);
+    }
+}
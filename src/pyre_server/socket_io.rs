@@ -1,38 +1,605 @@
+use bytes::{Bytes, BytesMut, BufMut, Buf};
 use mio::net::TcpStream;
-use bytes::{BytesMut, BufMut};
-use std::io::{self, Read, Write};
+#[cfg(unix)]
+use mio::net::UnixStream;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write, IoSlice, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
 
-/// The buffer IO trait allows the TcpStream to read and write form
-/// `bytes::BytesMut` directly rather than creating a intermediate
-/// buffer like a `&[u8]`.
+/// The size past which a chunk is queued by reference rather than being
+/// copied into the contiguous part of the write buffer.
+const MIN_QUEUED_CHUNK: usize = 16 * 1024;
+
+/// The max number of chunks handed to a single vectored write.
+const MAX_IO_SLICES: usize = 64;
+
+/// The max amount of a file copied through userspace in one write when
+/// the kernel can not send it directly.
+const FILE_COPY_SIZE: usize = 64 * 1024;
+
+
+/// The buffer IO trait allows the TcpStream or a TLS session to read
+/// and write form `bytes::BytesMut` directly rather than creating a
+/// intermediate buffer like a `&[u8]`.
 pub trait BufferIO {
     /// Reads data from self to the buffer.
     fn read_buf(&mut self, buffer: &mut BytesMut) -> io::Result<usize>;
 
     /// Writes data from the buffer to self.
-    fn write_buf(&mut self, buffer: &mut BytesMut) -> io::Result<usize>;
+    fn write_buf(&mut self, buffer: &mut WriteBuffer) -> io::Result<usize>;
 }
 
-impl BufferIO for TcpStream {
+impl<T: Read + SendFile> BufferIO for T {
     /// Reads data from the socket to the given buffer.
     fn read_buf(&mut self, buffer: &mut BytesMut) -> io::Result<usize> {
         let data = buffer.chunk_mut();
-        let mut slice = unsafe {
+        let slice = unsafe {
             std::slice::from_raw_parts_mut(data.as_mut_ptr(),data.len())
         };
 
-        let len = self.read(&mut slice)?;
+        let len = self.read(slice)?;
 
         unsafe { buffer.advance_mut(len); }
 
         Ok(len)
     }
 
-    /// Writes data from the given buffer to the socket.
-    fn write_buf(&mut self, buffer: &mut BytesMut) -> io::Result<usize> {
-        let len = self.write(buffer)?;
-        let _ = buffer.split_to(len);
+    /// Writes data from the given buffer to the socket, any queued
+    /// chunks are written with a single vectored write up until the next
+    /// file which is sent on its own.
+    fn write_buf(&mut self, buffer: &mut WriteBuffer) -> io::Result<usize> {
+        let len = match buffer.chunks.front() {
+            None => self.write(&buffer.tail)?,
+            Some(Chunk::File(region)) => self.send_file(region)?,
+            Some(Chunk::Bytes(_)) => {
+                let mut slices = [IoSlice::new(&[]); MAX_IO_SLICES];
+                let count = buffer.io_slices(&mut slices);
+                self.write_vectored(&slices[..count])?
+            },
+        };
+
+        buffer.advance(len);
         Ok(len)
     }
-}
\ No newline at end of file
+}
+
+
+/// Writes part of a file to self, by default the file is read into a
+/// intermediate buffer and then written.
+pub trait SendFile: Write {
+    /// Writes the start of the file region to self.
+    ///
+    /// Returns the amount of the region that has been written.
+    fn send_file(&mut self, region: &FileRegion) -> io::Result<usize> {
+        copy_file(self, region)
+    }
+}
+
+/// The kernel copies the file straight to the socket on Linux so the
+/// file is never read into userspace.
+impl SendFile for TcpStream {
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, region: &FileRegion) -> io::Result<usize> {
+        kernel_send_file(self, region)
+    }
+}
+
+#[cfg(unix)]
+impl SendFile for UnixStream {
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, region: &FileRegion) -> io::Result<usize> {
+        kernel_send_file(self, region)
+    }
+}
+
+/// Has the kernel write the start of the file region to the socket.
+#[cfg(target_os = "linux")]
+fn kernel_send_file<S>(socket: &mut S, region: &FileRegion) -> io::Result<usize>
+where
+    S: Write + std::os::unix::io::AsRawFd,
+{
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = region.offset as libc::off_t;
+    let n = unsafe {
+        libc::sendfile(
+            socket.as_raw_fd(),
+            region.file.as_raw_fd(),
+            &mut offset,
+            region.len,
+        )
+    };
+
+    if n >= 0 {
+        return match n as usize {
+            0 => Err(file_truncated()),
+            n => Ok(n),
+        }
+    }
+
+    // Files that can not be sent by the kernel e.g. a file on some
+    // network filesystems are copied instead.
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => copy_file(socket, region),
+        _ => Err(e),
+    }
+}
+
+#[cfg(feature = "tls")]
+impl SendFile for rustls::ServerSession {}
+
+/// Copies the start of the file region to the writer through a
+/// intermediate buffer.
+fn copy_file<W: Write + ?Sized>(
+    writer: &mut W,
+    region: &FileRegion,
+) -> io::Result<usize> {
+    let mut data = [0; FILE_COPY_SIZE];
+    let len = cmp::min(region.len, data.len());
+
+    let n = region.read_at(&mut data[..len])?;
+    if n == 0 {
+        return Err(file_truncated())
+    }
+
+    writer.write(&data[..n])
+}
+
+/// The error returned when a file ends before all of the region has been
+/// written, the response can not be completed so the connection is closed.
+fn file_truncated() -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "the file ended before the response was written",
+    )
+}
+
+
+/// A region of a open file waiting to be written.
+#[derive(Clone)]
+pub struct FileRegion {
+    /// The file itself, this is shared by every part of a split region.
+    file: Arc<File>,
+
+    /// The offset in the file the region starts at.
+    offset: u64,
+
+    /// The length of the region.
+    len: usize,
+}
+
+impl FileRegion {
+    /// Creates a region of the given file.
+    pub fn new(file: File, offset: u64, len: usize) -> Self {
+        Self {
+            file: Arc::new(file),
+            offset,
+            len,
+        }
+    }
+
+    /// Splits off the first `at` bytes of the region.
+    fn split_to(&mut self, at: usize) -> FileRegion {
+        let head = FileRegion {
+            file: self.file.clone(),
+            offset: self.offset,
+            len: at,
+        };
+
+        self.advance(at);
+        head
+    }
+
+    /// Removes the start of the region once it has been written.
+    fn advance(&mut self, amount: usize) {
+        self.offset += amount as u64;
+        self.len -= amount;
+    }
+
+    /// Reads from the start of the region without moving the file's
+    /// cursor which is shared with the app.
+    fn read_at(&self, data: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileExt;
+            self.file.read_at(data, self.offset)
+        }
+
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::FileExt;
+            self.file.seek_read(data, self.offset)
+        }
+    }
+}
+
+
+/// A chunk of a response body.
+pub enum Chunk {
+    /// Data held in memory.
+    Bytes(Bytes),
+
+    /// A region of a file, this is never read into memory if it can be
+    /// sent by the kernel.
+    File(FileRegion),
+}
+
+impl Chunk {
+    /// The length of the chunk.
+    pub fn len(&self) -> usize {
+        match self {
+            Chunk::Bytes(data) => data.len(),
+            Chunk::File(region) => region.len,
+        }
+    }
+
+    /// If the chunk is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Splits off the first `at` bytes of the chunk.
+    fn split_to(&mut self, at: usize) -> Chunk {
+        match self {
+            Chunk::Bytes(data) => Chunk::Bytes(data.split_to(at)),
+            Chunk::File(region) => Chunk::File(region.split_to(at)),
+        }
+    }
+
+    /// Removes the start of the chunk once it has been written.
+    fn advance(&mut self, amount: usize) {
+        match self {
+            Chunk::Bytes(data) => data.advance(amount),
+            Chunk::File(region) => region.advance(amount),
+        }
+    }
+}
+
+impl From<Bytes> for Chunk {
+    fn from(data: Bytes) -> Self {
+        Chunk::Bytes(data)
+    }
+}
+
+
+/// The data waiting to be written to the socket.
+///
+/// Small writes like the response head are copied into a contiguous
+/// buffer while large bodies and files are queued as their own chunks,
+/// this lets a body given by the app be written without ever being
+/// copied. The buffer derefs to the contiguous part so it can be written
+/// to like a regular `BytesMut`, anything written this way comes after
+/// the chunks.
+#[derive(Default)]
+pub struct WriteBuffer {
+    /// The chunks queued ahead of the contiguous part.
+    chunks: VecDeque<Chunk>,
+
+    /// The total length of the queued chunks.
+    queued: usize,
+
+    /// The contiguous part written after the chunks.
+    tail: BytesMut,
+}
+
+impl WriteBuffer {
+    /// The total amount of data waiting to be written.
+    pub fn len(&self) -> usize {
+        self.queued + self.tail.len()
+    }
+
+    /// If there is nothing waiting to be written.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes everything waiting to be written.
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.queued = 0;
+        self.tail.clear();
+    }
+
+    /// Adds the data to the end of the buffer, this is only copied if
+    /// it is small enough that a copy is cheaper than a extra chunk.
+    pub fn put_bytes(&mut self, data: Bytes) {
+        if data.len() < MIN_QUEUED_CHUNK {
+            self.tail.put_slice(&data);
+            return
+        }
+
+        // Anything already written has to be sent first.
+        if !self.tail.is_empty() {
+            let tail = self.tail.split().freeze();
+            self.push_chunk(Chunk::Bytes(tail));
+        }
+
+        self.push_chunk(Chunk::Bytes(data));
+    }
+
+    /// Adds the chunk to the end of the buffer.
+    pub fn put_chunk(&mut self, chunk: Chunk) {
+        let region = match chunk {
+            Chunk::Bytes(data) => return self.put_bytes(data),
+            Chunk::File(region) => region,
+        };
+
+        if !self.tail.is_empty() {
+            let tail = self.tail.split().freeze();
+            self.push_chunk(Chunk::Bytes(tail));
+        }
+
+        self.push_chunk(Chunk::File(region));
+    }
+
+    /// Moves all of the other buffer's data onto the end of this one.
+    pub fn append(&mut self, mut other: WriteBuffer) {
+        for chunk in other.chunks.drain(..) {
+            self.put_chunk(chunk);
+        }
+
+        self.put_bytes(other.tail.freeze());
+    }
+
+    /// Splits off the first `at` bytes of the buffer.
+    ///
+    /// Panics if `at` is larger than the buffer.
+    pub fn split_to(&mut self, mut at: usize) -> WriteBuffer {
+        assert!(at <= self.len(), "split_to out of bounds");
+
+        let mut head = WriteBuffer::default();
+        while at > 0 {
+            let chunk = match self.chunks.front_mut() {
+                Some(chunk) => chunk,
+                None => {
+                    head.tail = self.tail.split_to(at);
+                    break
+                },
+            };
+
+            let len = at.min(chunk.len());
+            let part = chunk.split_to(len);
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+            }
+
+            self.queued -= len;
+            head.push_chunk(part);
+            at -= len;
+        }
+
+        head
+    }
+
+    /// Fills the slices with the data waiting to be written in order,
+    /// this stops at the first file as it has to be written on its own.
+    ///
+    /// Returns the number of slices filled.
+    fn io_slices<'a>(&'a self, slices: &mut [IoSlice<'a>]) -> usize {
+        let reaches_tail = self.chunks
+            .iter()
+            .all(|chunk| matches!(chunk, Chunk::Bytes(_)));
+
+        let tail = Some(self.tail.as_ref()).filter(|_| reaches_tail);
+        let parts = self.chunks
+            .iter()
+            .map_while(|chunk| match chunk {
+                Chunk::Bytes(data) => Some(data.as_ref()),
+                Chunk::File(_) => None,
+            })
+            .chain(tail)
+            .filter(|part| !part.is_empty());
+
+        let mut count = 0;
+        for (slice, part) in slices.iter_mut().zip(parts) {
+            *slice = IoSlice::new(part);
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Removes the given amount of data once it has been written.
+    fn advance(&mut self, mut amount: usize) {
+        while amount > 0 {
+            let chunk = match self.chunks.front_mut() {
+                Some(chunk) => chunk,
+                None => {
+                    self.tail.advance(amount);
+                    return
+                },
+            };
+
+            let len = amount.min(chunk.len());
+            chunk.advance(len);
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+            }
+
+            self.queued -= len;
+            amount -= len;
+        }
+    }
+
+    fn push_chunk(&mut self, chunk: Chunk) {
+        if !chunk.is_empty() {
+            self.queued += chunk.len();
+            self.chunks.push_back(chunk);
+        }
+    }
+}
+
+impl Deref for WriteBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.tail
+    }
+}
+
+impl DerefMut for WriteBuffer {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.tail
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects all of the data held in memory in the order it is written.
+    fn contents(buffer: &WriteBuffer) -> Vec<u8> {
+        let mut data = Vec::new();
+        for chunk in buffer.chunks.iter() {
+            match chunk {
+                Chunk::Bytes(part) => data.extend_from_slice(part),
+                Chunk::File(_) => panic!("unexpected file chunk"),
+            }
+        }
+
+        data.extend_from_slice(&buffer.tail);
+        data
+    }
+
+    fn large(byte: u8) -> Bytes {
+        Bytes::from(vec![byte; MIN_QUEUED_CHUNK])
+    }
+
+    #[test]
+    fn copies_small_writes_into_tail() {
+        let mut buffer = WriteBuffer::default();
+        buffer.put_slice(b"head ");
+        buffer.put_bytes(Bytes::from_static(b"body"));
+
+        assert!(buffer.chunks.is_empty());
+        assert_eq!(buffer.len(), 9);
+        assert_eq!(contents(&buffer), b"head body");
+    }
+
+    #[test]
+    fn queues_large_writes_after_tail() {
+        let mut buffer = WriteBuffer::default();
+        buffer.put_slice(b"head");
+        buffer.put_bytes(large(b'a'));
+        buffer.put_slice(b"trailer");
+
+        assert_eq!(buffer.chunks.len(), 2);
+        assert_eq!(buffer.queued, 4 + MIN_QUEUED_CHUNK);
+        assert_eq!(buffer.len(), 4 + MIN_QUEUED_CHUNK + 7);
+
+        let data = contents(&buffer);
+        assert_eq!(&data[..4], b"head");
+        assert_eq!(&data[data.len() - 7..], b"trailer");
+    }
+
+    #[test]
+    fn split_to_within_tail() {
+        let mut buffer = WriteBuffer::default();
+        buffer.put_slice(b"hello world");
+
+        let head = buffer.split_to(5);
+        assert_eq!(contents(&head), b"hello");
+        assert_eq!(contents(&buffer), b" world");
+    }
+
+    #[test]
+    fn split_to_across_chunks() {
+        let mut buffer = WriteBuffer::default();
+        buffer.put_slice(b"head");
+        buffer.put_bytes(large(b'a'));
+        buffer.put_slice(b"tail");
+
+        let head = buffer.split_to(6);
+        assert_eq!(head.len(), 6);
+        assert_eq!(contents(&head), b"headaa");
+        assert_eq!(buffer.queued, MIN_QUEUED_CHUNK - 2);
+        assert_eq!(buffer.len(), MIN_QUEUED_CHUNK - 2 + 4);
+
+        let rest = buffer.split_to(MIN_QUEUED_CHUNK);
+        assert_eq!(rest.len(), MIN_QUEUED_CHUNK);
+        assert!(buffer.chunks.is_empty());
+        assert_eq!(buffer.queued, 0);
+        assert_eq!(contents(&buffer), b"il");
+    }
+
+    #[test]
+    fn split_to_whole_buffer() {
+        let mut buffer = WriteBuffer::default();
+        buffer.put_bytes(large(b'a'));
+        buffer.put_slice(b"tail");
+
+        let len = buffer.len();
+        let head = buffer.split_to(len);
+        assert_eq!(head.len(), len);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "split_to out of bounds")]
+    fn split_to_past_end_panics() {
+        let mut buffer = WriteBuffer::default();
+        buffer.put_slice(b"abc");
+        buffer.split_to(4);
+    }
+
+    #[test]
+    fn advance_across_chunks() {
+        let mut buffer = WriteBuffer::default();
+        buffer.put_slice(b"head");
+        buffer.put_bytes(large(b'a'));
+        buffer.put_slice(b"tail");
+
+        buffer.advance(2);
+        assert_eq!(buffer.queued, 2 + MIN_QUEUED_CHUNK);
+        assert_eq!(&contents(&buffer)[..2], b"ad");
+
+        buffer.advance(2 + MIN_QUEUED_CHUNK + 1);
+        assert!(buffer.chunks.is_empty());
+        assert_eq!(buffer.queued, 0);
+        assert_eq!(contents(&buffer), b"ail");
+
+        buffer.advance(3);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn io_slices_in_order() {
+        let mut buffer = WriteBuffer::default();
+        buffer.put_slice(b"head");
+        buffer.put_bytes(large(b'a'));
+        buffer.put_slice(b"tail");
+
+        let mut slices = [IoSlice::new(&[]); 4];
+        let count = buffer.io_slices(&mut slices);
+        assert_eq!(count, 3);
+        assert_eq!(&*slices[0], b"head");
+        assert_eq!(slices[1].len(), MIN_QUEUED_CHUNK);
+        assert_eq!(&*slices[2], b"tail");
+
+        let mut slices = [IoSlice::new(&[]); 2];
+        assert_eq!(buffer.io_slices(&mut slices), 2);
+    }
+
+    #[test]
+    fn append_keeps_order() {
+        let mut first = WriteBuffer::default();
+        first.put_slice(b"one ");
+
+        let mut second = WriteBuffer::default();
+        second.put_bytes(large(b'b'));
+        second.put_slice(b" two");
+
+        first.append(second);
+        assert_eq!(first.len(), 4 + MIN_QUEUED_CHUNK + 4);
+
+        let data = contents(&first);
+        assert_eq!(&data[..4], b"one ");
+        assert_eq!(&data[data.len() - 4..], b" two");
+    }
+}
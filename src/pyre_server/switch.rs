@@ -5,6 +5,46 @@ use pyo3::PyResult;
 pub enum SelectedProtocol {
     /// The HTTP/1.x protocol handler.
     H1,
+
+    /// The WebSocket protocol handler used once a HTTP/1.1 connection
+    /// has been upgraded.
+    WS,
+
+    /// The HTTP/2 protocol handler used for cleartext connections either
+    /// upgraded with `h2c` or started with the HTTP/2 preface.
+    H2,
+}
+
+
+/// The protocol a listener's connections start out with.
+#[derive(Copy, Clone, PartialEq)]
+pub enum DefaultProtocol {
+    /// Connections start as HTTP/1 and can move to HTTP/2 either with
+    /// a `h2c` upgrade, the HTTP/2 preface or TLS ALPN.
+    Auto,
+
+    /// Connections are HTTP/2 from the start, this is used for clients
+    /// with prior knowledge and TLS clients that do not use ALPN.
+    H2,
+}
+
+impl DefaultProtocol {
+    /// Parses the protocol from its name, either `auto` or `h2`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Self::Auto),
+            "h2" => Some(Self::H2),
+            _ => None,
+        }
+    }
+
+    /// The protocol selected before anything has been read.
+    pub fn selected(self) -> SelectedProtocol {
+        match self {
+            Self::Auto => SelectedProtocol::H1,
+            Self::H2 => SelectedProtocol::H2,
+        }
+    }
 }
 
 
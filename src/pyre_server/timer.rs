@@ -0,0 +1,85 @@
+use mio::Token;
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+
+/// A hashed timer wheel used to track client deadlines without having
+/// to scan every client on each tick.
+///
+/// Each slot covers one tick of the wheel, deadlines that are further
+/// away than a full rotation are simply re-inserted when their slot comes
+/// round and they have not expired yet.
+pub struct TimerWheel {
+    /// The slots of the wheel containing the tokens and their deadlines.
+    slots: Vec<Vec<(Token, Instant)>>,
+
+    /// The length of time a single slot covers.
+    resolution: Duration,
+
+    /// The index of the slot for the current tick.
+    current: usize,
+
+    /// The time the current tick started.
+    tick_start: Instant,
+}
+
+impl TimerWheel {
+    /// Creates a new wheel with the given amount of slots each covering
+    /// `resolution` amount of time.
+    pub fn new(resolution: Duration, slots: usize) -> Self {
+        let slots = (0..slots)
+            .map(|_| Vec::new())
+            .collect();
+
+        Self {
+            slots,
+            resolution,
+            current: 0,
+            tick_start: Instant::now(),
+        }
+    }
+
+    /// Schedules the token to expire at the given deadline, the token
+    /// is returned by `advance` on the first tick after the deadline.
+    pub fn schedule(&mut self, token: Token, deadline: Instant) {
+        let ticks = deadline
+            .saturating_duration_since(self.tick_start)
+            .as_nanos() / self.resolution.as_nanos();
+
+        // The current slot has already been processed so the earliest
+        // a deadline can fire is on the next tick.
+        let ticks = (ticks as usize).max(1);
+        let index = (self.current + ticks) % self.slots.len();
+
+        self.slots[index].push((token, deadline));
+    }
+
+    /// The time left until the next tick of the wheel is due.
+    pub fn next_tick_in(&self, now: Instant) -> Duration {
+        (self.tick_start + self.resolution).saturating_duration_since(now)
+    }
+
+    /// Advances the wheel up to the given time returning every token
+    /// that has reached its deadline along with the deadline it was
+    /// scheduled with.
+    pub fn advance(&mut self, now: Instant) -> Vec<(Token, Instant)> {
+        let mut expired = Vec::new();
+
+        while self.tick_start + self.resolution <= now {
+            self.current = (self.current + 1) % self.slots.len();
+            self.tick_start += self.resolution;
+
+            let slot = mem::take(&mut self.slots[self.current]);
+            for (token, deadline) in slot {
+                if deadline <= now {
+                    expired.push((token, deadline));
+                } else {
+                    self.schedule(token, deadline);
+                }
+            }
+        }
+
+        expired
+    }
+}
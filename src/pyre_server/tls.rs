@@ -0,0 +1,71 @@
+use rustls::{ServerConfig, ServerSession, NoClientAuth, Session};
+use rustls::internal::pemfile;
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use crate::pyre_server::switch::{DefaultProtocol, SelectedProtocol};
+
+
+/// The ALPN protocols offered to clients in order of preference.
+const ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+
+/// Builds the TLS settings from a PEM encoded certificate chain and
+/// private key, the key can either be PKCS8 or RSA.
+pub fn load_config(
+    certfile: &str,
+    keyfile: &str,
+) -> io::Result<Arc<ServerConfig>> {
+    let certs = pemfile::certs(&mut open(certfile)?)
+        .map_err(|_| invalid(format!("invalid certificate in {}", certfile)))?;
+
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates found in {}", certfile)))
+    }
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(keyfile)?)
+        .map_err(|_| invalid(format!("invalid private key in {}", keyfile)))?;
+
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(keyfile)?)
+            .map_err(|_| invalid(format!("invalid private key in {}", keyfile)))?;
+    }
+
+    let key = keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid(format!("no private key found in {}", keyfile)))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    let protocols: Vec<Vec<u8>> = ALPN_PROTOCOLS.iter()
+        .map(|protocol| protocol.to_vec())
+        .collect();
+    config.set_protocols(&protocols);
+
+    Ok(Arc::new(config))
+}
+
+/// The protocol the client agreed to with ALPN once the handshake is
+/// complete, clients that do not use ALPN get the listener's default.
+pub fn negotiated_protocol(
+    session: &ServerSession,
+    default: DefaultProtocol,
+) -> SelectedProtocol {
+    match session.get_alpn_protocol() {
+        Some(b"h2") => SelectedProtocol::H2,
+        Some(_) => SelectedProtocol::H1,
+        None => default.selected(),
+    }
+}
+
+fn open(path: &str) -> io::Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
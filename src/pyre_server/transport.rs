@@ -1,6 +1,7 @@
 #![allow(unused)]
 
 use mio::{Waker, Token};
+use log::error;
 use std::sync::Arc;
 use crossbeam::queue::SegQueue;
 
@@ -16,6 +17,28 @@ pub enum EventUpdate {
 
     ResumeReading(Token),
     ResumeWriting(Token),
+
+    /// Stops polling the socket and closes it, the client is kept to
+    /// handle a new connection.
+    Release(Token),
+
+    /// Stops accepting new connections and drains the existing ones.
+    Shutdown,
+}
+
+impl EventUpdate {
+    /// The token of the client the update applies to, this is `None`
+    /// for updates that apply to the whole server.
+    pub fn token(&self) -> Option<Token> {
+        match self {
+            Self::PauseReading(token) => Some(*token),
+            Self::PauseWriting(token) => Some(*token),
+            Self::ResumeReading(token) => Some(*token),
+            Self::ResumeWriting(token) => Some(*token),
+            Self::Release(token) => Some(*token),
+            Self::Shutdown => None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -36,31 +59,49 @@ impl EventLoopHandle {
         let update = EventUpdate::PauseReading(token);
         self.internal.push(update);
 
-        self.waker.wake()
-            .expect("Failed to wake event loop");
+        self.wake();
     }
 
     pub fn pause_writing(&self, token: Token) {
         let update = EventUpdate::PauseWriting(token);
         self.internal.push(update);
 
-        self.waker.wake()
-            .expect("Failed to wake event loop");
+        self.wake();
     }
 
     pub fn resume_reading(&self, token: Token) {
         let update = EventUpdate::ResumeReading(token);
         self.internal.push(update);
 
-        self.waker.wake()
-            .expect("Failed to wake event loop");
+        self.wake();
     }
 
     pub fn resume_writing(&self, token: Token) {
         let update = EventUpdate::ResumeWriting(token);
         self.internal.push(update);
 
-        self.waker.wake()
-            .expect("Failed to wake event loop");
+        self.wake();
+    }
+
+    pub fn release(&self, token: Token) {
+        let update = EventUpdate::Release(token);
+        self.internal.push(update);
+
+        self.wake();
+    }
+
+    pub fn shutdown(&self) {
+        let update = EventUpdate::Shutdown;
+        self.internal.push(update);
+
+        self.wake();
+    }
+
+    /// Wakes the event loop to apply the queued updates, if this fails
+    /// the updates are still applied the next time the loop wakes.
+    fn wake(&self) {
+        if let Err(e) = self.waker.wake() {
+            error!("Failed to wake event loop: {}", e);
+        }
     }
 }
\ No newline at end of file
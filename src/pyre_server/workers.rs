@@ -0,0 +1,212 @@
+use std::io;
+use std::mem;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+
+use crate::pyre_server::server::{LowLevelServer, ServerControl};
+use crate::pyre_server::py_callback::CallbackHandler;
+use crate::pyre_server::config::{SharedConfig, SharedListenerConfig};
+use crate::pyre_server::buffer_pool::{BufferPool, PoolStats};
+use crate::pyre_server::lifespan::Lifespan;
+use crate::pyre_server::net::{Addr, BindAddr, Listener};
+
+
+/// A group of servers that each run their own event loop on a separate
+/// thread.
+///
+/// When there is more than one worker each accepts from its own TCP
+/// listener bound to the same address with `SO_REUSEPORT`, the kernel
+/// spreads new connections between them and a client is handled by the
+/// worker that accepted it until the connection is closed. Unix sockets
+/// and adopted sockets are shared with every worker instead.
+///
+/// Every worker accepts from each of the addresses the pool is bound to.
+pub struct WorkerPool {
+    /// The addresses the listeners are bound to and their settings.
+    listeners: Vec<(Addr, SharedListenerConfig)>,
+
+    /// The servers waiting to be started, this is empty once started.
+    servers: Mutex<Vec<LowLevelServer>>,
+
+    /// The handles used to stop each worker and read its stats.
+    controls: Vec<ServerControl>,
+
+    /// The buffers shared by every worker.
+    buffers: BufferPool,
+
+    /// The threads running the workers, each returns the error that
+    /// stopped it if there was one.
+    threads: Mutex<Vec<JoinHandle<Result<(), String>>>>,
+}
+
+impl WorkerPool {
+    /// Binds a server for each worker, all of the binding happens up
+    /// front so any errors are raised before a worker is started.
+    ///
+    /// The lifespan and buffers are shared so the app's startup and
+    /// shutdown only happen once and memory is pooled for the whole pool.
+    pub fn bind(
+        addrs: &[(BindAddr, SharedListenerConfig)],
+        workers: usize,
+        keep_alive_timeout: Duration,
+        callbacks: CallbackHandler,
+        config: SharedConfig,
+        buffers: BufferPool,
+        lifespan: Lifespan,
+    ) -> io::Result<Self> {
+        let workers = workers.max(1);
+
+        let mut worker_listeners: Vec<Vec<_>> = (0..workers)
+            .map(|_| Vec::with_capacity(addrs.len()))
+            .collect();
+
+        for (addr, listener_config) in addrs {
+            let bound = bind_listeners(addr, workers)?;
+            for (listeners, listener) in worker_listeners.iter_mut().zip(bound) {
+                listeners.push((listener, listener_config.clone()));
+            }
+        }
+
+        let servers = worker_listeners.into_iter()
+            .map(|listeners| LowLevelServer::from_listeners(
+                listeners,
+                keep_alive_timeout,
+                callbacks.for_worker(),
+                config.clone(),
+                buffers.clone(),
+                lifespan.clone(),
+            ))
+            .collect::<io::Result<Vec<LowLevelServer>>>()?;
+
+        let listeners = servers[0].local_addrs()?
+            .into_iter()
+            .zip(addrs.iter().map(|(_, config)| config.clone()))
+            .collect();
+
+        let controls = servers.iter()
+            .map(LowLevelServer::control)
+            .collect();
+
+        Ok(Self {
+            listeners,
+            servers: Mutex::new(servers),
+            controls,
+            buffers,
+            threads: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The addresses the listeners are bound to and their settings in
+    /// the order they were given.
+    pub fn listeners(&self) -> &[(Addr, SharedListenerConfig)] {
+        &self.listeners
+    }
+
+    /// Starts every worker on its own thread.
+    ///
+    /// Returns `false` if the workers have already been started.
+    pub fn start(&self) -> io::Result<bool> {
+        let servers = mem::take(&mut *self.servers.lock().unwrap());
+        if servers.is_empty() {
+            return Ok(false)
+        }
+
+        let mut threads = self.threads.lock().unwrap();
+        for (id, mut server) in servers.into_iter().enumerate() {
+            let handle = thread::Builder::new()
+                .name(format!("pyre-worker-{}", id))
+                .spawn(move || server.start().map_err(|e| e.to_string()))?;
+
+            threads.push(handle);
+        }
+
+        Ok(true)
+    }
+
+    /// Tells every worker to stop, this does not wait for them to exit.
+    pub fn stop(&self) {
+        for control in self.controls.iter() {
+            control.stop();
+        }
+    }
+
+    /// If any of the workers are still running.
+    pub fn is_running(&self) -> bool {
+        self.threads.lock()
+            .unwrap()
+            .iter()
+            .any(|thread| !thread.is_finished())
+    }
+
+    /// Joins any workers that have exited.
+    ///
+    /// Returns if every worker has exited or the error of the first
+    /// worker that failed.
+    pub fn reap(&self) -> Result<bool, String> {
+        let mut threads = self.threads.lock().unwrap();
+
+        let (finished, running) = mem::take(&mut *threads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|thread| thread.is_finished());
+        *threads = running;
+
+        let mut error = None;
+        for thread in finished {
+            let result = thread.join()
+                .unwrap_or_else(|_| Err(String::from("a worker thread panicked")));
+
+            if let Err(e) = result {
+                error.get_or_insert(e);
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(threads.is_empty()),
+        }
+    }
+
+    /// The number of connections currently being handled by all workers.
+    pub fn len_clients(&self) -> usize {
+        self.controls.iter()
+            .map(|control| control.stats.clients.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The number of clients kept around by all workers to handle new
+    /// connections.
+    pub fn len_idle(&self) -> usize {
+        self.controls.iter()
+            .map(|control| control.stats.idle.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The usage counts of the buffers shared by all workers.
+    pub fn buffer_stats(&self) -> &PoolStats {
+        self.buffers.stats()
+    }
+}
+
+
+/// Binds a listener for each worker.
+fn bind_listeners(addr: &BindAddr, workers: usize) -> io::Result<Vec<Listener>> {
+    #[cfg(unix)]
+    {
+        if addr.is_shared() {
+            let mut listeners = vec![Listener::bind(addr, false)?];
+            while listeners.len() < workers {
+                let shared = listeners[0].try_clone()?;
+                listeners.push(shared);
+            }
+
+            return Ok(listeners)
+        }
+    }
+
+    let reuse_port = workers > 1;
+    (0..workers)
+        .map(|_| Listener::bind(addr, reuse_port))
+        .collect()
+}
@@ -1,16 +1,40 @@
+import asyncio
+
 import pyre_test
 
 
-def cb(send, *args):
-    send(
-        False,
-        b"HTTP/1.1 200 OK\r\n"
-        b"Content-Length: 13\r\n"
-        b"Server: Pyre\r\n"
-        b"\r\n"
-        b"Hello, World!"
+async def app(scope, receive, send):
+    assert scope["type"] == "http"
+
+    await receive()
+    await send({
+        "type": "http.response.start",
+        "status": 200,
+        "headers": [
+            (b"content-type", b"text/plain"),
+        ],
+    })
+    await send({
+        "type": "http.response.body",
+        "body": b"Hello, World!",
+    })
+
+
+async def main():
+    loop = asyncio.get_running_loop()
+
+    # The app is run as a task on this loop, join blocks so it is
+    # waited on in the executor to keep the loop running.
+    server = pyre_test.create_server(
+        "127.0.0.1",
+        5050,
+        app,
+        5,
+        loop=loop,
+        lifespan="off",
     )
-    print("wew")
+    server.start()
+    await loop.run_in_executor(None, server.join)
 
 
-pyre_test.create_server("127.0.0.1", 5050, cb, 5)
\ No newline at end of file
+asyncio.run(main())